@@ -0,0 +1,80 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{fs, io, path::Path};
+
+use eyre::{Result, WrapErr};
+use peerid::PeerId;
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+// A DTLS certificate and the PeerId that it's known by (its sha-256 fingerprint), like wonk-identity's cert and pid.
+#[derive(Clone)]
+pub struct Identity {
+	pub(crate) cert: RTCCertificate,
+	id: PeerId,
+}
+impl Identity {
+	// A new ECDSA P-256 certificate, which is what browsers generate
+	pub fn generate() -> Result<Self> {
+		let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+		Self::from_cert(RTCCertificate::from_key_pair(key_pair)?)
+	}
+	// The identity kept in a PEM file (certificate and private key), which is created if it doesn't exist yet.
+	pub fn load(path: &Path) -> Result<Self> {
+		match fs::read_to_string(path) {
+			Ok(pem) => RTCCertificate::from_pem(&pem)
+				.map_err(Into::into)
+				.and_then(Self::from_cert)
+				.wrap_err_with(|| format!("invalid identity in {}", path.display())),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				let ret = Self::generate()?;
+				let mut options = fs::OpenOptions::new();
+				options.write(true).create_new(true);
+				// It has the private key in it
+				#[cfg(unix)]
+				options.mode(0o600);
+				options
+					.open(path)
+					.and_then(|mut file| io::Write::write_all(&mut file, ret.cert.serialize_pem().as_bytes()))
+					.wrap_err_with(|| format!("writing {}", path.display()))?;
+				Ok(ret)
+			}
+			Err(e) => Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+		}
+	}
+	// The PeerId comes from the certificate's fingerprints, like OwnPeerId.from_cert
+	fn from_cert(cert: RTCCertificate) -> Result<Self> {
+		let sdp: String = cert
+			.get_fingerprints()
+			.iter()
+			.map(|f| format!("a=fingerprint:{} {}\n", f.algorithm, f.value))
+			.collect();
+		let id = PeerId::from_sdp(&sdp)?;
+		Ok(Self { cert, id })
+	}
+	pub fn id(&self) -> &PeerId {
+		&self.id
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn load() {
+		let path = std::env::temp_dir().join(format!("peer-identity-{}.pem", rand::random::<u64>()));
+		let created = Identity::load(&path).unwrap();
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+		}
+		let loaded = Identity::load(&path).unwrap();
+		assert_eq!(loaded.id(), created.id());
+		assert_ne!(Identity::generate().unwrap().id(), created.id());
+
+		fs::write(&path, "not a certificate").unwrap();
+		assert!(Identity::load(&path).is_err());
+		fs::remove_file(&path).unwrap();
+	}
+}
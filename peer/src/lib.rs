@@ -0,0 +1,414 @@
+// A native peer that speaks peercon/index.mjs's protocol, so that bots, test drivers and servers can join the overlay
+// next to browsers: peers swap SigMsgs, the polite one (PeerId::polite) is the DTLS server, and datachannel 0 is
+// pre-negotiated and carries perfect negotiation.
+//
+// This uses webrtc-rs's ORTC transports instead of an RTCPeerConnection.  Both sides of a PeerCon make the offer and
+// treat the other's SigMsg as the answer, so both start out as the controlling ICE agent: browsers sort that out with
+// tie-breakers (RFC 8445 section 7.3.1.1), but webrtc-ice doesn't, and RTCPeerConnection doesn't let us pick the role.
+// Here the impolite peer is controlling.  Two native peers always agree, while a browser gives way if its tie-breaker
+// is the lower one, so against a browser the native peer connects most reliably as the polite one.
+//
+// Both sides also start SCTP as clients (simultaneous open).  webrtc-sctp 0.7 spawns its read loop before it enters
+// COOKIE-WAIT, so on a multi-threaded runtime an INIT that's already waiting can establish the association only for it
+// to be reset, and the first send fails.  Use a current_thread runtime, like the peer binary does.
+use std::{sync::Arc, time::Duration};
+
+use eyre::{bail, eyre, Result};
+use peercon::{Address, Candidate, CandidateAddr, CandidateType, Protocol, SigMsg, CREDENTIAL, ICE_PWD};
+use peerid::{PeerId, ID_FINGERPRINT};
+use rand::Rng;
+use serde_json::Value;
+use tokio::sync::{mpsc, watch, Mutex};
+use tracing::{debug, info, warn};
+use webrtc::{
+	api::{APIBuilder, API},
+	data_channel::{data_channel_parameters::DataChannelParameters, RTCDataChannel},
+	dtls_transport::{
+		dtls_fingerprint::RTCDtlsFingerprint, dtls_parameters::DTLSParameters, dtls_role::DTLSRole, RTCDtlsTransport,
+	},
+	ice_transport::{
+		ice_candidate::RTCIceCandidate,
+		ice_candidate_type::RTCIceCandidateType,
+		ice_gatherer::{RTCIceGatherOptions, RTCIceGatherer},
+		ice_parameters::RTCIceParameters,
+		ice_protocol::RTCIceProtocol,
+		ice_role::RTCIceRole,
+		ice_server::RTCIceServer,
+		ice_transport_state::RTCIceTransportState,
+		RTCIceTransport,
+	},
+	peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy,
+	sctp_transport::{sctp_transport_capabilities::SCTPTransportCapabilities, RTCSctpTransport},
+};
+
+mod identity;
+pub use identity::Identity;
+
+// How long connect waits for ICE, DTLS and SCTP to come up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A connection that's waiting for the other peer's SigMsg.  Gathering starts as soon as it's created.
+pub struct PeerCon {
+	local_id: PeerId,
+	api: API,
+	gatherer: Arc<RTCIceGatherer>,
+	ice: Arc<RTCIceTransport>,
+	dtls: Arc<RTCDtlsTransport>,
+	sctp: Arc<RTCSctpTransport>,
+	gathered: watch::Receiver<bool>,
+	// Datachannels that the other peer opens
+	incoming: mpsc::UnboundedReceiver<Arc<RTCDataChannel>>,
+}
+impl PeerCon {
+	pub async fn new(identity: &Identity, options: RTCIceGatherOptions) -> Result<Self> {
+		let api = APIBuilder::new().build();
+		let gatherer = Arc::new(api.new_ice_gatherer(options)?);
+		let (gathered_tx, gathered) = watch::channel(false);
+		gatherer.on_gathering_complete(Box::new(move || {
+			gathered_tx.send_replace(true);
+			Box::pin(async {})
+		}));
+		let ice = Arc::new(api.new_ice_transport(gatherer.clone()));
+		let dtls = Arc::new(api.new_dtls_transport(ice.clone(), vec![identity.cert.clone()])?);
+		let sctp = Arc::new(api.new_sctp_transport(dtls.clone())?);
+		let (incoming_tx, incoming) = mpsc::unbounded_channel();
+		sctp.on_data_channel(Box::new(move |dc| {
+			let _ = incoming_tx.send(dc);
+			Box::pin(async {})
+		}));
+		gatherer.gather().await?;
+		Ok(Self {
+			local_id: identity.id().clone(),
+			api,
+			gatherer,
+			ice,
+			dtls,
+			sctp,
+			gathered,
+			incoming,
+		})
+	}
+
+	// Connect through a relay (relayu:, relayt: or relayl:), like PeerCon.connect_address: the relay pairs us with
+	// whoever allocates with the mirror image of our dst.src.token username, so the other peer's SigMsg is made up.
+	// Without a token, address.username picks one: set address.token first to know what to give the other peer.
+	pub async fn connect_address(identity: &Identity, address: &Address) -> Result<Connection> {
+		let (Some(url), Some(username)) = (address.turn_url(), address.username(identity.id())) else {
+			bail!("{}: addresses aren't supported", address.scheme);
+		};
+		let options = RTCIceGatherOptions {
+			ice_servers: vec![RTCIceServer {
+				urls: vec![url],
+				username,
+				credential: CREDENTIAL.into(),
+				..Default::default()
+			}],
+			ice_gather_policy: RTCIceTransportPolicy::Relay,
+		};
+		let conn = Self::new(identity, options).await?;
+		// The relay rewrites our checks' USERNAME and MESSAGE-INTEGRITY for the other peer, which it can only do if
+		// they're `<our ice pwd>:<our ice ufrag>` and keyed with ICE_PWD.
+		let (_, ice_pwd) = conn.local_ice_cred().await?;
+		let remote = SigMsg {
+			id: address.peer_id.clone(),
+			ice_ufrag: ice_pwd,
+			ice_pwd: ICE_PWD.into(),
+			candidates: vec![gen_candidate()],
+		};
+		conn.connect(remote).await
+	}
+
+	pub fn local_id(&self) -> &PeerId {
+		&self.local_id
+	}
+
+	// Our ICE ufrag and password, which are known before gathering finishes (like local_ice_cred)
+	pub async fn local_ice_cred(&self) -> Result<(String, String)> {
+		let params = self.gatherer.get_local_parameters().await?;
+		Ok((params.username_fragment, params.password))
+	}
+
+	// Our SigMsg, once gathering has finished (like local_msg).  It has our UDP candidates, lowest priority first.
+	pub async fn local_msg(&self) -> Result<SigMsg> {
+		self.gathered.clone().wait_for(|done| *done).await?;
+		let (ice_ufrag, ice_pwd) = self.local_ice_cred().await?;
+		let mut candidates = self.gatherer.get_local_candidates().await?;
+		candidates.retain(|c| c.protocol == RTCIceProtocol::Udp);
+		candidates.sort_by_key(|c| c.priority);
+		Ok(SigMsg {
+			id: self.local_id.clone(),
+			ice_ufrag,
+			ice_pwd,
+			candidates: candidates.iter().filter_map(to_candidate).collect(),
+		})
+	}
+
+	// Connect with the other peer's SigMsg, and open datachannel 0.
+	pub async fn connect(self, remote: SigMsg) -> Result<Connection> {
+		let polite = self.local_id.polite(&remote.id);
+		info!(peer = %remote.id, polite, "connecting");
+		match tokio::time::timeout(CONNECT_TIMEOUT, self.start(&remote, polite)).await {
+			Ok(Ok(dc)) => Ok(Connection::new(self, remote.id, polite, dc)),
+			Ok(Err(e)) => {
+				self.stop().await;
+				Err(e)
+			}
+			Err(_) => {
+				self.stop().await;
+				Err(eyre!("timed out connecting to {}", remote.id))
+			}
+		}
+	}
+
+	// What index.mjs puts in the remote description: the ICE credentials and candidates from the SigMsg, the
+	// fingerprint from its PeerId, and a=setup:active (the other peer is the DTLS client) when we're polite.
+	async fn start(&self, remote: &SigMsg, polite: bool) -> Result<Arc<RTCDataChannel>> {
+		let candidates: Vec<_> = remote
+			.candidates
+			.iter()
+			.enumerate()
+			.filter_map(|(i, c)| from_candidate(c, i))
+			.collect();
+		self.ice.set_remote_candidates(&candidates).await?;
+		let (ice_role, remote_dtls_role) = match polite {
+			true => (RTCIceRole::Controlled, DTLSRole::Client),
+			false => (RTCIceRole::Controlling, DTLSRole::Server),
+		};
+		let params = RTCIceParameters {
+			username_fragment: remote.ice_ufrag.clone(),
+			password: remote.ice_pwd.clone(),
+			ice_lite: false,
+		};
+		self.ice.start(&params, Some(ice_role)).await?;
+		debug!(peer = %remote.id, "ice connected");
+
+		let fingerprint = remote.id.fingerprint(ID_FINGERPRINT).unwrap_or_default();
+		let fingerprint = RTCDtlsFingerprint {
+			algorithm: ID_FINGERPRINT.name().into(),
+			value: fingerprint.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":"),
+		};
+		self.dtls
+			.start(DTLSParameters {
+				role: remote_dtls_role,
+				fingerprints: vec![fingerprint],
+			})
+			.await?;
+		debug!(peer = %remote.id, "dtls connected");
+
+		self.sctp.start(SCTPTransportCapabilities { max_message_size: 0 }).await?;
+		let dc = self.negotiated_channel("_", 0).await?;
+		Ok(dc)
+	}
+
+	async fn negotiated_channel(&self, label: &str, id: u16) -> Result<Arc<RTCDataChannel>> {
+		let params = DataChannelParameters {
+			label: label.into(),
+			ordered: true,
+			negotiated: Some(id),
+			..Default::default()
+		};
+		Ok(Arc::new(self.api.new_data_channel(self.sctp.clone(), params).await?))
+	}
+
+	async fn stop(&self) {
+		let results = [self.sctp.stop().await, self.dtls.stop().await, self.ice.stop().await];
+		for e in results.into_iter().filter_map(Result::err) {
+			debug!(error = %e, "stopping");
+		}
+	}
+}
+
+// A connected PeerCon
+pub struct Connection {
+	pub peer_id: PeerId,
+	pub polite: bool,
+	conn: PeerCon,
+	dc: Arc<RTCDataChannel>,
+	incoming: Mutex<mpsc::UnboundedReceiver<Arc<RTCDataChannel>>>,
+	closed: watch::Receiver<bool>,
+}
+impl Connection {
+	fn new(mut conn: PeerCon, peer_id: PeerId, polite: bool, dc: Arc<RTCDataChannel>) -> Self {
+		info!(peer = %peer_id, "connected");
+		let (closed_tx, closed) = watch::channel(false);
+		let closed_tx = Arc::new(closed_tx);
+		let tx = closed_tx.clone();
+		conn.ice.on_connection_state_change(Box::new(move |state| {
+			if matches!(state, RTCIceTransportState::Failed | RTCIceTransportState::Closed) {
+				tx.send_replace(true);
+			}
+			Box::pin(async {})
+		}));
+		dc.on_close(Box::new(move || {
+			closed_tx.send_replace(true);
+			Box::pin(async {})
+		}));
+		Self::perfect(&conn, &dc, polite);
+		let (_, dummy) = mpsc::unbounded_channel();
+		let incoming = Mutex::new(std::mem::replace(&mut conn.incoming, dummy));
+		Self {
+			peer_id,
+			polite,
+			conn,
+			dc,
+			incoming,
+			closed,
+		}
+	}
+
+	// Perfect negotiation messages on datachannel 0: {"description": ...} or {"candidate": ...}.  ORTC transports
+	// can't be renegotiated, so descriptions are ignored, but candidates are added (they arrive after ICE restarts).
+	fn perfect(conn: &PeerCon, dc: &RTCDataChannel, polite: bool) {
+		let ice = conn.ice.clone();
+		dc.on_message(Box::new(move |msg| {
+			let ice = ice.clone();
+			Box::pin(async move {
+				if !msg.is_string {
+					return;
+				}
+				let Ok(Value::Object(msg)) = serde_json::from_slice::<Value>(&msg.data) else { return };
+				if let Some(description) = msg.get("description").filter(|d| !d.is_null()) {
+					let typ = description.get("type").and_then(Value::as_str).unwrap_or_default();
+					warn!(typ, polite, "can't renegotiate: ignoring the description");
+				}
+				let candidate = msg.get("candidate").and_then(|c| c.get("candidate")).and_then(Value::as_str);
+				let Some(candidate) = candidate.filter(|c| !c.is_empty()) else { return };
+				match Candidate::from_sdp(candidate) {
+					Ok(candidate) => {
+						if let Some(candidate) = from_candidate(&candidate, 0) {
+							if let Err(e) = ice.add_remote_candidate(Some(candidate)).await {
+								debug!(error = %e, "adding candidate");
+							}
+						}
+					}
+					Err(e) => debug!(error = %e, "ignoring candidate"),
+				}
+			})
+		}));
+	}
+
+	// Datachannel 0, which perfect negotiation runs on
+	pub fn dc(&self) -> &Arc<RTCDataChannel> {
+		&self.dc
+	}
+
+	// A datachannel that both sides open with the same id (createDataChannel(label, {negotiated: true, id})).
+	// webrtc-rs's ORTC transports only keep track of the channels that they've accepted, so they'd give channels that
+	// we announce ids that clash: ours are always negotiated.
+	pub async fn negotiated_channel(&self, label: &str, id: u16) -> Result<Arc<RTCDataChannel>> {
+		if id == 0 {
+			bail!("datachannel 0 is perfect negotiation's");
+		}
+		self.conn.negotiated_channel(label, id).await
+	}
+
+	// The next datachannel that the other peer opens (a datachannel event)
+	pub async fn accept(&self) -> Option<Arc<RTCDataChannel>> {
+		self.incoming.lock().await.recv().await
+	}
+
+	// Resolves once ICE fails or datachannel 0 closes
+	pub async fn closed(&self) {
+		let _ = self.closed.clone().wait_for(|closed| *closed).await;
+	}
+
+	pub async fn close(&self) {
+		let _ = self.dc.close().await;
+		self.conn.stop().await;
+	}
+}
+
+// A SigMsg candidate as an ICE candidate (its SDP is Candidate::sdp(index)).  webrtc-ice only does UDP.
+fn from_candidate(c: &Candidate, index: usize) -> Option<RTCIceCandidate> {
+	if c.protocol != Protocol::Udp {
+		return None;
+	}
+	Some(RTCIceCandidate {
+		foundation: "foundation".into(),
+		priority: c.priority.unwrap_or(index as u32 + 1),
+		address: c.address.to_string(),
+		protocol: RTCIceProtocol::Udp,
+		port: c.port,
+		typ: match c.typ {
+			CandidateType::Host => RTCIceCandidateType::Host,
+			CandidateType::Srflx => RTCIceCandidateType::Srflx,
+			CandidateType::Prflx => RTCIceCandidateType::Prflx,
+			CandidateType::Relay => RTCIceCandidateType::Relay,
+		},
+		component: 1,
+		..Default::default()
+	})
+}
+
+// One of our candidates for a SigMsg.  Like encode_candidates, its place in the SigMsg stands in for its priority.
+// Candidates on unspecified addresses (webrtc-ice listens on `::`) aren't any use to the other peer.
+fn to_candidate(c: &RTCIceCandidate) -> Option<Candidate> {
+	let typ = match c.typ {
+		RTCIceCandidateType::Host => CandidateType::Host,
+		RTCIceCandidateType::Srflx => CandidateType::Srflx,
+		RTCIceCandidateType::Prflx => CandidateType::Prflx,
+		RTCIceCandidateType::Relay => CandidateType::Relay,
+		RTCIceCandidateType::Unspecified => return None,
+	};
+	let address = c.address.parse().ok()?;
+	if matches!(address, CandidateAddr::Ip(ip) if ip.is_unspecified()) {
+		return None;
+	}
+	Some(Candidate {
+		typ,
+		protocol: Protocol::Udp,
+		address,
+		port: c.port,
+		priority: None,
+	})
+}
+
+// A placeholder remote candidate, like gen_candidate: the relay forwards everything to the other peer, whatever its
+// address.
+fn gen_candidate() -> Candidate {
+	let mut rng = rand::thread_rng();
+	Candidate {
+		typ: CandidateType::Host,
+		protocol: Protocol::Udp,
+		address: CandidateAddr::Ip([30, rng.gen(), rng.gen(), rng.gen()].into()),
+		port: rng.gen(),
+		priority: None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Two native peers over host candidates.  PeerCon itself can't join in: index.mjs needs RTCPeerConnection and
+	// indexedDB, which node doesn't have, so against browsers this is checked by hand with the peer binary.
+	#[tokio::test]
+	async fn loopback() {
+		let (a, b) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+		let con_a = PeerCon::new(&a, RTCIceGatherOptions::default()).await.unwrap();
+		let con_b = PeerCon::new(&b, RTCIceGatherOptions::default()).await.unwrap();
+		// SigMsgs travel as text
+		let msg_a: SigMsg = con_a.local_msg().await.unwrap().to_string().parse().unwrap();
+		let msg_b: SigMsg = con_b.local_msg().await.unwrap().to_string().parse().unwrap();
+		let (con_a, con_b) = tokio::join!(con_a.connect(msg_b), con_b.connect(msg_a));
+		let (con_a, con_b) = (con_a.unwrap(), con_b.unwrap());
+		assert_eq!((&con_a.peer_id, &con_b.peer_id), (b.id(), a.id()));
+		assert_eq!(con_a.polite, a.id().polite(b.id()));
+		assert_ne!(con_a.polite, con_b.polite);
+
+		// Both sides open the same negotiated channel
+		let dc_a = con_a.negotiated_channel("test", 1).await.unwrap();
+		let dc_b = con_b.negotiated_channel("test", 1).await.unwrap();
+		let (tx, mut rx) = mpsc::unbounded_channel();
+		dc_b.on_message(Box::new(move |msg| {
+			let _ = tx.send(msg.data);
+			Box::pin(async {})
+		}));
+		dc_a.send_text("hello".to_string()).await.unwrap();
+		assert_eq!(&rx.recv().await.unwrap()[..], b"hello");
+		assert!(con_a.negotiated_channel("_", 0).await.is_err());
+
+		con_a.close().await;
+		tokio::time::timeout(CONNECT_TIMEOUT, con_b.closed()).await.unwrap();
+	}
+}
@@ -0,0 +1,158 @@
+use std::{io::IsTerminal, path::PathBuf, sync::Arc};
+
+use eyre::{bail, eyre, Result, WrapErr};
+use peer::{Connection, Identity, PeerCon};
+use peercon::{gen_token, Address, SigMsg};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use webrtc::{
+	data_channel::RTCDataChannel,
+	ice_transport::{ice_gatherer::RTCIceGatherOptions, ice_server::RTCIceServer},
+};
+
+const USAGE: &str = "\
+Usage: peer [options] [<address>]
+
+Connect to another peer over WebRTC, the way peercon/index.mjs's PeerCon does.  Without an address, SigMsgs are
+exchanged over stdio: ours is printed on the first line of stdout, and the other peer's is read from the first line
+of stdin.  With a relayu:, relayt: or relayl: address, the connection goes through that relay, and the address that
+the other peer answers with is printed to stderr.  Once connected, the lines of stdin are sent on --channel, and text
+from it (and from datachannels the other peer opens) is printed to stdout.
+
+Options:
+  -i, --identity <path>  PEM file with our certificate (created if it doesn't exist; default: a new identity)
+      --stun <url>       STUN server for server reflexive candidates (repeatable)
+      --channel <id>     Negotiated datachannel for stdio (default 1)
+      --log-filter <f>   Log levels, e.g. peer=debug,webrtc_ice=trace (default: RUST_LOG)
+  -h, --help             Print this help
+";
+
+struct Args {
+	identity: Option<PathBuf>,
+	stun: Vec<String>,
+	channel: u16,
+	log_filter: Option<String>,
+	address: Option<Address>,
+}
+
+fn from_args(args: impl IntoIterator<Item = String>) -> Result<Option<Args>> {
+	let mut args = args.into_iter();
+	let mut ret = Args {
+		identity: None,
+		stun: Vec::new(),
+		channel: 1,
+		log_filter: None,
+		address: None,
+	};
+	while let Some(arg) = args.next() {
+		let mut value = |name: &str| args.next().ok_or_else(|| eyre!("{name} requires a value\n\n{USAGE}"));
+		match arg.as_str() {
+			"-h" | "--help" => return Ok(None),
+			"-i" | "--identity" => ret.identity = Some(value(&arg)?.into()),
+			"--stun" => ret.stun.push(value(&arg)?),
+			"--channel" => {
+				let v = value(&arg)?;
+				ret.channel = v.parse().ok().filter(|id| *id != 0).ok_or_else(|| eyre!("invalid channel {v:?}"))?;
+			}
+			"--log-filter" => ret.log_filter = Some(value(&arg)?),
+			_ if arg.starts_with('-') || ret.address.is_some() => bail!("unknown argument {arg:?}\n\n{USAGE}"),
+			_ => ret.address = Some(arg.parse().wrap_err_with(|| format!("invalid address {arg:?}"))?),
+		}
+	}
+	Ok(Some(ret))
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+	let Some(args) = from_args(std::env::args().skip(1))? else {
+		print!("{USAGE}");
+		return Ok(());
+	};
+	let filter = match &args.log_filter {
+		Some(filter) => EnvFilter::try_new(filter)?,
+		None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn,peer=info")),
+	};
+	tracing_subscriber::fmt()
+		.with_env_filter(filter)
+		.with_writer(std::io::stderr)
+		.with_ansi(std::io::stderr().is_terminal())
+		.init();
+
+	let identity = match &args.identity {
+		Some(path) => Identity::load(path)?,
+		None => Identity::generate()?,
+	};
+	info!(id = %identity.id(), "identity");
+
+	let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+	let conn = match args.address {
+		Some(mut address) => {
+			if address.scheme.is_relay() && address.token.is_none() {
+				let token = gen_token(16);
+				let answer = Address {
+					peer_id: identity.id().clone(),
+					token: Some(token.clone()),
+					..address.clone()
+				};
+				info!(%answer, "the other peer can answer with");
+				address.token = Some(token);
+			}
+			PeerCon::connect_address(&identity, &address).await?
+		}
+		None => {
+			let options = RTCIceGatherOptions {
+				ice_servers: args
+					.stun
+					.into_iter()
+					.map(|url| RTCIceServer {
+						urls: vec![url],
+						..Default::default()
+					})
+					.collect(),
+				..Default::default()
+			};
+			let conn = PeerCon::new(&identity, options).await?;
+			println!("{}", conn.local_msg().await?);
+			let remote = stdin.next_line().await?.ok_or_else(|| eyre!("stdin closed before the other peer's SigMsg"))?;
+			let remote: SigMsg = remote.trim().parse().wrap_err("invalid SigMsg")?;
+			conn.connect(remote).await?
+		}
+	};
+
+	let dc = conn.negotiated_channel("stdio", args.channel).await?;
+	print_messages(&dc);
+	tokio::select! {
+		r = stdio(&conn, &dc, &mut stdin) => r?,
+		_ = conn.closed() => info!("connection closed"),
+		r = tokio::signal::ctrl_c() => r?,
+	}
+	conn.close().await;
+	Ok(())
+}
+
+// Send stdin's lines on the channel, and print what the other peer's channels bring
+async fn stdio(conn: &Connection, dc: &Arc<RTCDataChannel>, stdin: &mut Lines<BufReader<Stdin>>) -> Result<()> {
+	loop {
+		tokio::select! {
+			line = stdin.next_line() => {
+				let Some(line) = line? else { return Ok(()) };
+				dc.send_text(line).await?;
+			}
+			Some(incoming) = conn.accept() => {
+				info!(label = incoming.label(), id = incoming.id(), "datachannel opened");
+				print_messages(&incoming);
+			}
+		}
+	}
+}
+
+fn print_messages(dc: &RTCDataChannel) {
+	dc.on_message(Box::new(|msg| {
+		match std::str::from_utf8(&msg.data) {
+			Ok(text) if msg.is_string => println!("{text}"),
+			_ => warn!(len = msg.data.len(), "ignoring a binary message"),
+		}
+		Box::pin(async {})
+	}));
+}
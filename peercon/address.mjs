@@ -2,6 +2,8 @@ import { pid } from 'wonk-identity';
 import { PeerId, b64url } from 'wonk-peerid';
 
 /**
+ * (peercon/src/address.rs parses and formats these addresses the same way for native code.)
+ *
  * === Examples (Aspirational, not working yet) ===
  * relayu:OW-4EPSfaEAJ8eljpvKOVW_gqJPUwV5-K2G0ulT1Qio@local.evan-brass.net:4666
  * - Connect via a proxy (turn:local.evan-brass.net:4666?transport=udp)
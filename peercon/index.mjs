@@ -18,14 +18,29 @@ function encode_candidates(candidates) {
 	// TODO: Firefox doesn't have address/port/type on RTCIceCandidate - it only has .candidate which means we have to parse it manually.
 	return candidates.map(c => `${c.type.substr(0, 1)}${c.port.toString(16).padStart(4, '0')}${c.address}`).join(',');
 }
+// Candidates can have extensions after their address (see peercon/src/sigmsg.rs): ~t<a|p|s> for TCP candidates and
+// ~p<hex> for an explicit priority.  Without one, a candidate's priority is its index + 1, which is why SigMsgs list
+// their candidates lowest priority first.
 function decode_candidates(s) {
 	return decodeURIComponent(s).split(',').map((s, i) => {
-		const type = ['host', 'srflx', 'relay'].find(t => t.startsWith(s.substring(0, 1)));
-		const port = parseInt(s.substring(1, 5), 16);
-		const address = s.substring(5);
-		
+		const [head, ...extensions] = s.split('~');
+		const type = ['host', 'srflx', 'prflx', 'relay'].find(t => t.startsWith(head.substring(0, 1)));
+		const port = parseInt(head.substring(1, 5), 16);
+		const address = head.substring(5);
+		let protocol = 'udp';
+		let priority = i + 1;
+		let tcptype = '';
+		for (const ext of extensions) {
+			if (ext.startsWith('t')) {
+				protocol = 'tcp';
+				tcptype = ' tcptype ' + ['active', 'passive', 'so'].find(t => t.startsWith(ext.substring(1)));
+			} else if (ext.startsWith('p')) {
+				priority = parseInt(ext.substring(1), 16);
+			}
+		}
+
 		return new RTCIceCandidate({
-			candidate: `candidate:foundation 1 udp ${i + 1} ${address} ${port} typ ${type}`,
+			candidate: `candidate:foundation 1 ${protocol} ${priority} ${address} ${port} typ ${type}${tcptype}`,
 			sdpMLineIndex: 0
 		});
 	});
@@ -59,7 +74,7 @@ export class SigMsg {
 	}
 	[Symbol.toPrimitive](_hint) {
 		console.log(this.ice_candidates);
-		const ice_candidates = this.ice_candidates.filter(c => c.protocol.toLowerCase() == 'udp').sort((a, b) => b.priority - a.priority);
+		const ice_candidates = this.ice_candidates.filter(c => c.protocol.toLowerCase() == 'udp').sort((a, b) => a.priority - b.priority);
 		const candidates = encode_candidates(ice_candidates);
 
 		return `${String(this.id)}.${
@@ -70,6 +85,7 @@ export class SigMsg {
 	}
 }
 
+// peer/ is a native peer that connects to PeerCons (see peer/src/lib.rs)
 export class PeerCon extends RTCPeerConnection {
 	// The 0 datachannel is used to determine when the connection has succeeded.  Once openned, it is used to renegotiate the connection.
 	#dc;
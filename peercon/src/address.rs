@@ -0,0 +1,417 @@
+// Peer addresses, matching peercon/address.mjs:
+// * relayu:<peer id>[:<token>]@<host>[:<port>]: connect through a TURN relay over udp (default port 3478)
+// * relayt:<peer id>[:<token>]@<host>[:<port>]: connect through a TURN relay over tcp (default port 3478)
+// * relayl:<peer id>[:<token>]@<host>[:<port>]: connect through a TURN relay over tls (default port 5349)
+// * udp:<peer id>[:<ice password>]@<host>[:<port>]: connect straight to an ICE-lite peer (default port 3478)
+// * web+kad:<peer id>[/<service>]: find the peer through the DHT
+// Without a token, the address is for starting a new connection (which gets a random token); with one, it's for
+// answering the connection that the token identifies.
+use std::{fmt, net::Ipv6Addr, str::FromStr};
+
+use peerid::{b64url, PeerId, PeerIdErr};
+use rand::RngCore;
+
+// Every relay takes the same password: the TURN username is what identifies the connection.
+pub const CREDENTIAL: &str = "the/turn/password/constant";
+// The ICE password of udp: addresses that don't give one
+pub const ICE_PWD: &str = "the/ice/password/constant";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scheme {
+	RelayU,
+	RelayT,
+	RelayL,
+	Udp,
+	WebKad,
+}
+impl Scheme {
+	pub const ALL: [Self; 5] = [Self::RelayU, Self::RelayT, Self::RelayL, Self::Udp, Self::WebKad];
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::RelayU => "relayu",
+			Self::RelayT => "relayt",
+			Self::RelayL => "relayl",
+			Self::Udp => "udp",
+			Self::WebKad => "web+kad",
+		}
+	}
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|s| s.name().eq_ignore_ascii_case(name))
+	}
+	pub fn is_relay(self) -> bool {
+		matches!(self, Self::RelayU | Self::RelayT | Self::RelayL)
+	}
+	// web+kad: addresses don't have a host
+	pub fn default_port(self) -> Option<u16> {
+		match self {
+			Self::RelayU | Self::RelayT | Self::Udp => Some(3478),
+			Self::RelayL => Some(5349),
+			Self::WebKad => None,
+		}
+	}
+	pub fn transport(self) -> Option<Transport> {
+		match self {
+			Self::RelayU | Self::Udp => Some(Transport::Udp),
+			Self::RelayT | Self::RelayL => Some(Transport::Tcp),
+			Self::WebKad => None,
+		}
+	}
+}
+impl fmt::Display for Scheme {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+	Udp,
+	Tcp,
+}
+impl fmt::Display for Transport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Udp => "udp",
+			Self::Tcp => "tcp",
+		})
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressErr {
+	UnknownScheme(String),
+	PeerId(PeerIdErr),
+	PercentEncoding,
+	MissingHost,
+	// An unterminated or invalid [IPv6 address]
+	InvalidHost,
+	InvalidPort,
+	// Something that the scheme doesn't have: a host on web+kad:, a service on the others
+	Unexpected(Scheme, &'static str),
+}
+impl fmt::Display for AddressErr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::UnknownScheme(scheme) => write!(f, "unknown address scheme {scheme:?}"),
+			Self::PeerId(e) => write!(f, "invalid peer id: {e}"),
+			Self::PercentEncoding => write!(f, "invalid percent encoding"),
+			Self::MissingHost => write!(f, "missing host"),
+			Self::InvalidHost => write!(f, "invalid IPv6 address"),
+			Self::InvalidPort => write!(f, "invalid port"),
+			Self::Unexpected(scheme, what) => write!(f, "{scheme}: addresses don't have a {what}"),
+		}
+	}
+}
+impl std::error::Error for AddressErr {}
+impl From<PeerIdErr> for AddressErr {
+	fn from(e: PeerIdErr) -> Self {
+		Self::PeerId(e)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+	pub scheme: Scheme,
+	pub peer_id: PeerId,
+	// relay schemes: the token of the connection being answered
+	pub token: Option<String>,
+	// udp: the ICE-lite peer's ICE password
+	pub ice_pwd: Option<String>,
+	// Everything but web+kad: the relay's (or ICE-lite peer's) hostname, without brackets around IPv6 addresses
+	pub host: Option<String>,
+	pub port: Option<u16>,
+	// web+kad: the service to open a datachannel to
+	pub service: Option<String>,
+}
+impl Address {
+	// The explicit port, or the scheme's default
+	pub fn port(&self) -> Option<u16> {
+		self.port.or(self.scheme.default_port())
+	}
+	pub fn transport(&self) -> Option<Transport> {
+		self.scheme.transport()
+	}
+	// host[:port] with IPv6 addresses bracketed, always including the port
+	pub fn host_port(&self) -> Option<String> {
+		Some(format!("{}:{}", bracket(self.host.as_deref()?), self.port()?))
+	}
+	// The iceServers url to reach a relay through (relay schemes only), like Address.urls
+	pub fn turn_url(&self) -> Option<String> {
+		let proto = match self.scheme {
+			Scheme::RelayU | Scheme::RelayT => "turn",
+			Scheme::RelayL => "turns",
+			_ => return None,
+		};
+		Some(format!("{proto}:{}?transport={}", self.host_port()?, self.transport()?))
+	}
+	// The TURN username to allocate with (relay schemes only): `dst.src.token`.  A new connection (no token) gets a
+	// random one.
+	pub fn username(&self, local_id: &PeerId) -> Option<String> {
+		if !self.scheme.is_relay() {
+			return None;
+		}
+		let token = self.token.clone().unwrap_or_else(|| gen_token(16));
+		Some(format!("{}.{local_id}.{token}", self.peer_id))
+	}
+	// The ICE ufrag and password of an ICE-lite peer (udp only): its ufrag is its peer id.
+	pub fn ice_credentials(&self) -> Option<(String, &str)> {
+		(self.scheme == Scheme::Udp).then(|| (self.peer_id.to_string(), self.ice_pwd.as_deref().unwrap_or(ICE_PWD)))
+	}
+}
+
+// A random base64url token of len bytes, like gen_token
+pub fn gen_token(len: usize) -> String {
+	let mut bytes = vec![0; len];
+	rand::thread_rng().fill_bytes(&mut bytes);
+	b64url::encode(&bytes)
+}
+
+impl FromStr for Address {
+	type Err = AddressErr;
+	fn from_str(s: &str) -> Result<Self, AddressErr> {
+		let (scheme, rest) = s.split_once(':').ok_or_else(|| AddressErr::UnknownScheme(s.into()))?;
+		let scheme = Scheme::from_name(scheme).ok_or_else(|| AddressErr::UnknownScheme(scheme.into()))?;
+		// Tolerate the `//` of URLs that have an authority
+		let rest = rest.strip_prefix("//").unwrap_or(rest);
+
+		if scheme == Scheme::WebKad {
+			if rest.contains('@') {
+				return Err(AddressErr::Unexpected(scheme, "host"));
+			}
+			let (peer_id, service) = match rest.split_once('/') {
+				Some((peer_id, service)) => (peer_id, Some(percent_decode(service)?).filter(|s| !s.is_empty())),
+				None => (rest, None),
+			};
+			return Ok(Self {
+				scheme,
+				peer_id: peer_id.parse()?,
+				token: None,
+				ice_pwd: None,
+				host: None,
+				port: None,
+				service,
+			});
+		}
+
+		// ICE passwords can contain '/' (and '@'), so the host is whatever follows the last '@'
+		let (userinfo, host) = rest.rsplit_once('@').ok_or(AddressErr::MissingHost)?;
+		let (peer_id, secret) = match userinfo.split_once(':') {
+			Some((peer_id, secret)) => (peer_id, Some(percent_decode(secret)?).filter(|s| !s.is_empty())),
+			None => (userinfo, None),
+		};
+		let host = host.strip_suffix('/').unwrap_or(host);
+		if host.contains('/') {
+			return Err(AddressErr::Unexpected(scheme, "service"));
+		}
+		let (host, port) = split_host(host)?;
+		let (token, ice_pwd) = if scheme == Scheme::Udp { (None, secret) } else { (secret, None) };
+		Ok(Self {
+			scheme,
+			peer_id: peer_id.parse()?,
+			token,
+			ice_pwd,
+			host: Some(host),
+			port,
+			service: None,
+		})
+	}
+}
+impl fmt::Display for Address {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.scheme, self.peer_id)?;
+		if let Some(service) = &self.service {
+			return write!(f, "/{}", percent_encode(service, b"/"));
+		}
+		if let Some(secret) = self.token.as_ref().or(self.ice_pwd.as_ref()) {
+			write!(f, ":{}", percent_encode(secret, b"/"))?;
+		}
+		if let Some(host) = &self.host {
+			write!(f, "@{}", bracket(host))?;
+		}
+		if let Some(port) = self.port {
+			write!(f, ":{port}")?;
+		}
+		Ok(())
+	}
+}
+
+// host, [v6], host:port or [v6]:port, read the way address.mjs's URL does for these (non-special) schemes: an
+// empty port is no port, IPv6 addresses are normalized, and other hosts are kept as they are.
+fn split_host(s: &str) -> Result<(String, Option<u16>), AddressErr> {
+	let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+		let (host, rest) = rest.split_once(']').ok_or(AddressErr::InvalidHost)?;
+		let host = host.parse::<Ipv6Addr>().map_err(|_| AddressErr::InvalidHost)?.to_string();
+		match rest {
+			"" => (host, None),
+			_ => (host, Some(rest.strip_prefix(':').ok_or(AddressErr::InvalidPort)?)),
+		}
+	} else {
+		match s.split_once(':') {
+			Some((host, port)) => (host.to_string(), Some(port)),
+			None => (s.to_string(), None),
+		}
+	};
+	if host.is_empty() {
+		return Err(AddressErr::MissingHost);
+	}
+	let port = match port {
+		None | Some("") => None,
+		Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => {
+			Some(port.parse::<u16>().map_err(|_| AddressErr::InvalidPort)?)
+		}
+		Some(_) => return Err(AddressErr::InvalidPort),
+	};
+	Ok((host, port))
+}
+
+fn bracket(host: &str) -> String {
+	if host.contains(':') {
+		format!("[{host}]")
+	} else {
+		host.into()
+	}
+}
+
+fn percent_decode(s: &str) -> Result<String, AddressErr> {
+	let mut ret = Vec::with_capacity(s.len());
+	let mut bytes = s.bytes();
+	while let Some(b) = bytes.next() {
+		ret.push(match b {
+			b'%' => {
+				let hex = [bytes.next(), bytes.next()];
+				let hex = hex.map(|b| b.and_then(|b| (b as char).to_digit(16)));
+				match hex {
+					[Some(hi), Some(lo)] => (hi * 16 + lo) as u8,
+					_ => return Err(AddressErr::PercentEncoding),
+				}
+			}
+			b => b,
+		});
+	}
+	String::from_utf8(ret).map_err(|_| AddressErr::PercentEncoding)
+}
+
+// Escape everything but unreserved characters (and `keep`)
+fn percent_encode(s: &str, keep: &[u8]) -> String {
+	let mut ret = String::with_capacity(s.len());
+	for b in s.bytes() {
+		if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || keep.contains(&b) {
+			ret.push(b as char);
+		} else {
+			ret.push_str(&format!("%{b:02X}"));
+		}
+	}
+	ret
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const A: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+
+	fn parse(s: &str) -> Result<Address, AddressErr> {
+		s.replace("<A>", A).parse()
+	}
+
+	// What address.mjs's Address reports for these (String(address), hostname, port, token, urls), in node
+	#[test]
+	fn like_js() {
+		for (s, host, port, token, url) in [
+			("relayu://<A>@example.com", "example.com", 3478, None, "turn:example.com:3478?transport=udp"),
+			("relayu://<A>@example.com:4666", "example.com", 4666, None, "turn:example.com:4666?transport=udp"),
+			("relayt://<A>:tok@Example.COM:80", "Example.COM", 80, Some("tok"), "turn:Example.COM:80?transport=tcp"),
+			("relayl://<A>:tok@example.com", "example.com", 5349, Some("tok"), "turns:example.com:5349?transport=tcp"),
+			("RELAYL://<A>@example.com:5349", "example.com", 5349, None, "turns:example.com:5349?transport=tcp"),
+			("relayu://<A>@[::1]:4666", "::1", 4666, None, "turn:[::1]:4666?transport=udp"),
+			("relayu://<A>@[::1]", "::1", 3478, None, "turn:[::1]:3478?transport=udp"),
+			("relayu://<A>@[2001:DB8:0::1]:80", "2001:db8::1", 80, None, "turn:[2001:db8::1]:80?transport=udp"),
+			("relayu://<A>@127.0.0.1:1", "127.0.0.1", 1, None, "turn:127.0.0.1:1?transport=udp"),
+			("relayu://<A>@example.com:65535", "example.com", 65535, None, "turn:example.com:65535?transport=udp"),
+			("relayu://<A>@example.com:03478", "example.com", 3478, None, "turn:example.com:3478?transport=udp"),
+			("relayu://<A>@example.com:", "example.com", 3478, None, "turn:example.com:3478?transport=udp"),
+			("relayu://<A>@example.com/", "example.com", 3478, None, "turn:example.com:3478?transport=udp"),
+			("relayu://<A>%7Csha-256@example.com", "example.com", 3478, None, "turn:example.com:3478?transport=udp"),
+		] {
+			let address = parse(s).unwrap_or_else(|e| panic!("{s}: {e}"));
+			assert_eq!(address.peer_id.to_string(), A, "{s}");
+			assert_eq!(address.host.as_deref(), Some(host), "{s}");
+			assert_eq!(address.port(), Some(port), "{s}");
+			assert_eq!(address.token.as_deref(), token, "{s}");
+			assert_eq!(address.turn_url().as_deref(), Some(url), "{s}");
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		for s in [
+			"relayu:<A>@example.com",
+			"relayt:<A>:tok@Example.com:80",
+			"relayl:<A>:a/b%40c@example.com:5349",
+			"relayu:<A>@[2001:db8::1]:4666",
+			"udp:<A>@192.0.2.1",
+			"udp:<A>:this/is/ice/password@[::1]:3478",
+			"web+kad:<A>",
+			"web+kad:<A>/p2p-chat",
+			"web+kad:<A>/a/b%20c",
+		] {
+			let address = parse(s).unwrap_or_else(|e| panic!("{s}: {e}"));
+			assert_eq!(address.to_string(), s.replace("<A>", A));
+			assert_eq!(address.to_string().parse(), Ok(address));
+		}
+		let address = parse("relayl:<A>:a%2Fb%40c@example.com").unwrap();
+		assert_eq!(address.token.as_deref(), Some("a/b@c"));
+		let address = parse("udp:<A>:this/is/ice/password@[::1]").unwrap();
+		assert_eq!(address.ice_credentials(), Some((A.into(), "this/is/ice/password")));
+		assert_eq!(address.token, None);
+		assert_eq!(parse("udp:<A>@[::1]").unwrap().ice_credentials(), Some((A.into(), ICE_PWD)));
+		let address = parse("web+kad:<A>/a/b%20c").unwrap();
+		assert_eq!((address.service.as_deref(), address.host.as_deref(), address.port()), (Some("a/b c"), None, None));
+	}
+
+	#[test]
+	fn rejected() {
+		for (s, err) in [
+			// Bad ports
+			("relayu:<A>@example.com:65536", AddressErr::InvalidPort),
+			("relayu:<A>@example.com:-1", AddressErr::InvalidPort),
+			("relayu:<A>@example.com:+1", AddressErr::InvalidPort),
+			("relayu:<A>@example.com:port", AddressErr::InvalidPort),
+			("relayu:<A>@example.com:1:2", AddressErr::InvalidPort),
+			// IPv6 addresses have to be bracketed, and valid
+			("relayu:<A>@::1", AddressErr::MissingHost),
+			("relayu:<A>@2001:db8::1", AddressErr::InvalidPort),
+			("relayu:<A>@[::1", AddressErr::InvalidHost),
+			("relayu:<A>@[::1]x", AddressErr::InvalidPort),
+			("relayu:<A>@[example.com]", AddressErr::InvalidHost),
+			("relayu:<A>@[]:3478", AddressErr::InvalidHost),
+			// Unknown schemes
+			("turn:<A>@example.com", AddressErr::UnknownScheme("turn".into())),
+			("http://<A>@example.com", AddressErr::UnknownScheme("http".into())),
+			("nope", AddressErr::UnknownScheme("nope".into())),
+			// Missing or unexpected parts
+			("relayu:<A>", AddressErr::MissingHost),
+			("relayu:<A>@", AddressErr::MissingHost),
+			("relayu:<A>@:3478", AddressErr::MissingHost),
+			("relayu:<A>@example.com/service", AddressErr::Unexpected(Scheme::RelayU, "service")),
+			("web+kad:<A>@example.com", AddressErr::Unexpected(Scheme::WebKad, "host")),
+			("relayu:<A>:%ZZ@example.com", AddressErr::PercentEncoding),
+		] {
+			assert_eq!(parse(s), Err(err), "{s}");
+		}
+		assert!(matches!(parse("relayu:nonsense@example.com"), Err(AddressErr::PeerId(_))));
+	}
+
+	#[test]
+	fn usernames() {
+		let local = PeerId::from_id([7; 32]);
+		let address = parse("relayu:<A>:tok@example.com").unwrap();
+		assert_eq!(address.username(&local), Some(format!("{A}.{local}.tok")));
+		// A new connection gets a fresh 16 byte token
+		let address = parse("relayu:<A>@example.com").unwrap();
+		let username = address.username(&local).unwrap();
+		assert_eq!(username.rsplit('.').next().unwrap().len(), 22);
+		assert_ne!(address.username(&local), Some(username));
+		assert_eq!(parse("udp:<A>@example.com").unwrap().username(&local), None);
+	}
+}
@@ -0,0 +1,5 @@
+// The native side of peercon/index.mjs: what peers send each other to connect.
+mod address;
+mod sigmsg;
+pub use address::*;
+pub use sigmsg::*;
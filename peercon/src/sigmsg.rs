@@ -0,0 +1,400 @@
+// Signaling messages, matching SigMsg in peercon/index.mjs: `<peer id>.<ice ufrag>.<ice pwd>.<candidates>`, where the
+// ufrag and pwd have ICE's '+' and '/' swapped for '-' and '_'.  Candidates are comma separated, each packed as:
+//   <type><port><address>[~<extension>]...
+// * type: the first letter of host, srflx, prflx or relay
+// * port: 4 lowercase hex digits
+// * address: an IPv4 or IPv6 address, or an mDNS name (`<uuid>.local`)
+// * extensions (which older decoders don't know about, so they're only written when needed):
+//   * ~t<a|p|s>: a TCP candidate, with its tcptype (active, passive, so).  Without it, the candidate is UDP.
+//   * ~p<hex>: the candidate's priority.  Without it, the candidate at index i gets priority i + 1, so candidates
+//     are listed lowest priority first.
+//   Decoders skip extensions that they don't recognize.
+// A UDP candidate without a priority encodes exactly like encode_candidates does.
+use std::{
+	fmt::{self, Write},
+	net::IpAddr,
+	str::FromStr,
+};
+
+use peerid::{PeerId, PeerIdErr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigMsgErr {
+	// Fewer than four '.' separated parts
+	MissingParts,
+	PeerId(PeerIdErr),
+	// Ufrags and passwords are ice-chars (RFC 8445): alphanumerics, '+' and '/'
+	InvalidIceChars,
+	PercentEncoding,
+	UnknownType(char),
+	InvalidPort,
+	InvalidAddress(String),
+	InvalidExtension(String),
+	// A candidate attribute that we couldn't read
+	InvalidSdp(String),
+}
+impl fmt::Display for SigMsgErr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::MissingParts => write!(f, "expected <peer id>.<ice ufrag>.<ice pwd>.<candidates>"),
+			Self::PeerId(e) => write!(f, "invalid peer id: {e}"),
+			Self::InvalidIceChars => write!(f, "ice ufrag and pwd can only be alphanumerics, '+' and '/'"),
+			Self::PercentEncoding => write!(f, "invalid percent encoding"),
+			Self::UnknownType(c) => write!(f, "unknown candidate type {c:?}"),
+			Self::InvalidPort => write!(f, "candidate port isn't 4 hex digits"),
+			Self::InvalidAddress(addr) => write!(f, "{addr:?} isn't an ip address or mDNS name"),
+			Self::InvalidExtension(ext) => write!(f, "invalid candidate extension {ext:?}"),
+			Self::InvalidSdp(line) => write!(f, "invalid candidate attribute {line:?}"),
+		}
+	}
+}
+impl std::error::Error for SigMsgErr {}
+impl From<PeerIdErr> for SigMsgErr {
+	fn from(e: PeerIdErr) -> Self {
+		Self::PeerId(e)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandidateType {
+	Host,
+	Srflx,
+	Prflx,
+	Relay,
+}
+impl CandidateType {
+	pub const ALL: [Self; 4] = [Self::Host, Self::Srflx, Self::Prflx, Self::Relay];
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Host => "host",
+			Self::Srflx => "srflx",
+			Self::Prflx => "prflx",
+			Self::Relay => "relay",
+		}
+	}
+	fn letter(self) -> char {
+		self.name().as_bytes()[0] as char
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpType {
+	Active,
+	Passive,
+	So,
+}
+impl TcpType {
+	pub const ALL: [Self; 3] = [Self::Active, Self::Passive, Self::So];
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Active => "active",
+			Self::Passive => "passive",
+			Self::So => "so",
+		}
+	}
+	fn letter(self) -> char {
+		self.name().as_bytes()[0] as char
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+	Udp,
+	Tcp(TcpType),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CandidateAddr {
+	Ip(IpAddr),
+	// An mDNS name that hides a host candidate's address, like `1f4712db-ea17-4bcf-a596-105139dfd8bf.local`
+	Mdns(String),
+}
+impl FromStr for CandidateAddr {
+	type Err = SigMsgErr;
+	fn from_str(s: &str) -> Result<Self, SigMsgErr> {
+		if let Ok(ip) = s.parse() {
+			return Ok(Self::Ip(ip));
+		}
+		let name = s.strip_suffix(".local").filter(|name| {
+			!name.is_empty() && name.split('.').all(|label| {
+				!label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+			})
+		});
+		match name {
+			Some(_) => Ok(Self::Mdns(s.to_ascii_lowercase())),
+			None => Err(SigMsgErr::InvalidAddress(s.into())),
+		}
+	}
+}
+impl fmt::Display for CandidateAddr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Ip(ip) => write!(f, "{ip}"),
+			Self::Mdns(name) => f.write_str(name),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Candidate {
+	pub typ: CandidateType,
+	pub protocol: Protocol,
+	pub address: CandidateAddr,
+	pub port: u16,
+	pub priority: Option<u32>,
+}
+impl Candidate {
+	// Read a candidate attribute (`[a=]candidate:<foundation> <component> <transport> <priority> <address> <port> typ
+	// <type> ...`), keeping its priority.
+	pub fn from_sdp(line: &str) -> Result<Self, SigMsgErr> {
+		let bad = || SigMsgErr::InvalidSdp(line.into());
+		let rest = line.trim();
+		let rest = rest.strip_prefix("a=").unwrap_or(rest);
+		let rest = rest.strip_prefix("candidate:").ok_or_else(bad)?;
+		let fields: Vec<&str> = rest.split_ascii_whitespace().collect();
+		let [_foundation, _component, transport, priority, address, port, "typ", typ, extra @ ..] = &fields[..] else {
+			return Err(bad());
+		};
+		let typ = CandidateType::ALL.into_iter().find(|t| t.name() == *typ).ok_or_else(bad)?;
+		let protocol = if transport.eq_ignore_ascii_case("udp") {
+			Protocol::Udp
+		} else if transport.eq_ignore_ascii_case("tcp") {
+			let tcptype = extra.chunks(2).find_map(|kv| match kv {
+				["tcptype", t] => TcpType::ALL.into_iter().find(|tt| tt.name() == *t),
+				_ => None,
+			});
+			Protocol::Tcp(tcptype.ok_or_else(bad)?)
+		} else {
+			return Err(bad());
+		};
+		Ok(Self {
+			typ,
+			protocol,
+			address: address.parse()?,
+			port: port.parse().map_err(|_| bad())?,
+			priority: Some(priority.parse().map_err(|_| bad())?),
+		})
+	}
+	// The candidate attribute (without `a=`) for the candidate at index in its SigMsg, like decode_candidates: a
+	// candidate without a priority gets index + 1.
+	pub fn sdp(&self, index: usize) -> String {
+		let priority = self.priority.unwrap_or(index as u32 + 1);
+		let (transport, tcptype) = match self.protocol {
+			Protocol::Udp => ("udp", String::new()),
+			Protocol::Tcp(t) => ("tcp", format!(" tcptype {}", t.name())),
+		};
+		let typ = self.typ.name();
+		format!("candidate:foundation 1 {transport} {priority} {} {} typ {typ}{tcptype}", self.address, self.port)
+	}
+}
+impl FromStr for Candidate {
+	type Err = SigMsgErr;
+	fn from_str(s: &str) -> Result<Self, SigMsgErr> {
+		let mut parts = s.split('~');
+		let head = parts.next().unwrap_or_default();
+		let letter = head.chars().next().ok_or(SigMsgErr::UnknownType(' '))?;
+		let typ = CandidateType::ALL
+			.into_iter()
+			.find(|t| t.letter() == letter)
+			.ok_or(SigMsgErr::UnknownType(letter))?;
+		let port = head.get(1..5).filter(|p| p.bytes().all(|b| b.is_ascii_hexdigit())).ok_or(SigMsgErr::InvalidPort)?;
+		let port = u16::from_str_radix(port, 16).map_err(|_| SigMsgErr::InvalidPort)?;
+		let address = head[5..].parse()?;
+
+		let mut protocol = Protocol::Udp;
+		let mut priority = None;
+		for ext in parts {
+			let bad = || SigMsgErr::InvalidExtension(ext.into());
+			match ext.split_at_checked(1) {
+				Some(("t", t)) => {
+					let t = TcpType::ALL.into_iter().find(|tt| tt.letter().to_string() == t).ok_or_else(bad)?;
+					protocol = Protocol::Tcp(t);
+				}
+				Some(("p", p)) => priority = Some(u32::from_str_radix(p, 16).map_err(|_| bad())?),
+				Some(_) => {}
+				None => return Err(bad()),
+			}
+		}
+		Ok(Self {
+			typ,
+			protocol,
+			address,
+			port,
+			priority,
+		})
+	}
+}
+impl fmt::Display for Candidate {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}{:04x}{}", self.typ.letter(), self.port, self.address)?;
+		if let Protocol::Tcp(t) = self.protocol {
+			write!(f, "~t{}", t.letter())?;
+		}
+		if let Some(priority) = self.priority {
+			write!(f, "~p{priority:x}")?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigMsg {
+	pub id: PeerId,
+	// In ICE's alphabet (with '+' and '/')
+	pub ice_ufrag: String,
+	pub ice_pwd: String,
+	pub candidates: Vec<Candidate>,
+}
+impl FromStr for SigMsg {
+	type Err = SigMsgErr;
+	fn from_str(s: &str) -> Result<Self, SigMsgErr> {
+		let mut parts = s.splitn(4, '.');
+		let (Some(id), Some(ice_ufrag), Some(ice_pwd), Some(candidates)) =
+			(parts.next(), parts.next(), parts.next(), parts.next())
+		else {
+			return Err(SigMsgErr::MissingParts);
+		};
+		let candidates = percent_decode(candidates).ok_or(SigMsgErr::PercentEncoding)?;
+		Ok(Self {
+			id: id.parse()?,
+			ice_ufrag: from_url_safe(ice_ufrag)?,
+			ice_pwd: from_url_safe(ice_pwd)?,
+			candidates: candidates.split(',').filter(|c| !c.is_empty()).map(str::parse).collect::<Result<_, _>>()?,
+		})
+	}
+}
+impl fmt::Display for SigMsg {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{}.{}.", self.id, to_url_safe(&self.ice_ufrag), to_url_safe(&self.ice_pwd))?;
+		for (i, candidate) in self.candidates.iter().enumerate() {
+			if i != 0 {
+				f.write_char(',')?;
+			}
+			write!(f, "{candidate}")?;
+		}
+		Ok(())
+	}
+}
+
+fn to_url_safe(s: &str) -> String {
+	s.replace('+', "-").replace('/', "_")
+}
+fn from_url_safe(s: &str) -> Result<String, SigMsgErr> {
+	if s.is_empty() || !s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+		return Err(SigMsgErr::InvalidIceChars);
+	}
+	Ok(s.replace('-', "+").replace('_', "/"))
+}
+
+// decodeURIComponent
+fn percent_decode(s: &str) -> Option<String> {
+	let mut ret = Vec::with_capacity(s.len());
+	let mut bytes = s.bytes();
+	while let Some(b) = bytes.next() {
+		ret.push(match b {
+			b'%' => {
+				let hex = [bytes.next()?, bytes.next()?];
+				let hex = hex.map(|b| (b as char).to_digit(16));
+				(hex[0]? * 16 + hex[1]?) as u8
+			}
+			b => b,
+		});
+	}
+	String::from_utf8(ret).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const A: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+
+	// Read a SigMsg, check its candidates against the attributes that index.mjs's decode_candidates makes of it, and
+	// check that it writes back out as written.
+	fn check(s: &str, candidates: &[&str], written: &str) -> SigMsg {
+		let s = s.replace("<A>", A);
+		let msg: SigMsg = s.parse().unwrap_or_else(|e| panic!("{s}: {e}"));
+		let sdp: Vec<String> = msg.candidates.iter().enumerate().map(|(i, c)| c.sdp(i)).collect();
+		assert_eq!(sdp, candidates, "{s}");
+		assert_eq!(msg.to_string(), written.replace("<A>", A));
+		assert_eq!(msg.to_string().parse(), Ok(msg.clone()));
+		msg
+	}
+
+	// String(new SigMsg(...)) in index.mjs, from a relay, a srflx and an mDNS host candidate (and a TCP one that it
+	// leaves out)
+	#[test]
+	fn from_js() {
+		let s = "<A>.ab-_.pass-word_0123456789abcd.\
+			r0d96203.0.113.7,s00092001:db8::1,hd4311f4712db-ea17-4bcf-a596-105139dfd8bf.local";
+		let msg = check(
+			s,
+			&[
+				"candidate:foundation 1 udp 1 203.0.113.7 3478 typ relay",
+				"candidate:foundation 1 udp 2 2001:db8::1 9 typ srflx",
+				"candidate:foundation 1 udp 3 1f4712db-ea17-4bcf-a596-105139dfd8bf.local 54321 typ host",
+			],
+			s,
+		);
+		assert_eq!(msg.id.to_string(), A);
+		assert_eq!((msg.ice_ufrag.as_str(), msg.ice_pwd.as_str()), ("ab+/", "pass+word/0123456789abcd"));
+	}
+
+	// TCP candidates and explicit priorities, which decode_candidates reads, but String(SigMsg) never writes.  Unknown
+	// extensions are skipped, and percent encoding is undone.
+	#[test]
+	fn extensions() {
+		let msg = check(
+			"<A>.abcd.efgh.h0d9410.0.0.1~ta,r0d96203.0.113.7~p1ff,s0009%3A%3A1~tp~p7e7f00ff~zfuture,p00501.2.3.4~ts",
+			&[
+				"candidate:foundation 1 tcp 1 10.0.0.1 3476 typ host tcptype active",
+				"candidate:foundation 1 udp 511 203.0.113.7 3478 typ relay",
+				"candidate:foundation 1 tcp 2122252543 ::1 9 typ srflx tcptype passive",
+				"candidate:foundation 1 tcp 4 1.2.3.4 80 typ prflx tcptype so",
+			],
+			"<A>.abcd.efgh.h0d9410.0.0.1~ta,r0d96203.0.113.7~p1ff,s0009::1~tp~p7e7f00ff,p00501.2.3.4~ts",
+		);
+		assert_eq!(msg.candidates[2].protocol, Protocol::Tcp(TcpType::Passive));
+		assert_eq!(msg.candidates[2].priority, Some(0x7e7f00ff));
+	}
+
+	#[test]
+	fn from_sdp() {
+		let line = "a=candidate:842163049 1 udp 1677729535 203.0.113.7 61234 typ srflx raddr 0.0.0.0 rport 0";
+		let candidate = Candidate::from_sdp(line).unwrap();
+		assert_eq!(candidate.to_string(), "sef32203.0.113.7~p64001eff");
+		let line = "candidate:1 1 TCP 1518280447 192.168.1.2 9 typ host tcptype active";
+		let candidate = Candidate::from_sdp(line).unwrap();
+		assert_eq!(candidate.to_string(), "h0009192.168.1.2~ta~p5a7f1eff");
+		assert_eq!(candidate.sdp(0), "candidate:foundation 1 tcp 1518280447 192.168.1.2 9 typ host tcptype active");
+		for line in [
+			"candidate:1 1 udp 1 203.0.113.7 9 typ nonsense",
+			"candidate:1 1 tcp 1 203.0.113.7 9 typ host",
+			"candidate:1 1 sctp 1 203.0.113.7 9 typ host",
+			"candidate:1 1 udp 1 203.0.113.7 99999 typ host",
+			"candidate:1 1 udp 1 203.0.113.7 9",
+		] {
+			assert!(Candidate::from_sdp(line).is_err(), "{line}");
+		}
+	}
+
+	#[test]
+	fn rejected() {
+		for (s, err) in [
+			("<A>.abcd.efgh", SigMsgErr::MissingParts),
+			("<A>.ab+d.efgh.", SigMsgErr::InvalidIceChars),
+			("<A>..efgh.", SigMsgErr::InvalidIceChars),
+			("<A>.abcd.efgh.x00501.2.3.4", SigMsgErr::UnknownType('x')),
+			("<A>.abcd.efgh.h00g01.2.3.4", SigMsgErr::InvalidPort),
+			("<A>.abcd.efgh.h005", SigMsgErr::InvalidPort),
+			("<A>.abcd.efgh.h0050example.com", SigMsgErr::InvalidAddress("example.com".into())),
+			("<A>.abcd.efgh.h00501.2.3.4~tx", SigMsgErr::InvalidExtension("tx".into())),
+			("<A>.abcd.efgh.h00501.2.3.4~pzz", SigMsgErr::InvalidExtension("pzz".into())),
+			("<A>.abcd.efgh.h00501.2.3.4~", SigMsgErr::InvalidExtension("".into())),
+			("<A>.abcd.efgh.h00501.2.3.4%ZZ", SigMsgErr::PercentEncoding),
+		] {
+			assert_eq!(s.replace("<A>", A).parse::<SigMsg>(), Err(err), "{s}");
+		}
+		assert!(matches!("nonsense.abcd.efgh.".parse::<SigMsg>(), Err(SigMsgErr::PeerId(_))));
+	}
+}
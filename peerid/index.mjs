@@ -5,7 +5,7 @@ const { buftobinstr, btoa_url, binstrtobuf, atob_url } = b64url;
 const untagged = new Map();
 untagged.set(20, 'sha-1');
 untagged.set(32, 'sha-256');
-untagged.set(48, 'sha-284');
+untagged.set(48, 'sha-384');
 untagged.set(64, 'sha-512');
 
 export const advanced_usage = {
@@ -24,15 +24,15 @@ export class PeerId {
 		function decode(encoded) {
 			const fingerprints = Object.create(null);
 
-			const parts = decodeURIComponent(encoded).split('|');
-			if (parts.length < 1) throw new Error('Not a PeerId');
-
-			// TODO: Support multiple fingerprints?
-			const value = atob_url(parts.shift());
-			const alg = (parts.length > 0) ? parts.shift() : untagged.get(value.length);
-			if (!alg) throw new Error("Didn't recognize this untagged PeerId");
-			if (alg != advanced_usage.id_fingerprint) throw new Error("This PeerId didn't include the id_fingerprint.");
-			fingerprints[alg] = value;
+			// Several fingerprints can be given, comma separated (see to_string_all)
+			for (const part of decodeURIComponent(encoded).split(',')) {
+				const parts = part.split('|');
+				const value = atob_url(parts.shift());
+				const alg = (parts.length > 0) ? parts.shift().toLowerCase() : untagged.get(value.length);
+				if (!alg) throw new Error("Didn't recognize this untagged PeerId");
+				if (alg in fingerprints) throw new Error(`More than one ${alg} fingerprint`);
+				fingerprints[alg] = value;
+			}
 			return { fingerprints };
 		}
 		const {fingerprints} = (typeof arg == 'string') ? decode(arg) : arg;
@@ -61,10 +61,15 @@ export class PeerId {
 		// Append the hash algorithm if it's not an untagged alg
 		if (![...untagged.values()].includes(fingerprint)) {
 			ret += '|';
-			ret += advanced_usage.id_fingerprint;
+			ret += fingerprint;
 		}
 		return encodeURIComponent(ret);
 	}
+	// Every fingerprint, with the id fingerprint first
+	to_string_all() {
+		const others = Object.keys(this.fingerprints).filter(alg => alg != advanced_usage.id_fingerprint).sort();
+		return [advanced_usage.id_fingerprint, ...others].map(alg => this.to_string(alg)).join(',');
+	}
 	to_bigint(fingerprint = advanced_usage.id_fingerprint) {
 		let agg = '0x';
 		for (const b of this.to_buff(fingerprint)) {
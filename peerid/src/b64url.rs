@@ -0,0 +1,33 @@
+// Unpadded base64url, like b64url.mjs's btoa_url / atob_url.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn encode(data: &[u8]) -> String {
+	let mut ret = String::with_capacity((data.len() * 4).div_ceil(3));
+	for chunk in data.chunks(3) {
+		let mut group = [0u8; 3];
+		group[..chunk.len()].copy_from_slice(chunk);
+		let n = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+		for i in 0..=chunk.len() {
+			ret.push(ALPHABET[((n >> (18 - i * 6)) & 0x3F) as usize] as char);
+		}
+	}
+	ret
+}
+
+// Padding is tolerated (atob_url pads before decoding anyway)
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+	let s = s.trim_end_matches('=').as_bytes();
+	if s.len() % 4 == 1 {
+		return None;
+	}
+	let mut ret = Vec::with_capacity(s.len() * 3 / 4);
+	for chunk in s.chunks(4) {
+		let mut n = 0u32;
+		for (i, c) in chunk.iter().enumerate() {
+			let v = ALPHABET.iter().position(|b| b == c)? as u32;
+			n |= v << (18 - i * 6);
+		}
+		ret.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+	}
+	Some(ret)
+}
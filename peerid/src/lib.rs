@@ -0,0 +1,336 @@
+// Peer identities, matching peerid/index.mjs: a peer is identified by a fingerprint of its DTLS certificate (the
+// sha-256 one, advanced_usage.id_fingerprint), written as unpadded base64url.  Untagged fingerprints are recognized
+// by their length; other fingerprints are tagged with their algorithm: `<base64url>|<algorithm>`.
+use std::{
+	collections::BTreeMap,
+	fmt::{self, Write},
+	hash::{Hash, Hasher},
+	ops::BitXor,
+	str::FromStr,
+};
+
+pub mod b64url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Algorithm {
+	Sha1,
+	Sha256,
+	Sha384,
+	Sha512,
+}
+impl Algorithm {
+	pub const ALL: [Self; 4] = [Self::Sha1, Self::Sha256, Self::Sha384, Self::Sha512];
+	// The name in SDP's a=fingerprint lines (and in tags)
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Sha1 => "sha-1",
+			Self::Sha256 => "sha-256",
+			Self::Sha384 => "sha-384",
+			Self::Sha512 => "sha-512",
+		}
+	}
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|alg| alg.name().eq_ignore_ascii_case(name))
+	}
+	// Digest length in bytes
+	#[allow(clippy::len_without_is_empty)]
+	pub fn len(self) -> usize {
+		match self {
+			Self::Sha1 => 20,
+			Self::Sha256 => 32,
+			Self::Sha384 => 48,
+			Self::Sha512 => 64,
+		}
+	}
+	// Which algorithm an untagged fingerprint is
+	pub fn from_len(len: usize) -> Option<Self> {
+		Self::ALL.into_iter().find(|alg| alg.len() == len)
+	}
+}
+impl fmt::Display for Algorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+// The fingerprint that identifies a peer
+pub const ID_FINGERPRINT: Algorithm = Algorithm::Sha256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerIdErr {
+	// Bad %XX escapes, or they didn't decode to utf-8
+	PercentEncoding,
+	NotBase64Url,
+	// Untagged, and not the length of any algorithm that we know
+	UnknownLength(usize),
+	UnknownAlgorithm(String),
+	// The fingerprint's length doesn't match its algorithm
+	WrongLength(Algorithm, usize),
+	Duplicate(Algorithm),
+	// An a=fingerprint line that isn't colon separated hex bytes
+	InvalidHex,
+	// Every PeerId has to have the id fingerprint
+	MissingId,
+}
+impl fmt::Display for PeerIdErr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::PercentEncoding => write!(f, "invalid percent encoding"),
+			Self::NotBase64Url => write!(f, "not base64url"),
+			Self::UnknownLength(len) => write!(f, "untagged fingerprint of unknown length {len}"),
+			Self::UnknownAlgorithm(alg) => write!(f, "unknown fingerprint algorithm {alg:?}"),
+			Self::WrongLength(alg, len) => write!(f, "a {alg} fingerprint is {} bytes, not {len}", alg.len()),
+			Self::Duplicate(alg) => write!(f, "more than one {alg} fingerprint"),
+			Self::InvalidHex => write!(f, "fingerprint isn't hex"),
+			Self::MissingId => write!(f, "no {ID_FINGERPRINT} fingerprint"),
+		}
+	}
+}
+impl std::error::Error for PeerIdErr {}
+
+// The id fingerprint as a big-endian 256 bit unsigned integer (to_bigint).  Comparing these compares the numbers,
+// and XORing two of them gives their (Kademlia) distance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct U256(pub [u8; 32]);
+impl BitXor for U256 {
+	type Output = Self;
+	fn bitxor(self, rhs: Self) -> Self {
+		Self(std::array::from_fn(|i| self.0[i] ^ rhs.0[i]))
+	}
+}
+impl U256 {
+	// The number of leading zero bits (256 for zero), which is the length of the prefix two ids share when this is
+	// their distance.
+	pub fn leading_zeros(&self) -> u32 {
+		match self.0.iter().position(|b| *b != 0) {
+			Some(i) => i as u32 * 8 + self.0[i].leading_zeros(),
+			None => 256,
+		}
+	}
+}
+impl fmt::LowerHex for U256 {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if f.alternate() {
+			f.write_str("0x")?;
+		}
+		self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+	}
+}
+
+// A peer's fingerprints.  The id fingerprint is its identity: PeerIds are equal, hashed and ordered by it alone (in
+// the order of to_bigint), so two PeerIds with the same id but different extra fingerprints are the same peer.
+#[derive(Debug, Clone)]
+pub struct PeerId {
+	fingerprints: BTreeMap<Algorithm, Box<[u8]>>,
+}
+impl PeerId {
+	pub fn new(fingerprints: impl IntoIterator<Item = (Algorithm, Vec<u8>)>) -> Result<Self, PeerIdErr> {
+		let mut map = BTreeMap::new();
+		for (alg, value) in fingerprints {
+			if value.len() != alg.len() {
+				return Err(PeerIdErr::WrongLength(alg, value.len()));
+			}
+			if map.insert(alg, value.into_boxed_slice()).is_some() {
+				return Err(PeerIdErr::Duplicate(alg));
+			}
+		}
+		if !map.contains_key(&ID_FINGERPRINT) {
+			return Err(PeerIdErr::MissingId);
+		}
+		Ok(Self { fingerprints: map })
+	}
+	pub fn from_id(id: [u8; 32]) -> Self {
+		Self {
+			fingerprints: BTreeMap::from([(ID_FINGERPRINT, id.into())]),
+		}
+	}
+	// The fingerprints from an SDP's a=fingerprint lines (the first one for each algorithm, like OwnPeerId.from_cert).
+	// Lines for algorithms that we don't know are skipped.
+	pub fn from_sdp(sdp: &str) -> Result<Self, PeerIdErr> {
+		let mut fingerprints = BTreeMap::new();
+		for line in sdp.lines() {
+			let Some(rest) = line.trim().strip_prefix("a=fingerprint:") else { continue };
+			let Some((alg, value)) = rest.split_once(' ') else { continue };
+			let Some(alg) = Algorithm::from_name(alg) else { continue };
+			if fingerprints.contains_key(&alg) {
+				continue;
+			}
+			let value = value
+				.trim()
+				.split(':')
+				.map(|b| {
+					let hex = b.len() == 2 && b.bytes().all(|c| c.is_ascii_hexdigit());
+					hex.then(|| u8::from_str_radix(b, 16).unwrap())
+				})
+				.collect::<Option<Vec<u8>>>()
+				.ok_or(PeerIdErr::InvalidHex)?;
+			fingerprints.insert(alg, value);
+		}
+		Self::new(fingerprints)
+	}
+	pub fn fingerprint(&self, alg: Algorithm) -> Option<&[u8]> {
+		self.fingerprints.get(&alg).map(|v| &v[..])
+	}
+	pub fn fingerprints(&self) -> impl Iterator<Item = (Algorithm, &[u8])> {
+		self.fingerprints.iter().map(|(alg, v)| (*alg, &v[..]))
+	}
+	pub fn id(&self) -> [u8; 32] {
+		self.fingerprints[&ID_FINGERPRINT][..].try_into().unwrap()
+	}
+	pub fn to_bigint(&self) -> U256 {
+		U256(self.id())
+	}
+	pub fn distance(&self, other: &Self) -> U256 {
+		self.to_bigint() ^ other.to_bigint()
+	}
+	// Are we the polite peer (in perfect negotiation)?  The peer with the smaller id is.
+	pub fn polite(&self, other: &Self) -> bool {
+		self.to_bigint() < other.to_bigint()
+	}
+	// One fingerprint, formatted like to_string(fingerprint).  Every algorithm we know has a distinct length, so
+	// they're never tagged.
+	pub fn encode(&self, alg: Algorithm) -> Option<String> {
+		self.fingerprint(alg).map(b64url::encode)
+	}
+	// Every fingerprint, comma separated with the id first.  PeerId::from_str reads this back, but index.mjs only
+	// reads a single fingerprint, so this isn't for sending to browsers.
+	pub fn encode_all(&self) -> String {
+		let others = self.fingerprints().filter(|(alg, _)| *alg != ID_FINGERPRINT);
+		let mut ret = self.to_string();
+		for (_, value) in others {
+			ret.push(',');
+			ret.push_str(&b64url::encode(value));
+		}
+		ret
+	}
+	// a=fingerprint lines for some of our fingerprints (with lowercase hex, like sdp())
+	pub fn sdp(&self, algs: &[Algorithm]) -> String {
+		let mut ret = String::new();
+		for (alg, value) in algs.iter().filter_map(|alg| Some((alg, self.fingerprint(*alg)?))) {
+			let _ = write!(ret, "a=fingerprint:{alg} ");
+			for (i, b) in value.iter().enumerate() {
+				let _ = write!(ret, "{}{b:02x}", if i == 0 { "" } else { ":" });
+			}
+			ret.push('\n');
+		}
+		ret
+	}
+}
+
+// Read a PeerId the way PeerId's constructor does: URI decode it, then `<base64url>` or `<base64url>|<algorithm>`.
+// Several fingerprints can be given, comma separated (see encode_all).
+impl FromStr for PeerId {
+	type Err = PeerIdErr;
+	fn from_str(s: &str) -> Result<Self, PeerIdErr> {
+		let s = decode_uri_component(s).ok_or(PeerIdErr::PercentEncoding)?;
+		let mut fingerprints = Vec::new();
+		for part in s.split(',') {
+			let (value, alg) = match part.split_once('|') {
+				Some((value, alg)) => (value, Some(alg)),
+				None => (part, None),
+			};
+			let value = b64url::decode(value).ok_or(PeerIdErr::NotBase64Url)?;
+			let alg = match alg {
+				Some(alg) => Algorithm::from_name(alg).ok_or_else(|| PeerIdErr::UnknownAlgorithm(alg.into()))?,
+				None => Algorithm::from_len(value.len()).ok_or(PeerIdErr::UnknownLength(value.len()))?,
+			};
+			fingerprints.push((alg, value));
+		}
+		Self::new(fingerprints)
+	}
+}
+// The id fingerprint, like String(peer_id): this is the canonical form.
+impl fmt::Display for PeerId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&b64url::encode(&self.id()))
+	}
+}
+impl PartialEq for PeerId {
+	fn eq(&self, other: &Self) -> bool {
+		self.id() == other.id()
+	}
+}
+impl Eq for PeerId {}
+impl Hash for PeerId {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.id().hash(state);
+	}
+}
+impl PartialOrd for PeerId {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+impl Ord for PeerId {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.to_bigint().cmp(&other.to_bigint())
+	}
+}
+
+// decodeURIComponent: %XX escapes, which have to decode to utf-8
+fn decode_uri_component(s: &str) -> Option<String> {
+	if !s.contains('%') {
+		return Some(s.into());
+	}
+	let mut ret = Vec::with_capacity(s.len());
+	let mut bytes = s.bytes();
+	while let Some(b) = bytes.next() {
+		ret.push(match b {
+			b'%' => {
+				let hex = [bytes.next()?, bytes.next()?];
+				u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+			}
+			b => b,
+		});
+	}
+	String::from_utf8(ret).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::Value;
+
+	use super::*;
+
+	// The cases that test.mjs runs against index.mjs
+	fn vectors(kind: &str) -> Vec<Value> {
+		let vectors: Value = serde_json::from_str(include_str!("../vectors.json")).unwrap();
+		vectors[kind].as_array().unwrap().clone()
+	}
+
+	#[test]
+	fn valid() {
+		for vector in vectors("valid") {
+			let (note, input) = (&vector["note"], vector["input"].as_str().unwrap());
+			let peer_id: PeerId = input.parse().unwrap_or_else(|e| panic!("{note}: {e}"));
+			assert_eq!(peer_id.to_string(), vector["id"], "{note}");
+			assert_eq!(peer_id.encode_all(), vector["all"], "{note}");
+			let fingerprints: serde_json::Map<_, _> = peer_id
+				.fingerprints()
+				.map(|(alg, value)| {
+					let hex: String = value.iter().map(|b| format!("{b:02x}")).collect();
+					(alg.name().to_string(), hex.into())
+				})
+				.collect();
+			assert_eq!(Value::from(fingerprints), vector["fingerprints"], "{note}");
+			let algs: Vec<Algorithm> = peer_id.fingerprints().map(|(alg, _)| alg).collect();
+			let sdp = peer_id.sdp(&algs);
+			assert_eq!(sdp, vector["sdp"], "{note}");
+
+			// The canonical forms and the SDP read back as the same peer, with the same fingerprints
+			assert_eq!(vector["id"].as_str().unwrap().parse::<PeerId>().unwrap(), peer_id, "{note}");
+			let all: PeerId = vector["all"].as_str().unwrap().parse().unwrap();
+			assert_eq!(all.fingerprints().collect::<Vec<_>>(), peer_id.fingerprints().collect::<Vec<_>>());
+			let from_sdp = PeerId::from_sdp(&sdp).unwrap();
+			assert_eq!(from_sdp.fingerprints().collect::<Vec<_>>(), peer_id.fingerprints().collect::<Vec<_>>());
+		}
+	}
+
+	#[test]
+	fn invalid() {
+		for vector in vectors("invalid") {
+			let input = vector["input"].as_str().unwrap();
+			assert!(input.parse::<PeerId>().is_err(), "{}", vector["note"]);
+		}
+	}
+}
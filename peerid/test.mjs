@@ -0,0 +1,31 @@
+// Run with `npm test`: the cases in vectors.json, which the Rust crate's tests run too.
+import { test } from 'node:test';
+import assert from 'node:assert/strict';
+import { readFileSync } from 'node:fs';
+import { PeerId } from './index.mjs';
+
+const vectors = JSON.parse(readFileSync(new URL('./vectors.json', import.meta.url), 'utf8'));
+
+function hex(binstr) {
+	return binstr.split('').map(c => c.charCodeAt(0).toString(16).padStart(2, '0')).join('');
+}
+
+test('valid PeerIds', () => {
+	for (const { note, input, id, all, fingerprints, sdp } of vectors.valid) {
+		const peer_id = new PeerId(input);
+		assert.equal(String(peer_id), id, note);
+		assert.equal(peer_id.to_string_all(), all, note);
+		const got = Object.fromEntries(Object.entries(peer_id.fingerprints).map(([alg, v]) => [alg, hex(v)]));
+		assert.deepEqual(got, fingerprints, note);
+		assert.equal(peer_id.sdp(Object.keys(fingerprints).sort()), sdp, note);
+		// The canonical forms read back as the same peer
+		assert.equal(String(new PeerId(id)), id, note);
+		assert.equal(new PeerId(all).to_string_all(), all, note);
+	}
+});
+
+test('invalid PeerIds', () => {
+	for (const { note, input } of vectors.invalid) {
+		assert.throws(() => new PeerId(input), undefined, note);
+	}
+});
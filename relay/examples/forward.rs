@@ -0,0 +1,166 @@
+// Forwards packets between pairs of rendezvous allocations and reports how many arrive per second, to see how
+// forwarding scales with the relay's --workers.  Each pair's two sides usually land on different shards.
+// Usage: cargo run --release --example forward -- [relay addr] [pairs] [seconds] [turn password] [realm]
+use std::{
+	net::{SocketAddr, UdpSocket},
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Barrier,
+	},
+	thread,
+	time::{Duration, Instant},
+};
+
+use eyre::{bail, eyre, Result};
+use peerid::PeerId;
+use stun::{
+	attr::{Data, Integrity, RequestedTransport, StunAttr},
+	attrs::typed,
+	encoder::StunEncoder,
+	view::StunView,
+	StunTyp,
+};
+
+// How many packets each pair keeps in flight:
+const WINDOW: usize = 64;
+
+struct Side {
+	sock: UdpSocket,
+	buff: [u8; 4096],
+}
+impl Side {
+	fn new(relay: SocketAddr) -> Result<Self> {
+		let bind: SocketAddr = if relay.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+		let sock = UdpSocket::bind(bind)?;
+		sock.connect(relay)?;
+		sock.set_read_timeout(Some(Duration::from_millis(500)))?;
+		Ok(Self { sock, buff: [0; 4096] })
+	}
+	fn send(&mut self, typ: StunTyp, attrs: &[StunAttr<'_>]) -> Result<()> {
+		let txid = rand::random();
+		let len = StunEncoder::encode(&mut self.buff, &typ, &txid, attrs).ok_or_else(|| eyre!("too long"))?;
+		self.sock.send(&self.buff[..len])?;
+		Ok(())
+	}
+	fn recv(&mut self) -> Result<StunView<'_>> {
+		let len = self.sock.recv(&mut self.buff)?;
+		StunView::decode(&self.buff[..len]).map_err(|e| eyre!("{e:?}"))
+	}
+	// A long-term credential allocation, after the 401 that hands us a nonce
+	fn allocate(&mut self, username: &str, password: &str, realm: &str) -> Result<()> {
+		let transport = || StunAttr::RequestedTransport(RequestedTransport(17));
+		self.send(StunTyp::Req(0x003), &[transport(), StunAttr::Fingerprint])?;
+		let nonce = self.recv()?.get::<typed::Nonce>().ok_or_else(|| eyre!("no nonce"))?.to_string();
+		let key = md5::compute(format!("{username}:{realm}:{password}")).0;
+		self.send(
+			StunTyp::Req(0x003),
+			&[
+				transport(),
+				StunAttr::Username(username),
+				StunAttr::Realm(realm),
+				StunAttr::Nonce(&nonce),
+				StunAttr::Integrity(Integrity::Set { key_data: &key }),
+				StunAttr::Fingerprint,
+			],
+		)?;
+		match self.recv()?.typ() {
+			StunTyp::Res(0x003) => Ok(()),
+			typ => bail!("allocation failed: {typ:?}"),
+		}
+	}
+	// Send data into the pairing with a Send indication
+	fn forward(&mut self, data: &[u8]) -> Result<()> {
+		let xpeer = self.sock.peer_addr()?;
+		self.send(
+			StunTyp::Ind(0x006),
+			&[StunAttr::XPeer(xpeer), StunAttr::Data(Data::Slice(data)), StunAttr::Fingerprint],
+		)
+	}
+	// The relay only forwards to allocations once it has seen an ICE check from them
+	fn publish(&mut self) -> Result<()> {
+		let mut check = [0u8; 512];
+		let txid = rand::random();
+		let len = StunEncoder::encode(
+			&mut check,
+			&StunTyp::Req(0x001),
+			&txid,
+			&[
+				StunAttr::Username("remote:local"),
+				StunAttr::Priority(1),
+				StunAttr::IceControlling(rand::random()),
+				StunAttr::Integrity(Integrity::Set { key_data: b"pwd" }),
+				StunAttr::Fingerprint,
+			],
+		)
+		.ok_or_else(|| eyre!("too long"))?;
+		self.forward(&check[..len])
+	}
+}
+
+fn main() -> Result<()> {
+	let mut args = std::env::args().skip(1);
+	let relay: SocketAddr = args.next().as_deref().unwrap_or("[::1]:3478").parse()?;
+	let pairs: usize = args.next().as_deref().unwrap_or("4").parse()?;
+	let seconds: u64 = args.next().as_deref().unwrap_or("5").parse()?;
+	let password = args.next().unwrap_or_else(|| "the/turn/password/constant".into());
+	let realm = args.next().unwrap_or_else(|| "realm".into());
+
+	let sent = AtomicU64::new(0);
+	let received = AtomicU64::new(0);
+	// Every pair is set up before any of them start flooding the relay
+	let ready = Barrier::new(pairs);
+
+	thread::scope(|s| -> Result<()> {
+		let mut threads = Vec::new();
+		for i in 0..pairs {
+			let (sent, received, ready, password, realm) = (&sent, &received, &ready, &password, &realm);
+			threads.push(s.spawn(move || -> Result<()> {
+				let setup = || -> Result<(Side, Side)> {
+					let (a_id, b_id) = (PeerId::from_id(rand::random()), PeerId::from_id(rand::random()));
+					let (mut a, mut b) = (Side::new(relay)?, Side::new(relay)?);
+					a.allocate(&format!("{b_id}.{a_id}.pair{i}"), password, realm)?;
+					b.allocate(&format!("{a_id}.{b_id}.pair{i}"), password, realm)?;
+					a.publish()?;
+					// b's check reaches a once both have published, but their shards might see them in either order
+					for _ in 0..10 {
+						b.publish()?;
+						if a.recv().is_ok() {
+							return Ok((a, b));
+						}
+					}
+					bail!("pair {i} never connected");
+				};
+				let setup = setup();
+				ready.wait();
+				let (mut a, mut b) = setup?;
+				let deadline = Instant::now() + Duration::from_secs(seconds);
+
+				// Something that looks like a DTLS record
+				let mut data = [0u8; 100];
+				data[0] = 23;
+				while Instant::now() < deadline {
+					for _ in 0..WINDOW {
+						a.forward(&data)?;
+						sent.fetch_add(1, Ordering::Relaxed);
+					}
+					for _ in 0..WINDOW {
+						if b.sock.recv(&mut b.buff).is_err() {
+							break;
+						}
+						received.fetch_add(1, Ordering::Relaxed);
+					}
+				}
+				Ok(())
+			}));
+		}
+		for thread in threads {
+			thread.join().map_err(|_| eyre!("pair panicked"))??;
+		}
+		Ok(())
+	})?;
+
+	let sent = sent.into_inner();
+	let received = received.into_inner();
+	println!("sent {sent}, forwarded {received} ({:.0} packets/s)", received as f64 / seconds as f64);
+	Ok(())
+}
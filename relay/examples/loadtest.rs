@@ -0,0 +1,60 @@
+// Floods a relay with Binding requests from several client sockets and reports how many responses per second come back.
+// Usage: cargo run --release --example loadtest -- [relay addr] [clients] [seconds]
+use std::{
+	net::{SocketAddr, UdpSocket},
+	sync::atomic::{AtomicU64, Ordering},
+	thread,
+	time::{Duration, Instant},
+};
+
+use eyre::Result;
+use stun::{attr::StunAttr, encoder::StunEncoder, StunTyp};
+
+// How many requests each client keeps in flight:
+const WINDOW: usize = 64;
+
+fn main() -> Result<()> {
+	let mut args = std::env::args().skip(1);
+	let relay: SocketAddr = args.next().as_deref().unwrap_or("[::1]:3478").parse()?;
+	let clients: usize = args.next().as_deref().unwrap_or("4").parse()?;
+	let seconds: u64 = args.next().as_deref().unwrap_or("5").parse()?;
+
+	let sent = AtomicU64::new(0);
+	let received = AtomicU64::new(0);
+	let deadline = Instant::now() + Duration::from_secs(seconds);
+
+	thread::scope(|s| {
+		for _ in 0..clients {
+			s.spawn(|| -> Result<()> {
+				let bind: SocketAddr = if relay.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse()?;
+				let sock = UdpSocket::bind(bind)?;
+				sock.connect(relay)?;
+				sock.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+				let mut req = [0u8; 64];
+				let mut res = [0u8; 4096];
+				let mut txid = [0u8; 12];
+				while Instant::now() < deadline {
+					for _ in 0..WINDOW {
+						txid[..8].copy_from_slice(&rand::random::<u64>().to_be_bytes());
+						let len = StunEncoder::encode(&mut req, &StunTyp::Req(0x001), &txid, &[StunAttr::Fingerprint]).unwrap();
+						sock.send(&req[..len])?;
+						sent.fetch_add(1, Ordering::Relaxed);
+					}
+					for _ in 0..WINDOW {
+						if sock.recv(&mut res).is_err() {
+							break;
+						}
+						received.fetch_add(1, Ordering::Relaxed);
+					}
+				}
+				Ok(())
+			});
+		}
+	});
+
+	let sent = sent.into_inner();
+	let received = received.into_inner();
+	println!("sent {sent}, received {received} ({:.0} responses/s)", received as f64 / seconds as f64);
+	Ok(())
+}
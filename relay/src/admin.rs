@@ -0,0 +1,444 @@
+use std::{
+	net::SocketAddr,
+	sync::{Arc, Mutex},
+	time::Instant,
+};
+
+use peerid::PeerId;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::{
+	auth, http,
+	peers::Side,
+	server::{Allocation, Kick, Shared},
+};
+
+// The largest request body the admin API takes (a log filter)
+pub const MAX_BODY: usize = 4096;
+
+// Swaps out the log filter at runtime
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+// What the admin API asks of the shards, which own the allocations
+pub enum Control {
+	Allocations(oneshot::Sender<Vec<Allocation>>),
+	Kick(Kick, oneshot::Sender<usize>),
+}
+
+// The operator's API:
+// * GET /metrics: the Prometheus text format
+// * GET /allocations, GET /pairings: what's live right now
+// * POST /kick?client=<addr> or ?peer=<peer id>: remove allocations
+// * GET /hosted, PUT / DELETE /hosted/<peer id>: manage hosted peers
+// * GET / PUT /log: the log filter, in tracing's directive syntax ("debug", "info,relay::stream=trace")
+// Everything but /metrics needs admin.api_key to be set, and every request needs it if it is.
+pub struct Admin {
+	shared: Arc<Shared>,
+	shards: Arc<[mpsc::Sender<Control>]>,
+	log: LogHandle,
+	// The directives that the log filter was last set to
+	log_filter: Mutex<String>,
+}
+impl Admin {
+	pub fn new(shared: Arc<Shared>, shards: Arc<[mpsc::Sender<Control>]>, log: LogHandle) -> Self {
+		let log_filter = Mutex::new(shared.config.log_directives());
+		Self {
+			shared,
+			shards,
+			log,
+			log_filter,
+		}
+	}
+	pub fn serves(path: &str) -> bool {
+		matches!(path, "/metrics" | "/allocations" | "/pairings" | "/kick" | "/hosted" | "/log")
+			|| path.starts_with("/hosted/")
+	}
+	pub async fn handle(&self, req: http::Request) -> http::Response {
+		let api_key = self.shared.config.admin.api_key.as_deref();
+		let key = req.header("authorization").and_then(|h| h.strip_prefix("Bearer "));
+		if api_key.is_some_and(|api_key| !key.is_some_and(|key| auth::same(key, api_key))) {
+			return http::Response::text(401, "unauthorized\n");
+		}
+		if api_key.is_none() && req.path != "/metrics" {
+			return http::Response::text(403, "admin.api_key isn't set\n");
+		}
+		match (req.method.as_str(), req.path.as_str()) {
+			("GET", "/metrics") => self.metrics(),
+			("GET", "/allocations") => self.allocations().await,
+			("GET", "/pairings") => self.pairings(),
+			("POST", "/kick") => self.kick(&req).await,
+			("GET", "/hosted") => self.hosted(),
+			("GET", "/log") => http::Response::text(200, format!("{}\n", self.log_filter.lock().unwrap())),
+			("PUT", "/log") => self.set_log_filter(&req.body),
+			(method, path) => match path.strip_prefix("/hosted/") {
+				Some(id) => self.register(method, id).await,
+				None => http::Response::text(405, "method not allowed\n"),
+			},
+		}
+	}
+
+	fn metrics(&self) -> http::Response {
+		let peers = &self.shared.peers;
+		http::Response {
+			status: 200,
+			content_type: "text/plain; version=0.0.4",
+			body: self.shared.metrics.render(peers.allocations(), peers.pairings()).into_bytes(),
+		}
+	}
+
+	// Ask every shard, and collect their answers.  Shards that have shut down don't answer.
+	async fn ask<T>(&self, control: impl Fn(oneshot::Sender<T>) -> Control) -> Vec<T> {
+		let mut ret = Vec::new();
+		for shard in self.shards.iter() {
+			let (tx, rx) = oneshot::channel();
+			if shard.send(control(tx)).await.is_ok() {
+				if let Ok(answer) = rx.await {
+					ret.push(answer);
+				}
+			}
+		}
+		ret
+	}
+
+	async fn allocations(&self) -> http::Response {
+		let now = Instant::now();
+		let allocations: Vec<_> = self
+			.ask(Control::Allocations)
+			.await
+			.into_iter()
+			.flatten()
+			.map(|a| {
+				let pairing = a.pairing.as_ref();
+				json!({
+					"client": a.client.to_string(),
+					"transport": if a.stream { "stream" } else { "udp" },
+					"username": a.username,
+					"dst": pairing.map(|p| p.dst()),
+					"src": pairing.map(|p| p.src()),
+					"token": pairing.map(|p| p.token()),
+					"relayed": a.relayed.map(|r| r.to_string()),
+					"expires_in": a.expires.saturating_duration_since(now).as_secs(),
+					"ice_username": a.ice_username,
+					"permissions": a.permissions,
+					"channels": a.channels,
+					"sent_packets": a.traffic.sent_packets.get(),
+					"sent_bytes": a.traffic.sent_bytes.get(),
+					"received_packets": a.traffic.received_packets.get(),
+					"received_bytes": a.traffic.received_bytes.get(),
+				})
+			})
+			.collect();
+		http::Response::json(&json!({ "allocations": allocations }))
+	}
+
+	fn pairings(&self) -> http::Response {
+		let pairings: Vec<_> = self
+			.shared
+			.peers
+			.list()
+			.into_iter()
+			.map(|(a, b)| {
+				let side = |s: &Side| {
+					let clients: Vec<_> = s.clients.iter().map(SocketAddr::to_string).collect();
+					json!({ "username": s.username, "clients": clients })
+				};
+				json!({ "sides": [side(&a), side(&b)] })
+			})
+			.collect();
+		http::Response::json(&json!({ "pairings": pairings }))
+	}
+
+	async fn kick(&self, req: &http::Request) -> http::Response {
+		let kick = match (req.query("client"), req.query("peer")) {
+			(Some(client), None) => match client.parse() {
+				Ok(client) => Kick::Client(client),
+				Err(_) => return http::Response::text(400, "invalid client address\n"),
+			},
+			(None, Some(peer)) => match peer.parse::<PeerId>() {
+				Ok(peer) => Kick::Peer(peer.to_string()),
+				Err(_) => return http::Response::text(400, "invalid peer id\n"),
+			},
+			_ => return http::Response::text(400, "pass one of client=<addr> or peer=<peer id>\n"),
+		};
+		let kicked: usize = self.ask(|tx| Control::Kick(kick.clone(), tx)).await.into_iter().sum();
+		http::Response::json(&json!({ "kicked": kicked }))
+	}
+
+	fn hosted(&self) -> http::Response {
+		let hosted: Vec<_> = self
+			.shared
+			.hosted
+			.list()
+			.into_iter()
+			.map(|(peer, configured)| json!({ "peer": peer, "configured": configured }))
+			.collect();
+		http::Response::json(&json!({ "hosted": hosted }))
+	}
+
+	// PUT /hosted/<peer id> registers a hosted peer, DELETE /hosted/<peer id> unregisters it.
+	async fn register(&self, method: &str, id: &str) -> http::Response {
+		let hosted = &self.shared.hosted;
+		let Ok(id) = id.parse::<PeerId>() else {
+			return http::Response::text(400, "invalid peer id\n");
+		};
+		let ret = match method {
+			"PUT" => hosted.add(&id).await,
+			"DELETE" => hosted.remove(&id).await,
+			_ => return http::Response::text(405, "method not allowed\n"),
+		};
+		match ret {
+			Ok(true) => http::Response::text(204, ""),
+			Ok(false) if method == "PUT" => http::Response::text(204, ""),
+			Ok(false) => http::Response::text(404, "not registered\n"),
+			Err(e) => {
+				warn!(error = format!("{e:#}"), "unable to save the hosted registry");
+				http::Response::text(500, "unable to save the registry\n")
+			}
+		}
+	}
+
+	fn set_log_filter(&self, body: &[u8]) -> http::Response {
+		let Ok(directives) = std::str::from_utf8(body).map(str::trim) else {
+			return http::Response::text(400, "log filter must be utf-8\n");
+		};
+		let filter = match EnvFilter::builder().parse(directives) {
+			Ok(filter) if !directives.is_empty() => filter,
+			_ => return http::Response::text(400, "invalid log filter\n"),
+		};
+		if self.log.reload(filter).is_err() {
+			return http::Response::text(500, "unable to change the log filter\n");
+		}
+		*self.log_filter.lock().unwrap() = directives.into();
+		info!(filter = directives, "log filter changed");
+		http::Response::text(204, "")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use serde_json::Value;
+	use stun::{
+		attr::{Integrity, RequestedTransport, StunAttr},
+		attrs::typed,
+		encoder::StunEncoder,
+		view::StunView,
+		StunTyp,
+	};
+	use tokio::{net::UdpSocket, sync::watch, time::timeout};
+
+	use super::*;
+	use crate::{
+		auth::long_term_key,
+		config::{Config, Mode, Rate},
+		http::client::{request, serve},
+		server::{
+			tests::{allocate, USERNAME},
+			Server,
+		},
+	};
+
+	const A: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+
+	// The filter that the admin API swaps out, which only lives as long as its layer
+	struct Log {
+		_layer: reload::Layer<EnvFilter, Registry>,
+		handle: LogHandle,
+	}
+
+	// An admin API in front of one shard with an allocation from 192.0.2.1:5000 on it
+	async fn admin(api_key: Option<&str>) -> (SocketAddr, Log) {
+		let mut config = Config::default();
+		config.limits.requests_per_ip = Rate::UNLIMITED;
+		config.admin.api_key = api_key.map(Into::into);
+		let shared = Arc::new(Shared::new(config, None).unwrap());
+		let mut server = Server::new(shared.clone(), mpsc::channel(1).0);
+		assert_eq!(allocate(&mut server, SocketAddr::from(([192, 0, 2, 1], 5000))).await, None);
+		let (control_tx, mut control_rx) = mpsc::channel(1);
+		tokio::spawn(async move {
+			while let Some(control) = control_rx.recv().await {
+				match control {
+					Control::Allocations(reply) => drop(reply.send(server.allocations())),
+					Control::Kick(kick, reply) => drop(reply.send(server.kick(&kick))),
+				}
+			}
+		});
+		let (layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+		let admin = Arc::new(Admin::new(shared, Arc::new([control_tx]), handle.clone()));
+		let addr = serve(MAX_BODY, move |req| {
+			let admin = admin.clone();
+			async move { admin.handle(req).await }
+		})
+		.await;
+		(addr, Log { _layer: layer, handle })
+	}
+
+	async fn get(addr: SocketAddr, method: &str, target: &str, body: &str) -> (u16, String) {
+		request(addr, method, target, &[("Authorization", "Bearer key")], body).await
+	}
+
+	#[tokio::test]
+	async fn auth() {
+		let (addr, _log) = admin(Some("key")).await;
+		assert_eq!(request(addr, "GET", "/allocations", &[], "").await.0, 401);
+		assert_eq!(request(addr, "GET", "/metrics", &[], "").await.0, 401);
+		for wrong in ["Bearer wrong", "Bearer ke", "Bearer keyy", "key"] {
+			let wrong = [("Authorization", wrong)];
+			assert_eq!(request(addr, "GET", "/allocations", &wrong, "").await.0, 401);
+		}
+		assert_eq!(get(addr, "GET", "/allocations", "").await.0, 200);
+
+		// Without an api_key, only /metrics is served
+		let (addr, _log) = admin(None).await;
+		assert_eq!(request(addr, "GET", "/metrics", &[], "").await.0, 200);
+		assert_eq!(request(addr, "GET", "/allocations", &[], "").await.0, 403);
+		assert_eq!(request(addr, "PUT", &format!("/hosted/{A}"), &[], "").await.0, 403);
+	}
+
+	#[tokio::test]
+	async fn allocations_and_kick() {
+		let (addr, _log) = admin(Some("key")).await;
+		let (status, body) = get(addr, "GET", "/allocations", "").await;
+		assert_eq!(status, 200);
+		let body: Value = serde_json::from_str(&body).unwrap();
+		let allocation = &body["allocations"][0];
+		assert_eq!(allocation["client"], "192.0.2.1:5000");
+		assert_eq!(allocation["username"], USERNAME);
+		assert_eq!(allocation["dst"], A);
+		assert_eq!(allocation["transport"], "stream");
+
+		assert_eq!(get(addr, "POST", "/kick", "").await.0, 400);
+		assert_eq!(get(addr, "POST", "/kick?client=nonsense", "").await.0, 400);
+		assert_eq!(get(addr, "POST", "/kick?peer=nonsense", "").await.0, 400);
+		assert_eq!(get(addr, "POST", "/kick?client=192.0.2.2:5000", "").await, (200, r#"{"kicked":0}"#.into()));
+		assert_eq!(get(addr, "POST", &format!("/kick?peer={A}"), "").await, (200, r#"{"kicked":1}"#.into()));
+		assert_eq!(get(addr, "POST", "/kick?client=192.0.2.1:5000", "").await, (200, r#"{"kicked":0}"#.into()));
+		let (_, body) = get(addr, "GET", "/allocations", "").await;
+		assert_eq!(body, r#"{"allocations":[]}"#);
+	}
+
+	#[tokio::test]
+	async fn hosted() {
+		let (addr, _log) = admin(Some("key")).await;
+		let target = format!("/hosted/{A}");
+		assert_eq!(get(addr, "DELETE", &target, "").await.0, 404);
+		assert_eq!(get(addr, "PUT", &target, "").await.0, 204);
+		// Registering twice is fine
+		assert_eq!(get(addr, "PUT", &target, "").await.0, 204);
+		let (_, body) = get(addr, "GET", "/hosted", "").await;
+		assert_eq!(body, format!(r#"{{"hosted":[{{"configured":false,"peer":"{A}"}}]}}"#));
+		assert_eq!(get(addr, "DELETE", &target, "").await.0, 204);
+		assert_eq!(get(addr, "GET", "/hosted", "").await.1, r#"{"hosted":[]}"#);
+		assert_eq!(get(addr, "PUT", "/hosted/nonsense", "").await.0, 400);
+		assert_eq!(get(addr, "POST", &target, "").await.0, 405);
+	}
+
+	#[tokio::test]
+	async fn log_filter() {
+		let (addr, log) = admin(Some("key")).await;
+		assert_eq!(get(addr, "PUT", "/log", "debug,relay::stream=trace").await.0, 204);
+		assert_eq!(get(addr, "GET", "/log", "").await.1, "debug,relay::stream=trace\n");
+		log.handle.with_current(|filter| assert_eq!(filter.to_string(), "relay::stream=trace,debug")).unwrap();
+		assert_eq!(get(addr, "PUT", "/log", "").await.0, 400);
+		assert_eq!(get(addr, "PUT", "/log", "relay=nonsense").await.0, 400);
+		assert_eq!(get(addr, "GET", "/log", "").await.1, "debug,relay::stream=trace\n");
+	}
+
+	// Send a STUN request from sock to the relay and wait for the response
+	async fn transact(sock: &UdpSocket, relay: SocketAddr, typ: StunTyp, attrs: &[StunAttr<'_>]) -> Vec<u8> {
+		let mut packet = [0u8; 512];
+		let len = StunEncoder::encode(&mut packet, &typ, &rand::random(), attrs).unwrap();
+		sock.send_to(&packet[..len], relay).await.unwrap();
+		let mut res = vec![0u8; 1500];
+		let len = timeout(Duration::from_secs(5), sock.recv(&mut res)).await.unwrap().unwrap();
+		res.truncate(len);
+		res
+	}
+
+	#[tokio::test]
+	async fn live_allocation() {
+		// A standard mode relay on loopback, with one shard behind the admin API
+		let mut config = Config::default();
+		config.relaying.mode = Mode::Standard;
+		config.relaying.address = [127, 0, 0, 1].into();
+		config.admin.api_key = Some("key".into());
+		let socks: Arc<[UdpSocket]> = Arc::new([crate::bind(([127, 0, 0, 1], 0).into(), false).unwrap()]);
+		let relay = socks[0].local_addr().unwrap();
+		let shared = Arc::new(Shared::new(config, None).unwrap());
+		let (_stream_tx, stream_rx) = mpsc::channel(1);
+		let (control_tx, control_rx) = mpsc::channel(1);
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		tokio::spawn(crate::shard(0, socks, shared.clone(), stream_rx, control_rx, shutdown_rx));
+		let (_layer, handle) = reload::Layer::new(EnvFilter::new("info"));
+		let admin = Arc::new(Admin::new(shared.clone(), Arc::new([control_tx]), handle));
+		let addr = serve(MAX_BODY, move |req| {
+			let admin = admin.clone();
+			async move { admin.handle(req).await }
+		})
+		.await;
+
+		// Allocate like any TURN client: the first request is turned down with a nonce
+		let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let transport = StunAttr::RequestedTransport(RequestedTransport(17));
+		let res = transact(&client, relay, StunTyp::Req(0x003), &[transport.clone(), StunAttr::Fingerprint]).await;
+		let res = StunView::decode(&res).unwrap();
+		assert_eq!(res.get::<typed::Error>().unwrap().code, 401);
+		let nonce = res.get::<typed::Nonce>().unwrap();
+		let config = &shared.config;
+		let key = long_term_key(USERNAME, &config.realm, &config.secrets.turn_password);
+		let auth = [
+			StunAttr::Username(USERNAME),
+			StunAttr::Realm(&config.realm),
+			StunAttr::Nonce(nonce),
+			StunAttr::Integrity(Integrity::Set { key_data: &key }),
+			StunAttr::Fingerprint,
+		];
+		let res = transact(&client, relay, StunTyp::Req(0x003), &[&[transport][..], &auth].concat()).await;
+		let res = StunView::decode(&res).unwrap();
+		assert!(matches!(res.typ(), StunTyp::Res(0x003)));
+		let relayed = res.get::<typed::XRelayed>().unwrap();
+
+		// A peer with a permission gets through to the client
+		let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let permission = StunAttr::XPeer(peer.local_addr().unwrap());
+		let res = transact(&client, relay, StunTyp::Req(0x008), &[&[permission][..], &auth].concat()).await;
+		assert!(matches!(StunView::decode(&res).unwrap().typ(), StunTyp::Res(0x008)));
+		peer.send_to(b"hello", relayed).await.unwrap();
+		let mut buff = [0u8; 1500];
+		let len = timeout(Duration::from_secs(5), client.recv(&mut buff)).await.unwrap().unwrap();
+		let data = StunView::decode(&buff[..len]).unwrap();
+		assert!(matches!(data.get::<typed::Data>(), Some(b"hello")));
+
+		let (status, body) = get(addr, "GET", "/allocations", "").await;
+		assert_eq!(status, 200);
+		let body: Value = serde_json::from_str(&body).unwrap();
+		let allocation = &body["allocations"][0];
+		assert_eq!(allocation["client"], client.local_addr().unwrap().to_string());
+		assert_eq!(allocation["transport"], "udp");
+		assert_eq!(allocation["relayed"], relayed.to_string());
+		assert_eq!(allocation["permissions"], 1);
+
+		let target = format!("/kick?client={}", client.local_addr().unwrap());
+		assert_eq!(get(addr, "POST", &target, "").await, (200, r#"{"kicked":1}"#.into()));
+		assert_eq!(get(addr, "GET", "/allocations", "").await.1, r#"{"allocations":[]}"#);
+		// The relayed port is closed, so it can be bound again
+		let mut closed = false;
+		for _ in 0..50 {
+			if std::net::UdpSocket::bind(relayed).is_ok() {
+				closed = true;
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+		assert!(closed, "{relayed} is still bound");
+		// and the shard has forgotten the allocation: a Refresh for it goes unanswered
+		let mut packet = [0u8; 512];
+		let len = StunEncoder::encode(&mut packet, &StunTyp::Req(0x004), &rand::random(), &auth).unwrap();
+		client.send_to(&packet[..len], relay).await.unwrap();
+		assert!(timeout(Duration::from_millis(200), client.recv(&mut buff)).await.is_err());
+	}
+}
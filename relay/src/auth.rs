@@ -0,0 +1,104 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::{base64, config::Secrets};
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs()
+}
+
+// Compare secrets without an early exit, so that the time taken doesn't give away how much of a guess was right
+pub fn same(a: &str, b: &str) -> bool {
+	a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+// The long-term credential key: MD5(username ":" realm ":" password)
+pub fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+	let mut hasher = md5::Context::new();
+	hasher.consume(username);
+	hasher.consume(":");
+	hasher.consume(realm);
+	hasher.consume(":");
+	hasher.consume(password);
+
+	hasher.compute().into()
+}
+
+// Where TURN passwords come from:
+// * Static: every username shares one password.
+// * Rest: the TURN REST API (draft-uberti-behave-turn-rest), where usernames are `<expiry>:<user>` and the
+//   password is base64(HMAC-SHA1(secret, username)).  The first secret is used to issue new credentials, but any
+//   of them are accepted so that secrets can be rotated without breaking credentials that were already handed out.
+#[derive(Clone)]
+pub enum Credentials {
+	Static(String),
+	Rest(Vec<String>),
+}
+impl Credentials {
+	pub fn new(secrets: &Secrets) -> Self {
+		if secrets.rest_secrets.is_empty() {
+			Self::Static(secrets.turn_password.clone())
+		} else {
+			Self::Rest(secrets.rest_secrets.clone())
+		}
+	}
+	// The unix time that a REST API username expires at
+	pub fn expiry(username: &str) -> Option<u64> {
+		let (expiry, _) = username.split_once(':')?;
+		expiry.parse().ok()
+	}
+	// Who a username belongs to: the user that a REST API username was issued for, otherwise the username itself
+	pub fn user(username: &str) -> &str {
+		match username.split_once(':') {
+			Some((expiry, user)) if expiry.parse::<u64>().is_ok() => user,
+			_ => username,
+		}
+	}
+	pub fn expired(&self, username: &str) -> bool {
+		match self {
+			Self::Static(_) => false,
+			Self::Rest(_) => Self::expiry(username).is_none_or(|expiry| expiry < now()),
+		}
+	}
+	fn rest_password(secret: &str, username: &str) -> String {
+		let mut hmac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("bad secret");
+		hmac.update(username.as_bytes());
+		base64::encode(&hmac.finalize().into_bytes())
+	}
+	// The candidate passwords for a username (doesn't check expiry)
+	pub fn passwords<'a>(&'a self, username: &'a str) -> impl Iterator<Item = String> + 'a {
+		let (password, secrets) = match self {
+			Self::Static(password) => (Some(password.clone()), &[][..]),
+			Self::Rest(secrets) => (None, &secrets[..]),
+		};
+		password
+			.into_iter()
+			.chain(secrets.iter().map(move |secret| Self::rest_password(secret, username)))
+	}
+	// Issue a (username, password) pair for user that's valid for ttl seconds.
+	pub fn issue(&self, user: &str, ttl: u64) -> Option<(String, String)> {
+		let Self::Rest(secrets) = self else { return None };
+		let username = format!("{}:{user}", now().saturating_add(ttl));
+		let password = Self::rest_password(secrets.first()?, &username);
+		Some((username, password))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn compare() {
+		assert!(same("key", "key"));
+		assert!(same("", ""));
+		for other in ["kez", "ke", "keys", "", "KEY"] {
+			assert!(!same("key", other), "{other}");
+		}
+	}
+}
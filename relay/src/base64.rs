@@ -0,0 +1,19 @@
+// Standard (RFC 4648) base64 with padding.  We only ever need to encode.
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(data: &[u8]) -> String {
+	let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+	for chunk in data.chunks(3) {
+		let mut group = [0u8; 3];
+		group[..chunk.len()].copy_from_slice(chunk);
+		let n = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+		for i in 0..4 {
+			if i <= chunk.len() {
+				ret.push(ALPHABET[((n >> (18 - i * 6)) & 0x3F) as usize] as char);
+			} else {
+				ret.push('=');
+			}
+		}
+	}
+	ret
+}
@@ -0,0 +1,455 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{net::UdpSocket, sync::mpsc};
+
+// How many datagrams we read / write per syscall:
+pub const BATCH: usize = 32;
+pub const BUFF_LEN: usize = 4096;
+
+// Where a datagram goes out: one of the UDP sockets, or a TCP / TLS connection's writer.
+#[derive(Debug, Clone)]
+pub enum Via {
+	Udp(usize),
+	Stream(mpsc::Sender<Vec<u8>>),
+}
+
+pub struct RecvBatch {
+	buffs: Box<[[u8; BUFF_LEN]; BATCH]>,
+	lens: [usize; BATCH],
+	addrs: [SocketAddr; BATCH],
+	count: usize,
+}
+impl RecvBatch {
+	pub fn new() -> Self {
+		Self {
+			buffs: Box::new([[0u8; BUFF_LEN]; BATCH]),
+			lens: [0; BATCH],
+			addrs: [SocketAddr::from(([0u8; 16], 0)); BATCH],
+			count: 0,
+		}
+	}
+	// Waits until at least one datagram has been received.  Cancel safe.
+	pub async fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+		self.count = 0;
+		self.count = sys::recv(sock, &mut self.buffs, &mut self.lens, &mut self.addrs).await?;
+		Ok(self.count)
+	}
+	pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+		(0..self.count).map(|i| (&self.buffs[i][..self.lens[i]], self.addrs[i]))
+	}
+}
+
+// Datagrams can be queued for any of the listening sockets: each one remembers the index of its socket.
+pub struct SendBatch {
+	buffs: Box<[[u8; BUFF_LEN]; BATCH]>,
+	lens: [usize; BATCH],
+	addrs: [SocketAddr; BATCH],
+	socks: [usize; BATCH],
+	count: usize,
+	gso: Gso,
+}
+// Whether UDP_SEGMENT works is only known once a segmented send has gone through or failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Gso {
+	Probing,
+	Works,
+	Unsupported,
+}
+impl SendBatch {
+	pub fn new() -> Self {
+		Self {
+			buffs: Box::new([[0u8; BUFF_LEN]; BATCH]),
+			lens: [0; BATCH],
+			addrs: [SocketAddr::from(([0u8; 16], 0)); BATCH],
+			socks: [0; BATCH],
+			count: 0,
+			gso: if cfg!(target_os = "linux") { Gso::Probing } else { Gso::Unsupported },
+		}
+	}
+	// The buffer that the next datagram should be encoded into:
+	pub fn buff(&mut self) -> &mut [u8] {
+		&mut self.buffs[self.count]
+	}
+	// Queue the datagram that was encoded into buff() to be sent via a socket, flushing if the batch is full.
+	// Connections aren't batched: the datagram is handed straight to their writer (and dropped if it's behind).
+	pub async fn push(&mut self, len: usize, addr: SocketAddr, via: &Via, socks: &[UdpSocket]) -> io::Result<()> {
+		let sock = match via {
+			Via::Udp(sock) => *sock,
+			Via::Stream(tx) => {
+				let _ = tx.try_send(self.buffs[self.count][..len].to_vec());
+				return Ok(());
+			}
+		};
+		self.lens[self.count] = len;
+		self.addrs[self.count] = addr;
+		self.socks[self.count] = sock;
+		self.count += 1;
+		if self.count == BATCH {
+			self.flush(socks).await?;
+		}
+		Ok(())
+	}
+	pub async fn flush(&mut self, socks: &[UdpSocket]) -> io::Result<()> {
+		let count = std::mem::take(&mut self.count);
+		let mut sent = 0;
+		let mut ret = Ok(());
+		while sent < count {
+			// Send runs of datagrams that go out the same socket together:
+			let sock = self.socks[sent];
+			let end = (sent..count).find(|&i| self.socks[i] != sock).unwrap_or(count);
+			let (buffs, lens, addrs) = (&self.buffs[sent..end], &self.lens[sent..end], &self.addrs[sent..end]);
+			match sys::send(&socks[sock], buffs, lens, addrs, self.gso != Gso::Unsupported).await {
+				Ok((n, segmented)) => {
+					sent += n;
+					if segmented {
+						self.gso = Gso::Works;
+					}
+				}
+				// A segmented send can still be too big for the kernel (EMSGSIZE): send its datagrams one by one.
+				Err((e, n)) if n > 1 && sys::is_size_err(&e) => {
+					match sys::send(&socks[sock], &buffs[..n], &lens[..n], &addrs[..n], false).await {
+						Ok((n, _)) => sent += n,
+						Err((e, n)) => {
+							sent += n;
+							if ret.is_ok() {
+								ret = Err(e);
+							}
+						}
+					}
+				}
+				// Some kernels / NICs don't support UDP_SEGMENT, which shows when the first segmented send fails: fall
+				// back to plain sendmmsg.  Later errors are about the datagrams, not GSO.
+				Err((e, n)) if n > 1 && self.gso == Gso::Probing && sys::is_gso_err(&e) => {
+					self.gso = Gso::Unsupported
+				}
+				// Datagrams that can't be sent (unreachable peer, etc.) shouldn't take the rest of the batch down with
+				// them.
+				Err((e, n)) => {
+					sent += n;
+					if ret.is_ok() {
+						ret = Err(e);
+					}
+				}
+			}
+		}
+		ret
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+	use std::{
+		io,
+		mem::{size_of, zeroed},
+		net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+		os::fd::AsRawFd,
+	};
+
+	use tokio::{io::Interest, net::UdpSocket};
+
+	use super::{BATCH, BUFF_LEN};
+
+	// Maximum number of segments in one GSO send (UDP_MAX_SEGMENTS in the kernel)
+	const MAX_SEGMENTS: usize = 64;
+
+	// The most UDP payload that one send can carry: what's left of the IP packet's 16 bit length after the headers
+	// (for IPv6, the length doesn't include its own header).  IPv4-mapped addresses go out as IPv4.
+	fn max_payload(addr: &SocketAddr) -> usize {
+		match addr {
+			SocketAddr::V6(a) if a.ip().to_ipv4_mapped().is_none() => u16::MAX as usize - 8,
+			_ => u16::MAX as usize - 20 - 8,
+		}
+	}
+
+	fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+		let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+		let len = match addr {
+			SocketAddr::V4(a) => {
+				let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+				sin.sin_family = libc::AF_INET as _;
+				sin.sin_port = a.port().to_be();
+				sin.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+				size_of::<libc::sockaddr_in>()
+			}
+			SocketAddr::V6(a) => {
+				let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+				sin6.sin6_family = libc::AF_INET6 as _;
+				sin6.sin6_port = a.port().to_be();
+				sin6.sin6_addr.s6_addr = a.ip().octets();
+				sin6.sin6_flowinfo = a.flowinfo();
+				sin6.sin6_scope_id = a.scope_id();
+				size_of::<libc::sockaddr_in6>()
+			}
+		};
+		(storage, len as libc::socklen_t)
+	}
+	fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+		match storage.ss_family as libc::c_int {
+			libc::AF_INET => {
+				let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+				Some(SocketAddr::V4(SocketAddrV4::new(
+					sin.sin_addr.s_addr.to_ne_bytes().into(),
+					u16::from_be(sin.sin_port),
+				)))
+			}
+			libc::AF_INET6 => {
+				let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+				Some(SocketAddr::V6(SocketAddrV6::new(
+					sin6.sin6_addr.s6_addr.into(),
+					u16::from_be(sin6.sin6_port),
+					sin6.sin6_flowinfo,
+					sin6.sin6_scope_id,
+				)))
+			}
+			_ => None,
+		}
+	}
+
+	pub async fn recv(
+		sock: &UdpSocket,
+		buffs: &mut [[u8; BUFF_LEN]; BATCH],
+		lens: &mut [usize; BATCH],
+		addrs: &mut [SocketAddr; BATCH],
+	) -> io::Result<usize> {
+		loop {
+			sock.readable().await?;
+			match sock.try_io(Interest::READABLE, || recvmmsg(sock, buffs, lens, addrs)) {
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+				ret => return ret,
+			}
+		}
+	}
+	fn recvmmsg(
+		sock: &UdpSocket,
+		buffs: &mut [[u8; BUFF_LEN]; BATCH],
+		lens: &mut [usize; BATCH],
+		addrs: &mut [SocketAddr; BATCH],
+	) -> io::Result<usize> {
+		let mut names: [libc::sockaddr_storage; BATCH] = unsafe { zeroed() };
+		let mut iovs: [libc::iovec; BATCH] = unsafe { zeroed() };
+		let mut msgs: [libc::mmsghdr; BATCH] = unsafe { zeroed() };
+		for i in 0..BATCH {
+			iovs[i] = libc::iovec {
+				iov_base: buffs[i].as_mut_ptr() as *mut _,
+				iov_len: BUFF_LEN,
+			};
+			msgs[i].msg_hdr.msg_name = &mut names[i] as *mut _ as *mut _;
+			msgs[i].msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+			msgs[i].msg_hdr.msg_iov = &mut iovs[i];
+			msgs[i].msg_hdr.msg_iovlen = 1;
+		}
+		let ret = unsafe {
+			libc::recvmmsg(
+				sock.as_raw_fd(),
+				msgs.as_mut_ptr(),
+				BATCH as _,
+				libc::MSG_WAITFORONE,
+				std::ptr::null_mut(),
+			)
+		};
+		if ret < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let mut count = 0;
+		for i in 0..ret as usize {
+			// Skip datagrams that didn't fit in the buffer, and anything that isn't IPv4 / IPv6
+			if msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+				continue;
+			}
+			let Some(addr) = from_sockaddr(&names[i]) else { continue };
+			if i != count {
+				buffs.copy_within(i..i + 1, count);
+			}
+			lens[count] = msgs[i].msg_len as usize;
+			addrs[count] = addr;
+			count += 1;
+		}
+		Ok(count)
+	}
+
+	// Returns how many of the datagrams were sent and whether any went out as GSO segments.  On error, returns how
+	// many datagrams the message that failed held (more than one means it was segmented).
+	pub async fn send(
+		sock: &UdpSocket,
+		buffs: &[[u8; BUFF_LEN]],
+		lens: &[usize],
+		addrs: &[SocketAddr],
+		gso: bool,
+	) -> Result<(usize, bool), (io::Error, usize)> {
+		loop {
+			sock.writable().await.map_err(|e| (e, 1))?;
+			let mut first = 1;
+			match sock.try_io(Interest::WRITABLE, || sendmmsg(sock, buffs, lens, addrs, gso, &mut first)) {
+				Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+				Err(e) => return Err((e, first)),
+				Ok(ret) => return Ok(ret),
+			}
+		}
+	}
+	fn sendmmsg(
+		sock: &UdpSocket,
+		buffs: &[[u8; BUFF_LEN]],
+		lens: &[usize],
+		addrs: &[SocketAddr],
+		gso: bool,
+		first: &mut usize,
+	) -> io::Result<(usize, bool)> {
+		let mut names: [(libc::sockaddr_storage, libc::socklen_t); BATCH] = unsafe { zeroed() };
+		let mut iovs: [libc::iovec; BATCH] = unsafe { zeroed() };
+		let mut msgs: [libc::mmsghdr; BATCH] = unsafe { zeroed() };
+		// Space for one UDP_SEGMENT control message per mmsghdr:
+		let cmsg_space = unsafe { libc::CMSG_SPACE(size_of::<u16>() as _) } as usize;
+		let mut cmsgs = [[0u64; 4]; BATCH];
+		debug_assert!(cmsg_space <= size_of::<[u64; 4]>());
+
+		// Group runs of datagrams to the same address into a single GSO send if they're the same size (the last
+		// segment of a run is allowed to be shorter):
+		let mut msg_count = 0;
+		let mut msg_datagrams = [0usize; BATCH];
+		let mut i = 0;
+		while i < lens.len() {
+			let seg = lens[i];
+			let mut j = i + 1;
+			if gso {
+				while j < lens.len()
+					&& j - i < MAX_SEGMENTS
+					&& addrs[j] == addrs[i]
+					&& lens[j] <= seg
+					&& lens[j - 1] == seg
+					&& (j - i + 1) * seg <= max_payload(&addrs[i])
+				{
+					j += 1;
+				}
+			}
+			for k in i..j {
+				iovs[k] = libc::iovec {
+					iov_base: buffs[k].as_ptr() as *mut _,
+					iov_len: lens[k],
+				};
+			}
+			names[msg_count] = to_sockaddr(&addrs[i]);
+			let hdr = &mut msgs[msg_count].msg_hdr;
+			hdr.msg_name = &mut names[msg_count].0 as *mut _ as *mut _;
+			hdr.msg_namelen = names[msg_count].1;
+			hdr.msg_iov = &mut iovs[i];
+			hdr.msg_iovlen = (j - i) as _;
+			if j - i > 1 {
+				hdr.msg_control = cmsgs[msg_count].as_mut_ptr() as *mut _;
+				hdr.msg_controllen = cmsg_space as _;
+				unsafe {
+					let cmsg = libc::CMSG_FIRSTHDR(hdr);
+					(*cmsg).cmsg_level = libc::SOL_UDP;
+					(*cmsg).cmsg_type = libc::UDP_SEGMENT;
+					(*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+					std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, seg as u16);
+				}
+			}
+			msg_datagrams[msg_count] = j - i;
+			msg_count += 1;
+			i = j;
+		}
+
+		// sendmmsg only fails if the first message couldn't be sent:
+		*first = msg_datagrams[0];
+		let ret = unsafe { libc::sendmmsg(sock.as_raw_fd(), msgs.as_mut_ptr(), msg_count as _, 0) };
+		if ret < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let sent = &msg_datagrams[..ret as usize];
+		Ok((sent.iter().sum(), sent.iter().any(|n| *n > 1)))
+	}
+
+	pub fn is_gso_err(e: &io::Error) -> bool {
+		matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL | libc::ENOPROTOOPT | libc::EOPNOTSUPP))
+	}
+	pub fn is_size_err(e: &io::Error) -> bool {
+		e.raw_os_error() == Some(libc::EMSGSIZE)
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+	use std::{io, net::SocketAddr};
+
+	use tokio::net::UdpSocket;
+
+	use super::{BATCH, BUFF_LEN};
+
+	pub async fn recv(
+		sock: &UdpSocket,
+		buffs: &mut [[u8; BUFF_LEN]; BATCH],
+		lens: &mut [usize; BATCH],
+		addrs: &mut [SocketAddr; BATCH],
+	) -> io::Result<usize> {
+		let (len, addr) = sock.recv_from(&mut buffs[0]).await?;
+		lens[0] = len;
+		addrs[0] = addr;
+		Ok(1)
+	}
+	pub async fn send(
+		sock: &UdpSocket,
+		buffs: &[[u8; BUFF_LEN]],
+		lens: &[usize],
+		addrs: &[SocketAddr],
+		_gso: bool,
+	) -> Result<(usize, bool), (io::Error, usize)> {
+		for i in 0..lens.len() {
+			if let Err(e) = sock.send_to(&buffs[i][..lens[i]], addrs[i]).await {
+				return if i == 0 { Err((e, 1)) } else { Ok((i, false)) };
+			}
+		}
+		Ok((lens.len(), false))
+	}
+	pub fn is_gso_err(_: &io::Error) -> bool {
+		false
+	}
+	pub fn is_size_err(_: &io::Error) -> bool {
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::*;
+
+	async fn socks() -> [UdpSocket; 2] {
+		[UdpSocket::bind("127.0.0.1:0").await.unwrap(), UdpSocket::bind("127.0.0.1:0").await.unwrap()]
+	}
+
+	// 31 datagrams of 2114 bytes fit in an IP packet's length, but not in its payload: one GSO send can't carry them
+	const SEG: usize = 2114;
+
+	#[tokio::test]
+	async fn segmented() {
+		let [sock, peer] = socks().await;
+		socket2::SockRef::from(&peer).set_recv_buffer_size(1 << 20).unwrap();
+		let addr = peer.local_addr().unwrap();
+		let mut send = SendBatch::new();
+		for i in 0..BATCH {
+			send.buff().fill(i as u8);
+			send.push(SEG, addr, &Via::Udp(0), std::slice::from_ref(&sock)).await.unwrap();
+		}
+		send.flush(std::slice::from_ref(&sock)).await.unwrap();
+		let mut buff = [0u8; BUFF_LEN + 1];
+		for i in 0..BATCH {
+			let len = tokio::time::timeout(Duration::from_secs(5), peer.recv(&mut buff)).await.unwrap().unwrap();
+			assert_eq!(len, SEG);
+			assert!(buff[..len].iter().all(|b| *b == i as u8));
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn truncated() {
+		let [sock, peer] = socks().await;
+		let addr = sock.local_addr().unwrap();
+		peer.send_to(&[1; BUFF_LEN + 1], addr).await.unwrap();
+		peer.send_to(&[2; 100], addr).await.unwrap();
+		let mut recv = RecvBatch::new();
+		assert_eq!(recv.recv(&sock).await.unwrap(), 1);
+		let (data, from) = recv.iter().next().unwrap();
+		assert_eq!((data, from), (&[2; 100][..], peer.local_addr().unwrap()));
+	}
+}
@@ -0,0 +1,671 @@
+use std::{
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	path::PathBuf,
+};
+
+use eyre::{bail, eyre, Result, WrapErr};
+use serde::Deserialize;
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+	Error,
+	Warn,
+	Info,
+	Debug,
+	Trace,
+}
+impl std::str::FromStr for LogLevel {
+	type Err = eyre::Report;
+	fn from_str(s: &str) -> Result<Self> {
+		Ok(match s {
+			"error" => Self::Error,
+			"warn" => Self::Warn,
+			"info" => Self::Info,
+			"debug" => Self::Debug,
+			"trace" => Self::Trace,
+			_ => bail!("unknown log level {s:?} (expected error, warn, info, debug or trace)"),
+		})
+	}
+}
+
+impl From<LogLevel> for LevelFilter {
+	fn from(level: LogLevel) -> Self {
+		match level {
+			LogLevel::Error => Self::ERROR,
+			LogLevel::Warn => Self::WARN,
+			LogLevel::Info => Self::INFO,
+			LogLevel::Debug => Self::DEBUG,
+			LogLevel::Trace => Self::TRACE,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	Text,
+	Json,
+}
+impl std::str::FromStr for LogFormat {
+	type Err = eyre::Report;
+	fn from_str(s: &str) -> Result<Self> {
+		Ok(match s {
+			"text" => Self::Text,
+			"json" => Self::Json,
+			_ => bail!("unknown log format {s:?} (expected text or json)"),
+		})
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Secrets {
+	// Password for the long-term TURN credentials
+	pub turn_password: String,
+	// The ICE password that browsers use when connecting through the relay
+	pub ice_password: String,
+	// TURN REST API shared secrets.  When this isn't empty, turn_password is ignored and usernames have to be
+	// `<expiry>:<user>` with password base64(HMAC-SHA1(secret, username)).  New credentials are issued with the
+	// first secret; the rest are still accepted so that secrets can be rotated.
+	pub rest_secrets: Vec<String>,
+}
+impl Default for Secrets {
+	fn default() -> Self {
+		Self {
+			turn_password: "the/turn/password/constant".into(),
+			ice_password: "the/ice/password/constant".into(),
+			rest_secrets: Vec::new(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Lifetimes {
+	// Seconds:
+	pub allocation: u32,
+	pub nonce: u64,
+	// TCP and TLS connections that go this long without receiving anything are closed
+	pub idle: u64,
+	// The longest that a Refresh can extend a hosted pairing's allocations by
+	pub hosted: u32,
+}
+impl Default for Lifetimes {
+	fn default() -> Self {
+		Self {
+			allocation: 60,
+			nonce: 600,
+			idle: 300,
+			hosted: 3600,
+		}
+	}
+}
+
+// A token bucket: rate per second, up to burst at once.  A rate of 0 means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+	pub rate: u64,
+	pub burst: u64,
+}
+impl Rate {
+	pub const UNLIMITED: Self = Self { rate: 0, burst: 0 };
+	pub fn unlimited(&self) -> bool {
+		self.rate == 0
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+	// Past this many allocations, new allocations get a 508 Insufficient Capacity
+	pub max_allocations: usize,
+	// How many usernames to cache TURN keys for
+	pub key_cache: usize,
+	// STUN / TURN requests from each IP.  Requests over the limit are dropped before they're authenticated.
+	pub requests_per_ip: Rate,
+	// Concurrent allocations per client IP and per username (the user part, for TURN REST API usernames).  Past
+	// these, new allocations get a 486 Allocation Quota Reached.  0 means unlimited.
+	pub allocations_per_ip: usize,
+	pub allocations_per_username: usize,
+	// Bytes relayed by each allocation (both directions), and by each pairing in rendezvous mode.  Packets over the
+	// limit are dropped.
+	pub bytes_per_allocation: Rate,
+	pub bytes_per_pairing: Rate,
+}
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			max_allocations: 100_000,
+			key_cache: 4096,
+			requests_per_ip: Rate { rate: 100, burst: 200 },
+			allocations_per_ip: 256,
+			allocations_per_username: 16,
+			bytes_per_allocation: Rate::UNLIMITED,
+			bytes_per_pairing: Rate::UNLIMITED,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+	// Allocations relay to themselves, and data is passed between allocations with matching dst.src.token usernames
+	Rendezvous,
+	// Plain TURN: every allocation gets its own relayed UDP port, and data to / from peers needs permissions
+	Standard,
+}
+impl std::str::FromStr for Mode {
+	type Err = eyre::Report;
+	fn from_str(s: &str) -> Result<Self> {
+		Ok(match s {
+			"rendezvous" => Self::Rendezvous,
+			"standard" => Self::Standard,
+			_ => bail!("unknown mode {s:?} (expected rendezvous or standard)"),
+		})
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Relaying {
+	pub mode: Mode,
+	// Standard mode: where allocation sockets are bound
+	pub address: IpAddr,
+	// The address to put in XOR-RELAYED-ADDRESS when it isn't address (behind NAT, or when address is unspecified)
+	pub external_address: Option<IpAddr>,
+	pub min_port: u16,
+	pub max_port: u16,
+}
+impl Default for Relaying {
+	fn default() -> Self {
+		Self {
+			mode: Mode::Rendezvous,
+			address: Ipv4Addr::UNSPECIFIED.into(),
+			external_address: None,
+			min_port: 49152,
+			max_port: 65535,
+		}
+	}
+}
+
+// PEM files for the TLS listeners.  They're reloaded when they change (or on SIGHUP), so certificates can be renewed
+// without a restart.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+	// The certificate chain, leaf first
+	pub certificate: Option<PathBuf>,
+	pub private_key: Option<PathBuf>,
+}
+
+// An HTTP endpoint that hands out TURN REST API credentials: GET /ice-servers?user=<user>
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rest {
+	pub http_listen: Option<SocketAddr>,
+	// The TURN urls to put in the iceServers entry, e.g. "turn:relay.example.com:3478"
+	pub urls: Vec<String>,
+	// Seconds that issued credentials are valid for
+	pub credential_ttl: u64,
+	// If set, requests must also pass key=<api_key> (or an "Authorization: Bearer <api_key>" header)
+	pub api_key: Option<String>,
+}
+impl Default for Rest {
+	fn default() -> Self {
+		Self {
+			http_listen: None,
+			urls: Vec::new(),
+			credential_ttl: 86400,
+			api_key: None,
+		}
+	}
+}
+
+// Signaling over HTTP: POST /signal/<peer id> leaves a message (e.g. peercon's SigMsg) for that peer, which
+// long-polls GET /signal/<peer id> with TURN REST API credentials for its PeerId to collect its messages.  This can
+// share rest.http_listen.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Signaling {
+	pub http_listen: Option<SocketAddr>,
+	// Bytes
+	pub max_message: usize,
+	// Seconds that uncollected messages are kept
+	pub ttl: u64,
+	// The longest (in seconds) that a GET waits for messages
+	pub max_wait: u64,
+	// Uncollected messages per peer id, and peer ids that can have messages waiting at once
+	pub max_pending: usize,
+	pub max_mailboxes: usize,
+	// Uncollected messages per peer id from any one IP, so that one sender can't fill a mailbox for everyone else
+	pub max_pending_per_ip: usize,
+}
+impl Default for Signaling {
+	fn default() -> Self {
+		Self {
+			http_listen: None,
+			max_message: 4096,
+			ttl: 60,
+			max_wait: 30,
+			max_pending: 16,
+			max_mailboxes: 10_000,
+			max_pending_per_ip: 4,
+		}
+	}
+}
+
+// Rendezvous allocations can only be refreshed if one side of their pairing is a hosted peer.  Hosted peers are the
+// ones listed here plus the ones registered through the admin API, which are kept in the registry file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hosted {
+	pub peers: Vec<String>,
+	pub registry: Option<PathBuf>,
+}
+
+// The operator's HTTP endpoint: GET /metrics for Prometheus, plus the admin API (see admin.rs) for inspecting
+// allocations, kicking them, managing hosted peers and changing the log filter.  This can share an address with the
+// other endpoints, but it shouldn't be reachable from the internet.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+	pub http_listen: Option<SocketAddr>,
+	// If set, requests must have an "Authorization: Bearer <api_key>" header.  The admin API (everything but
+	// /metrics) is only served when it's set.
+	pub api_key: Option<String>,
+}
+
+// ICE-lite endpoint mode: the relay is a peer itself, reachable at udp:<peer_id>[:<password>]@<relay>.  It answers
+// connectivity checks on its UDP listen addresses, and hands the 5-tuple that gets nominated to a DTLS handler.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceLite {
+	// The relay's PeerId, which is its ICE ufrag.  ICE-lite is off without one.
+	pub peer_id: Option<String>,
+	// The ICE password (22 to 256 alphanumerics, '+' or '/')
+	pub password: String,
+	// Seconds that a 5-tuple keeps consent after its last connectivity check (RFC 7675)
+	pub consent: u64,
+	// Where to forward the DTLS of nominated 5-tuples (UDP), e.g. a native peer.  Without one, it's dropped.
+	pub dtls_backend: Option<SocketAddr>,
+}
+impl Default for IceLite {
+	fn default() -> Self {
+		Self {
+			peer_id: None,
+			password: "the/ice/password/constant".into(),
+			consent: 30,
+			dtls_backend: None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub listen: Vec<SocketAddr>,
+	// TURN over TCP and TLS (turns:) listen addresses
+	pub tcp_listen: Vec<SocketAddr>,
+	pub tls_listen: Vec<SocketAddr>,
+	// Worker threads, each with its own SO_REUSEPORT socket per listen address.  0 means one per CPU.
+	pub workers: usize,
+	pub realm: String,
+	pub log_level: LogLevel,
+	pub log_format: LogFormat,
+	// Per-module levels on top of log_level, in tracing's directive syntax: "relay::stream=debug,stun=trace"
+	pub log_filter: String,
+	pub secrets: Secrets,
+	pub lifetimes: Lifetimes,
+	pub limits: Limits,
+	pub relaying: Relaying,
+	pub tls: Tls,
+	pub rest: Rest,
+	pub signaling: Signaling,
+	pub hosted: Hosted,
+	pub admin: Admin,
+	pub ice_lite: IceLite,
+}
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			listen: vec!["[::]:3478".parse().unwrap()],
+			tcp_listen: Vec::new(),
+			tls_listen: Vec::new(),
+			workers: 0,
+			realm: "realm".into(),
+			log_level: LogLevel::Info,
+			log_format: LogFormat::Text,
+			log_filter: String::new(),
+			secrets: Secrets::default(),
+			lifetimes: Lifetimes::default(),
+			limits: Limits::default(),
+			relaying: Relaying::default(),
+			tls: Tls::default(),
+			rest: Rest::default(),
+			signaling: Signaling::default(),
+			hosted: Hosted::default(),
+			admin: Admin::default(),
+			ice_lite: IceLite::default(),
+		}
+	}
+}
+impl Config {
+	pub fn load(path: &PathBuf) -> Result<Self> {
+		let text = std::fs::read_to_string(path)
+			.wrap_err_with(|| format!("unable to read config file {}", path.display()))?;
+		toml::from_str(&text).wrap_err_with(|| format!("invalid config file {}", path.display()))
+	}
+	// log_level with log_filter's per-module levels
+	pub fn log_directives(&self) -> String {
+		match self.log_filter.as_str() {
+			"" => LevelFilter::from(self.log_level).to_string(),
+			filter => format!("{},{filter}", LevelFilter::from(self.log_level)),
+		}
+	}
+	pub fn log_filter(&self) -> Result<EnvFilter> {
+		EnvFilter::builder()
+			.parse(self.log_directives())
+			.wrap_err("log_filter: invalid directives")
+	}
+	// The number of worker threads to actually run
+	pub fn workers(&self) -> usize {
+		match self.workers {
+			0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+			n => n,
+		}
+	}
+	pub fn validate(&self) -> Result<()> {
+		if self.listen.is_empty() {
+			bail!("listen: at least one listener is required");
+		}
+		if self.realm.is_empty() {
+			bail!("realm: must not be empty");
+		}
+		self.log_filter()?;
+		if self.secrets.rest_secrets.iter().any(String::is_empty) {
+			bail!("secrets.rest_secrets: must not contain empty secrets");
+		}
+		if self.secrets.turn_password.is_empty() && self.secrets.rest_secrets.is_empty() {
+			bail!("secrets.turn_password: must not be empty");
+		}
+		if self.secrets.ice_password.is_empty() {
+			bail!("secrets.ice_password: must not be empty");
+		}
+		if self.lifetimes.allocation == 0 {
+			bail!("lifetimes.allocation: must be at least 1 second");
+		}
+		if self.lifetimes.nonce == 0 || self.lifetimes.nonce > 86400 {
+			bail!("lifetimes.nonce: must be between 1 second and a day");
+		}
+		if self.lifetimes.idle == 0 {
+			bail!("lifetimes.idle: must be at least 1 second");
+		}
+		if self.lifetimes.hosted == 0 {
+			bail!("lifetimes.hosted: must be at least 1 second");
+		}
+		if self.limits.max_allocations == 0 {
+			bail!("limits.max_allocations: must be at least 1");
+		}
+		if self.limits.key_cache == 0 {
+			bail!("limits.key_cache: must be at least 1");
+		}
+		if !self.limits.requests_per_ip.unlimited() && self.limits.requests_per_ip.burst == 0 {
+			bail!("limits.requests_per_ip: burst must be at least 1");
+		}
+		// A bucket that can't hold a full-size packet would never let one through
+		for (name, rate) in [
+			("bytes_per_allocation", self.limits.bytes_per_allocation),
+			("bytes_per_pairing", self.limits.bytes_per_pairing),
+		] {
+			if !rate.unlimited() && rate.burst < 4096 {
+				bail!("limits.{name}: burst must be at least 4096 bytes");
+			}
+		}
+		if self.relaying.min_port == 0 || self.relaying.min_port > self.relaying.max_port {
+			bail!("relaying: min_port must be between 1 and max_port");
+		}
+		if self.relaying.mode == Mode::Standard
+			&& self.relaying.address.is_unspecified()
+			&& self.relaying.external_address.is_none()
+		{
+			bail!("relaying.external_address: required in standard mode when relaying.address is unspecified");
+		}
+		if !self.tls_listen.is_empty() && (self.tls.certificate.is_none() || self.tls.private_key.is_none()) {
+			bail!("tls: tls_listen requires tls.certificate and tls.private_key");
+		}
+		if self.rest.http_listen.is_some() {
+			if self.secrets.rest_secrets.is_empty() {
+				bail!("rest.http_listen: requires secrets.rest_secrets");
+			}
+			if self.rest.urls.is_empty() {
+				bail!("rest.urls: at least one url is required to serve credentials");
+			}
+			if self.rest.credential_ttl == 0 || self.rest.credential_ttl > 30 * 86400 {
+				bail!("rest.credential_ttl: must be between 1 second and 30 days");
+			}
+		}
+		if self.signaling.http_listen.is_some() && self.secrets.rest_secrets.is_empty() {
+			bail!("signaling.http_listen: requires secrets.rest_secrets (peers collect their messages with them)");
+		}
+		if self.signaling.max_message == 0 {
+			bail!("signaling.max_message: must be at least 1 byte");
+		}
+		if self.signaling.ttl == 0 || self.signaling.ttl > 86400 {
+			bail!("signaling.ttl: must be between 1 second and a day");
+		}
+		// Long-polls shouldn't outlive the mailbox that they're waiting on
+		if self.signaling.max_wait > self.signaling.ttl {
+			bail!("signaling.max_wait: must be at most signaling.ttl");
+		}
+		let signaling = &self.signaling;
+		if signaling.max_pending == 0 || signaling.max_mailboxes == 0 || signaling.max_pending_per_ip == 0 {
+			bail!("signaling: max_pending, max_mailboxes and max_pending_per_ip must be at least 1");
+		}
+		for id in &self.hosted.peers {
+			id.parse::<peerid::PeerId>()
+				.wrap_err_with(|| format!("hosted.peers: invalid peer id {id:?}"))?;
+		}
+		if self.admin.api_key.as_deref() == Some("") {
+			bail!("admin.api_key: must not be empty");
+		}
+		if let Some(id) = &self.ice_lite.peer_id {
+			id.parse::<peerid::PeerId>()
+				.wrap_err_with(|| format!("ice_lite.peer_id: invalid peer id {id:?}"))?;
+		}
+		let password = &self.ice_lite.password;
+		if !(22..=256).contains(&password.len())
+			|| !password.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+		{
+			bail!("ice_lite.password: must be 22 to 256 alphanumerics, '+' or '/'");
+		}
+		if self.ice_lite.consent == 0 {
+			bail!("ice_lite.consent: must be at least 1 second");
+		}
+		if self.ice_lite.dtls_backend.is_some() && self.ice_lite.peer_id.is_none() {
+			bail!("ice_lite.dtls_backend: requires ice_lite.peer_id");
+		}
+		Ok(())
+	}
+}
+
+const USAGE: &str = "\
+Usage: relay [options]
+
+Options:
+  -c, --config <path>          TOML config file (the options below override it)
+      --listen <addr>          UDP listen address (repeatable, replaces the config's list)
+      --tcp-listen <addr>      TCP listen address (repeatable, replaces the config's list)
+      --tls-listen <addr>      TLS listen address (repeatable, replaces the config's list)
+      --tls-certificate <path> PEM certificate chain for the TLS listeners
+      --tls-key <path>         PEM private key for the TLS listeners
+      --workers <n>            Worker threads (0 = one per CPU)
+      --realm <realm>          TURN realm
+      --turn-password <pwd>    Password for the long-term TURN credentials
+      --ice-password <pwd>     ICE password used by browsers connecting through the relay
+      --rest-secret <secret>   TURN REST API shared secret (repeatable, replaces the config's list)
+      --http-listen <addr>     Serve TURN REST API credentials over HTTP on this address
+      --signal-listen <addr>   Serve signaling (POST / GET /signal/<peer id>) over HTTP on this address
+      --admin-listen <addr>    Serve metrics (GET /metrics) and the admin API over HTTP on this address
+      --hosted <peer id>       Hosted peer (repeatable, replaces the config's list)
+      --hosted-registry <path> File that hosted peers registered through the admin API are kept in
+      --ice-lite <peer id>     Answer ICE connectivity checks as this peer (udp:<peer id>@<relay> addresses)
+      --ice-lite-pwd <pwd>     ICE password for --ice-lite
+      --dtls-backend <addr>    Forward --ice-lite's DTLS to this UDP address
+      --lifetime <sec>         Allocation lifetime
+      --nonce-lifetime <sec>   Nonce lifetime
+      --idle-timeout <sec>     Close TCP / TLS connections that are idle this long
+      --log-level <level>      error, warn, info, debug or trace
+      --log-format <format>    text or json
+      --log-filter <filter>    Per-module log levels, e.g. relay::stream=debug,stun=trace
+      --max-allocations <n>    Maximum number of allocations
+      --mode <mode>            rendezvous or standard (a relayed UDP port per allocation)
+      --relay-address <ip>     Standard mode: address that relayed ports are bound on
+      --external-address <ip>  Standard mode: relayed address to advertise, if not --relay-address
+      --check-config           Validate the config and exit
+  -h, --help                   Print this help
+";
+
+pub enum Command {
+	Run(Config),
+	Check(Config),
+	Help,
+}
+
+// Parse the command line, load the config file (if any) and apply the overrides.
+pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Command> {
+	let mut args = args.into_iter();
+	let mut path = None;
+	let mut check = false;
+	let mut overrides: Vec<(String, String)> = Vec::new();
+	while let Some(arg) = args.next() {
+		let mut value = |name: &str| args.next().ok_or_else(|| eyre!("{name} requires a value\n\n{USAGE}"));
+		match arg.as_str() {
+			"-h" | "--help" => return Ok(Command::Help),
+			"--check-config" => check = true,
+			"-c" | "--config" => path = Some(PathBuf::from(value(&arg)?)),
+			"--listen" | "--tcp-listen" | "--tls-listen" | "--tls-certificate" | "--tls-key" | "--workers"
+			| "--realm" | "--turn-password" | "--ice-password" | "--rest-secret"
+			| "--http-listen" | "--signal-listen" | "--admin-listen" | "--hosted" | "--hosted-registry"
+			| "--ice-lite" | "--ice-lite-pwd" | "--dtls-backend"
+			| "--lifetime" | "--nonce-lifetime" | "--idle-timeout"
+			| "--log-level" | "--log-format" | "--log-filter"
+			| "--max-allocations" | "--mode" | "--relay-address" | "--external-address" => {
+				let v = value(&arg)?;
+				overrides.push((arg, v));
+			}
+			_ => bail!("unknown argument {arg:?}\n\n{USAGE}"),
+		}
+	}
+
+	let mut config = match &path {
+		Some(path) => Config::load(path)?,
+		None => Config::default(),
+	};
+	let mut listen = Vec::new();
+	let mut tcp_listen = Vec::new();
+	let mut tls_listen = Vec::new();
+	let mut rest_secrets = Vec::new();
+	let mut hosted = Vec::new();
+	for (name, value) in overrides {
+		let err = || format!("invalid value for {name}: {value:?}");
+		match name.as_str() {
+			"--listen" => listen.push(value.parse().wrap_err_with(err)?),
+			"--tcp-listen" => tcp_listen.push(value.parse().wrap_err_with(err)?),
+			"--tls-listen" => tls_listen.push(value.parse().wrap_err_with(err)?),
+			"--tls-certificate" => config.tls.certificate = Some(value.into()),
+			"--tls-key" => config.tls.private_key = Some(value.into()),
+			"--workers" => config.workers = value.parse().wrap_err_with(err)?,
+			"--realm" => config.realm = value,
+			"--turn-password" => config.secrets.turn_password = value,
+			"--ice-password" => config.secrets.ice_password = value,
+			"--rest-secret" => rest_secrets.push(value),
+			"--http-listen" => config.rest.http_listen = Some(value.parse().wrap_err_with(err)?),
+			"--signal-listen" => config.signaling.http_listen = Some(value.parse().wrap_err_with(err)?),
+			"--admin-listen" => config.admin.http_listen = Some(value.parse().wrap_err_with(err)?),
+			"--hosted" => hosted.push(value),
+			"--hosted-registry" => config.hosted.registry = Some(value.into()),
+			"--ice-lite" => config.ice_lite.peer_id = Some(value),
+			"--ice-lite-pwd" => config.ice_lite.password = value,
+			"--dtls-backend" => config.ice_lite.dtls_backend = Some(value.parse().wrap_err_with(err)?),
+			"--lifetime" => config.lifetimes.allocation = value.parse().wrap_err_with(err)?,
+			"--nonce-lifetime" => config.lifetimes.nonce = value.parse().wrap_err_with(err)?,
+			"--idle-timeout" => config.lifetimes.idle = value.parse().wrap_err_with(err)?,
+			"--log-level" => config.log_level = value.parse().wrap_err_with(err)?,
+			"--log-format" => config.log_format = value.parse().wrap_err_with(err)?,
+			"--log-filter" => config.log_filter = value,
+			"--max-allocations" => config.limits.max_allocations = value.parse().wrap_err_with(err)?,
+			"--mode" => config.relaying.mode = value.parse().wrap_err_with(err)?,
+			"--relay-address" => config.relaying.address = value.parse().wrap_err_with(err)?,
+			"--external-address" => {
+				config.relaying.external_address = Some(value.parse().wrap_err_with(err)?)
+			}
+			_ => unreachable!(),
+		}
+	}
+	if !listen.is_empty() {
+		config.listen = listen;
+	}
+	if !tcp_listen.is_empty() {
+		config.tcp_listen = tcp_listen;
+	}
+	if !tls_listen.is_empty() {
+		config.tls_listen = tls_listen;
+	}
+	if !rest_secrets.is_empty() {
+		config.secrets.rest_secrets = rest_secrets;
+	}
+	if !hosted.is_empty() {
+		config.hosted.peers = hosted;
+	}
+	config.validate().wrap_err("invalid config")?;
+
+	Ok(if check {
+		Command::Check(config)
+	} else {
+		Command::Run(config)
+	})
+}
+
+pub fn usage() -> &'static str {
+	USAGE
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn signaling_waits() {
+		let mut config = Config::default();
+		config.validate().unwrap();
+		config.signaling.max_wait = config.signaling.ttl + 1;
+		assert!(config.validate().is_err());
+		config.signaling.max_wait = config.signaling.ttl;
+		config.validate().unwrap();
+		// Huge values would overflow the deadlines computed from them
+		config.signaling.ttl = u64::MAX;
+		config.signaling.max_wait = u64::MAX;
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn lifetimes() {
+		let mut config = Config::default();
+		config.lifetimes.nonce = 86400;
+		config.validate().unwrap();
+		config.lifetimes.nonce = u64::MAX;
+		assert!(config.validate().is_err());
+
+		let mut config = Config::default();
+		config.rest.http_listen = Some(([127, 0, 0, 1], 8080).into());
+		config.secrets.rest_secrets = vec!["secret".into()];
+		config.rest.urls = vec!["turn:relay.example".into()];
+		config.rest.credential_ttl = 30 * 86400;
+		config.validate().unwrap();
+		config.rest.credential_ttl = u64::MAX;
+		assert!(config.validate().is_err());
+	}
+}
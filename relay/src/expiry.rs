@@ -0,0 +1,49 @@
+use std::{cmp::Reverse, collections::BinaryHeap, time::Instant};
+
+// A deadline queue for timers that get pushed back a lot (allocation refreshes, etc.).  Rescheduling doesn't touch
+// the old entry: it's left in the heap and skipped when it comes due, because the owner's current deadline no longer
+// matches it.  To keep memory bounded under churn, the heap is compacted whenever it's doubled in size since the
+// last compaction (which keeps the cost amortized).
+pub struct Deadlines<K> {
+	heap: BinaryHeap<Reverse<(Instant, K)>>,
+	// The heap's size after the last compaction
+	compacted: usize,
+}
+impl<K: Ord> Deadlines<K> {
+	pub fn new() -> Self {
+		Self {
+			heap: BinaryHeap::new(),
+			compacted: 0,
+		}
+	}
+	pub fn schedule(&mut self, deadline: Instant, key: K) {
+		self.heap.push(Reverse((deadline, key)));
+	}
+	// The earliest deadline, which may turn out to be stale.
+	pub fn next(&self) -> Option<Instant> {
+		self.heap.peek().map(|Reverse((deadline, _))| *deadline)
+	}
+	// Pop every entry that's due.  current(key) returns the key's deadline right now (None if it's gone), and only
+	// entries that still match it are returned.
+	pub fn due(&mut self, now: Instant, mut current: impl FnMut(&K) -> Option<Instant>) -> Vec<K> {
+		let mut ret = Vec::new();
+		while let Some(Reverse((deadline, _))) = self.heap.peek() {
+			if *deadline > now {
+				break;
+			}
+			let Reverse((deadline, key)) = self.heap.pop().unwrap();
+			if current(&key) == Some(deadline) {
+				ret.push(key);
+			}
+		}
+		ret
+	}
+	// Drop stale entries if the heap has doubled since the last compaction.  Call after scheduling.
+	pub fn compact(&mut self, mut current: impl FnMut(&K) -> Option<Instant>) {
+		if self.heap.len() > 2 * self.compacted + 64 {
+			self.heap
+				.retain(|Reverse((deadline, key))| current(key) == Some(*deadline));
+			self.compacted = self.heap.len();
+		}
+	}
+}
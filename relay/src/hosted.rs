@@ -0,0 +1,137 @@
+use std::{collections::BTreeSet, path::PathBuf, sync::RwLock};
+
+use eyre::{Result, WrapErr};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use peerid::PeerId;
+
+use crate::config;
+
+// The peers that the relay hosts: rendezvous allocations are only kept alive (refreshed) if one side of their pairing
+// is hosted.  Peers come from the config, which can't be changed at runtime, and from the registry, which the admin
+// API adds to and removes from.  The registry is kept in a file (one PeerId per line) so that it survives restarts.
+// PeerIds are kept in their canonical form, which is how they appear in TurnUsernames.
+pub struct Hosted {
+	configured: BTreeSet<String>,
+	registered: RwLock<BTreeSet<String>>,
+	registry: Option<PathBuf>,
+	// Held while the registry file is written, so that writes land in the order their changes were made
+	writing: Mutex<()>,
+}
+impl Hosted {
+	pub fn load(config: &config::Hosted) -> Result<Self> {
+		let registered = match &config.registry {
+			Some(path) => match std::fs::read_to_string(path) {
+				Ok(text) => text
+					.lines()
+					.map(str::trim)
+					.filter(|line| !line.is_empty() && !line.starts_with('#'))
+					.filter_map(|line| match line.parse::<PeerId>() {
+						Ok(id) => Some(id.to_string()),
+						Err(e) => {
+							let error = format!("{e:#}");
+							warn!(registry = %path.display(), line, error, "skipping invalid peer id");
+							None
+						}
+					})
+					.collect(),
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+				Err(e) => return Err(e).wrap_err_with(|| format!("unable to read {}", path.display())),
+			},
+			None => BTreeSet::new(),
+		};
+		Ok(Self {
+			configured: config.peers.iter().filter_map(|id| Some(id.parse::<PeerId>().ok()?.to_string())).collect(),
+			registered: RwLock::new(registered),
+			registry: config.registry.clone(),
+			writing: Mutex::new(()),
+		})
+	}
+	pub fn contains(&self, id: &str) -> bool {
+		self.configured.contains(id) || self.registered.read().unwrap().contains(id)
+	}
+	// Every hosted peer, and whether it's in the config (as opposed to the registry)
+	pub fn list(&self) -> Vec<(String, bool)> {
+		let registered = self.registered.read().unwrap();
+		let configured = self.configured.iter().map(|id| (id.clone(), true));
+		let registered = registered.difference(&self.configured).map(|id| (id.clone(), false));
+		configured.chain(registered).collect()
+	}
+	// Register a peer.  Returns whether it wasn't registered already.
+	pub async fn add(&self, id: &PeerId) -> Result<bool> {
+		let added = self.update(|registered| registered.insert(id.to_string())).await?;
+		if added {
+			info!(peer = %id, "hosted peer registered");
+		}
+		Ok(added)
+	}
+	// Unregister a peer.  Returns whether it was registered.  Peers from the config can't be removed.
+	pub async fn remove(&self, id: &PeerId) -> Result<bool> {
+		let removed = self.update(|registered| registered.remove(&id.to_string())).await?;
+		if removed {
+			info!(peer = %id, "hosted peer unregistered");
+		}
+		Ok(removed)
+	}
+	// Make a change to a copy of the registry, and only swap it in once it's been saved, so that a change that can't
+	// be saved doesn't take effect either.  Returns whether change changed anything.
+	async fn update(&self, change: impl FnOnce(&mut BTreeSet<String>) -> bool) -> Result<bool> {
+		let _writing = self.writing.lock().await;
+		let mut registered = self.registered.read().unwrap().clone();
+		if !change(&mut registered) {
+			return Ok(false);
+		}
+		self.save(&registered).await?;
+		*self.registered.write().unwrap() = registered;
+		Ok(true)
+	}
+	// Write the registry to a temporary file and move it into place, so that a crash can't leave it half written.
+	async fn save(&self, registered: &BTreeSet<String>) -> Result<()> {
+		let Some(path) = self.registry.clone() else { return Ok(()) };
+		let text: String = registered.iter().map(|id| format!("{id}\n")).collect();
+		tokio::task::spawn_blocking(move || {
+			let tmp = path.with_extension("tmp");
+			std::fs::write(&tmp, text)
+				.and_then(|_| std::fs::rename(&tmp, &path))
+				.wrap_err_with(|| format!("unable to write {}", path.display()))
+		})
+		.await?
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const A: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+	const B: &str = "gckU8soOEqYjH2nUkmW3JMtERbUfNZXErmnvxT5BT1I";
+
+	#[tokio::test]
+	async fn registry() {
+		let dir = std::env::temp_dir().join(format!("relay-hosted-{}", rand::random::<u64>()));
+		std::fs::create_dir(&dir).unwrap();
+		let config = config::Hosted {
+			peers: vec![B.into()],
+			registry: Some(dir.join("hosted")),
+		};
+		let (a, b) = (A.parse::<PeerId>().unwrap(), B.parse::<PeerId>().unwrap());
+		let hosted = Hosted::load(&config).unwrap();
+		assert!(hosted.add(&a).await.unwrap());
+		assert!(!hosted.add(&a).await.unwrap());
+		assert!(!hosted.remove(&b).await.unwrap());
+		assert_eq!(hosted.list(), [(B.to_string(), true), (A.to_string(), false)]);
+
+		// The registry survives a restart
+		let hosted = Hosted::load(&config).unwrap();
+		assert!(hosted.contains(A) && hosted.contains(B));
+
+		// Changes that can't be saved don't happen
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert!(hosted.remove(&a).await.is_err());
+		assert!(hosted.contains(A));
+		let c = "A".repeat(43).parse::<PeerId>().unwrap();
+		assert!(hosted.add(&c).await.is_err());
+		assert!(!hosted.contains(&c.to_string()));
+	}
+}
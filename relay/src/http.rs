@@ -0,0 +1,273 @@
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use eyre::Result;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+// Just enough HTTP/1.1 for the relay's small JSON / text endpoints: one request per connection.
+const MAX_HEAD: usize = 16 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct Request {
+	pub method: String,
+	pub path: String,
+	pub query: Vec<(String, String)>,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>,
+	pub peer: SocketAddr,
+}
+impl Request {
+	pub fn query(&self, name: &str) -> Option<&str> {
+		self.query
+			.iter()
+			.find(|(n, _)| n == name)
+			.map(|(_, v)| v.as_str())
+	}
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.as_str())
+	}
+}
+
+#[derive(Debug)]
+pub struct Response {
+	pub status: u16,
+	pub content_type: &'static str,
+	pub body: Vec<u8>,
+}
+impl Response {
+	pub fn json(value: &serde_json::Value) -> Self {
+		Self {
+			status: 200,
+			content_type: "application/json",
+			body: value.to_string().into_bytes(),
+		}
+	}
+	pub fn text(status: u16, body: impl Into<String>) -> Self {
+		Self {
+			status,
+			content_type: "text/plain; charset=utf-8",
+			body: body.into().into_bytes(),
+		}
+	}
+	pub fn not_found() -> Self {
+		Self::text(404, "not found\n")
+	}
+}
+
+fn reason(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		202 => "Accepted",
+		204 => "No Content",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		413 => "Payload Too Large",
+		431 => "Request Header Fields Too Large",
+		429 => "Too Many Requests",
+		500 => "Internal Server Error",
+		503 => "Service Unavailable",
+		_ => "",
+	}
+}
+
+pub fn percent_decode(s: &str) -> Option<String> {
+	let mut ret = Vec::with_capacity(s.len());
+	let mut bytes = s.bytes();
+	while let Some(b) = bytes.next() {
+		ret.push(match b {
+			b'+' => b' ',
+			b'%' => {
+				let hex = [bytes.next()?, bytes.next()?];
+				u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+			}
+			b => b,
+		});
+	}
+	String::from_utf8(ret).ok()
+}
+
+fn bad<E>(_: E) -> Response {
+	Response::text(400, "bad request\n")
+}
+
+// On failure, returns the response that should be sent instead.
+async fn read_request(stream: &mut TcpStream, peer: SocketAddr, max_body: usize) -> Result<Request, Response> {
+	let mut buff = Vec::new();
+	let head_len = loop {
+		if let Some(i) = buff.windows(4).position(|w| w == b"\r\n\r\n") {
+			break i + 4;
+		}
+		if buff.len() > MAX_HEAD {
+			return Err(Response::text(431, "request header fields too large\n"));
+		}
+		let mut chunk = [0u8; 4096];
+		let n = stream.read(&mut chunk).await.map_err(bad)?;
+		if n == 0 {
+			return Err(bad(()));
+		}
+		buff.extend_from_slice(&chunk[..n]);
+	};
+	let head = std::str::from_utf8(&buff[..head_len]).map_err(bad)?;
+	let mut lines = head.split("\r\n");
+	let mut request_line = lines.next().unwrap_or_default().split(' ');
+	let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+		return Err(bad(()));
+	};
+	let (path, query) = target.split_once('?').unwrap_or((target, ""));
+	let query = query
+		.split('&')
+		.filter(|p| !p.is_empty())
+		.filter_map(|p| {
+			let (n, v) = p.split_once('=').unwrap_or((p, ""));
+			Some((percent_decode(n)?, percent_decode(v)?))
+		})
+		.collect();
+	let headers: Vec<(String, String)> = lines
+		.filter_map(|l| l.split_once(':'))
+		.map(|(n, v)| (n.trim().to_string(), v.trim().to_string()))
+		.collect();
+
+	let content_length = headers
+		.iter()
+		.find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+		.map(|(_, v)| v.parse::<usize>())
+		.transpose()
+		.map_err(bad)?
+		.unwrap_or(0);
+	if content_length > max_body {
+		return Err(Response::text(413, "payload too large\n"));
+	}
+	let mut body = buff[head_len..].to_vec();
+	body.truncate(content_length);
+	while body.len() < content_length {
+		let mut chunk = vec![0u8; content_length - body.len()];
+		let n = stream.read(&mut chunk).await.map_err(bad)?;
+		if n == 0 {
+			return Err(bad(()));
+		}
+		body.extend_from_slice(&chunk[..n]);
+	}
+
+	Ok(Request {
+		method: method.to_string(),
+		path: percent_decode(path).unwrap_or_else(|| path.to_string()),
+		query,
+		headers,
+		body,
+		peer,
+	})
+}
+
+async fn write_response(stream: &mut TcpStream, res: &Response) -> Result<()> {
+	let head = format!(
+		"HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+		Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+		res.status,
+		reason(res.status),
+		res.content_type,
+		res.body.len()
+	);
+	stream.write_all(head.as_bytes()).await?;
+	stream.write_all(&res.body).await?;
+	stream.shutdown().await?;
+	Ok(())
+}
+
+async fn connection<F, Fut>(mut stream: TcpStream, peer: SocketAddr, max_body: usize, handler: F) -> Result<()>
+where
+	F: Fn(Request) -> Fut,
+	Fut: Future<Output = Response>,
+{
+	let res = match tokio::time::timeout(TIMEOUT, read_request(&mut stream, peer, max_body)).await {
+		Ok(Ok(req)) => handler(req).await,
+		Ok(Err(res)) => res,
+		Err(_) => return Ok(()),
+	};
+	tokio::time::timeout(TIMEOUT, write_response(&mut stream, &res)).await?
+}
+
+pub async fn serve<F, Fut>(listener: TcpListener, max_body: usize, handler: F)
+where
+	F: Fn(Request) -> Fut + Clone + Send + 'static,
+	Fut: Future<Output = Response> + Send,
+{
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(accepted) => accepted,
+			Err(e) => {
+				// Probably out of file descriptors: back off like stream::listen does.
+				warn!(error = %e, "http accept failed");
+				tokio::time::sleep(Duration::from_millis(100)).await;
+				continue;
+			}
+		};
+		let handler = handler.clone();
+		tokio::spawn(async move {
+			let _ = connection(stream, peer, max_body, handler).await;
+		});
+	}
+}
+
+// A client for testing the endpoints over loopback
+#[cfg(test)]
+pub mod client {
+	use std::{future::Future, net::SocketAddr};
+
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::{TcpListener, TcpStream},
+	};
+
+	use super::{Request, Response};
+
+	// Serve handler on a free loopback port
+	pub async fn serve<F, Fut>(max_body: usize, handler: F) -> SocketAddr
+	where
+		F: Fn(Request) -> Fut + Clone + Send + 'static,
+		Fut: Future<Output = Response> + Send + 'static,
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(super::serve(listener, max_body, handler));
+		addr
+	}
+	// Make a request and return its status and body
+	pub async fn request(
+		addr: SocketAddr,
+		method: &str,
+		target: &str,
+		headers: &[(&str, &str)],
+		body: &str,
+	) -> (u16, String) {
+		let mut stream = TcpStream::connect(addr).await.unwrap();
+		let mut head = format!("{method} {target} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {}\r\n", body.len());
+		for (name, value) in headers {
+			head += &format!("{name}: {value}\r\n");
+		}
+		stream.write_all(format!("{head}\r\n{body}").as_bytes()).await.unwrap();
+		let mut res = String::new();
+		stream.read_to_string(&mut res).await.unwrap();
+		let (head, body) = res.split_once("\r\n\r\n").unwrap();
+		(head.split(' ').nth(1).unwrap().parse().unwrap(), body.into())
+	}
+	// Percent-encode a query value
+	pub fn encode(s: &str) -> String {
+		s.bytes()
+			.map(|b| match b {
+				b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (b as char).to_string(),
+				b => format!("%{b:02X}"),
+			})
+			.collect()
+	}
+}
@@ -0,0 +1,557 @@
+use std::{
+	collections::{hash_map::Entry, HashMap},
+	io,
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use peerid::PeerId;
+use stun::attr::{Error, Integrity, IntegrityKey};
+use tokio::{net::UdpSocket, sync::watch, task::JoinHandle};
+use tracing::{debug, info, warn};
+
+use crate::{batch::BUFF_LEN, config, metrics::Outcome, relayed, webrtc::WebRTC};
+
+// A validated 5-tuple (always UDP): a remote address whose connectivity checks we've answered, and the socket that
+// they arrived on.
+#[derive(Debug, Clone)]
+pub struct Tuple {
+	pub remote: SocketAddr,
+	// The address that the remote agent sees us at (not the wildcard address that the socket is bound to)
+	pub local: SocketAddr,
+	// The remote agent's ufrag, which stays the same across the tuples of one ICE session
+	pub remote_ufrag: Arc<str>,
+	socks: Arc<[UdpSocket]>,
+	index: usize,
+}
+impl Tuple {
+	// Send a datagram (e.g. a DTLS record) to the remote address from the tuple's socket.
+	pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+		self.socks[self.index].send_to(packet, self.remote).await.map(|_| ())
+	}
+}
+
+// The address that datagrams to remote go out from.  Listen addresses are usually wildcards, and then that's whichever
+// address the kernel routes them from, which we find by connecting a throwaway socket.
+fn local_addr(sock: &UdpSocket, remote: SocketAddr) -> io::Result<SocketAddr> {
+	let bound = sock.local_addr()?;
+	if !bound.ip().is_unspecified() {
+		return Ok(bound);
+	}
+	let probe = std::net::UdpSocket::bind(SocketAddr::new(bound.ip(), 0))?;
+	probe.connect(remote)?;
+	Ok(SocketAddr::new(probe.local_addr()?.ip(), bound.port()))
+}
+
+// What runs on top of ICE-lite.  Calls can come from any shard, so they shouldn't block: a DTLS stack would queue
+// packets for its own task, and answer with Tuple::send.
+pub trait DtlsHandler: Send + Sync {
+	// The controlling agent nominated tuple, and it's now the selected one for its remote ufrag.  If the remote ufrag
+	// already had a selected tuple, the session has moved to this one.
+	fn selected(&self, tuple: &Tuple);
+	// A DTLS record from a selected tuple
+	fn dtls(&self, tuple: &Tuple, packet: &[u8]);
+	// The selected tuple lost consent, and there's no other nominated tuple for its remote ufrag to move to.
+	fn closed(&self, tuple: &Tuple);
+}
+
+// Without ice_lite.dtls_backend, DTLS is dropped.
+pub struct Discard;
+impl DtlsHandler for Discard {
+	fn selected(&self, tuple: &Tuple) {
+		debug!(remote = %tuple.remote, local = %tuple.local, "no dtls handler for the selected tuple");
+	}
+	fn dtls(&self, _: &Tuple, _: &[u8]) {}
+	fn closed(&self, _: &Tuple) {}
+}
+
+// ice_lite.dtls_backend: the relay doesn't terminate DTLS itself, but it can hand it to something that does.  Each ICE
+// session (remote ufrag) gets a socket of its own, so the backend sees one client per session however often the
+// session moves between tuples, and whatever the backend sends back goes to the session's selected tuple.
+pub struct Forward {
+	backend: SocketAddr,
+	sessions: Mutex<HashMap<Arc<str>, Session>>,
+}
+struct Session {
+	// Sent to directly rather than through tokio, which wouldn't know that a new socket is writable yet
+	sock: std::net::UdpSocket,
+	tuple: watch::Sender<Tuple>,
+	replies: JoinHandle<()>,
+}
+impl Drop for Session {
+	fn drop(&mut self) {
+		self.replies.abort();
+	}
+}
+impl Forward {
+	pub fn new(backend: SocketAddr) -> Self {
+		Self {
+			backend,
+			sessions: Mutex::new(HashMap::new()),
+		}
+	}
+	fn session(&self, tuple: &Tuple) -> io::Result<Session> {
+		let bind = if self.backend.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+		let sock = std::net::UdpSocket::bind(SocketAddr::new(bind, 0))?;
+		sock.connect(self.backend)?;
+		sock.set_nonblocking(true)?;
+		let (tx, rx) = watch::channel(tuple.clone());
+		let replies = tokio::spawn({
+			let sock = UdpSocket::from_std(sock.try_clone()?)?;
+			async move {
+				let mut buff = [0u8; BUFF_LEN];
+				loop {
+					// ICMP errors are from a backend that isn't there (yet), which the next datagram might fix
+					let len = match sock.recv(&mut buff).await {
+						Ok(len) => len,
+						Err(e) if relayed::transient(&e) => continue,
+						Err(e) => {
+							warn!(error = %e, "dtls backend socket failed");
+							break;
+						}
+					};
+					let tuple = rx.borrow().clone();
+					let _ = tuple.send(&buff[..len]).await;
+				}
+			}
+		});
+		Ok(Session { sock, tuple: tx, replies })
+	}
+}
+impl DtlsHandler for Forward {
+	fn selected(&self, tuple: &Tuple) {
+		let mut sessions = self.sessions.lock().unwrap();
+		if let Some(session) = sessions.get(&tuple.remote_ufrag) {
+			session.tuple.send_replace(tuple.clone());
+			return;
+		}
+		match self.session(tuple) {
+			Ok(session) => {
+				sessions.insert(tuple.remote_ufrag.clone(), session);
+			}
+			Err(e) => warn!(error = %e, remote = %tuple.remote, "unable to open a socket to the dtls backend"),
+		}
+	}
+	fn dtls(&self, tuple: &Tuple, packet: &[u8]) {
+		if let Some(session) = self.sessions.lock().unwrap().get(&tuple.remote_ufrag) {
+			let _ = session.sock.send(packet);
+		}
+	}
+	fn closed(&self, tuple: &Tuple) {
+		self.sessions.lock().unwrap().remove(&tuple.remote_ufrag);
+	}
+}
+
+// What IceLite did with a packet
+pub enum Handled {
+	// Not ICE for us, or DTLS from somewhere that we haven't validated: it's the TURN server's
+	Pass,
+	// A check whose MESSAGE-INTEGRITY didn't verify with our password (dropped)
+	Unauthorized,
+	// A response to the check is encoded in the buffer
+	Reply(usize, Outcome),
+	// DTLS that was handed to the DtlsHandler
+	Dtls,
+	// DTLS from a validated tuple that isn't selected (dropped)
+	Dropped,
+}
+
+struct Pair {
+	tuple: Tuple,
+	// The PRIORITY of its latest check: of the nominated pairs for a remote ufrag, the highest one is selected
+	priority: u32,
+	nominated: bool,
+	consent: Instant,
+}
+
+// ICE-lite (RFC 8445 section 2.5), for udp:<peer id>[:<ice pwd>]@<relay> addresses: the relay's ufrag is its peer id,
+// it has a host candidate on each UDP listen address, and it never sends checks of its own.  It answers the checks
+// that the (full, controlling) remote agent sends, and when one of them carries USE-CANDIDATE that 5-tuple is
+// nominated and its DTLS goes to the DtlsHandler.  Tuples lose consent (RFC 7675) if no check arrives for
+// ice_lite.consent seconds.
+// The shards share one: a remote agent's tuples are spread across shards by the kernel, but the selected tuple is
+// chosen from all of them.  Tuples are keyed by the index of the socket that they arrived on and the remote address.
+pub struct IceLite {
+	ufrag: String,
+	key: IntegrityKey,
+	consent: Duration,
+	handler: Arc<dyn DtlsHandler>,
+	socks: Arc<[UdpSocket]>,
+	state: Mutex<State>,
+}
+#[derive(Default)]
+struct State {
+	pairs: HashMap<(usize, SocketAddr), Pair>,
+	// remote ufrag -> its selected pair
+	selected: HashMap<Arc<str>, (usize, SocketAddr)>,
+}
+impl IceLite {
+	// None if ice_lite.peer_id isn't set
+	pub fn new(config: &config::IceLite, handler: Arc<dyn DtlsHandler>, socks: Arc<[UdpSocket]>) -> Option<Self> {
+		let ufrag = config.peer_id.as_ref()?.parse::<PeerId>().ok()?;
+		Some(Self {
+			ufrag: ufrag.to_string(),
+			key: IntegrityKey::new(config.password.as_bytes()),
+			consent: Duration::from_secs(config.consent),
+			handler,
+			socks,
+			state: Mutex::default(),
+		})
+	}
+
+	// When the pair for addr on socks[index] loses consent
+	pub fn consent(&self, index: usize, addr: SocketAddr) -> Option<Instant> {
+		self.state.lock().unwrap().pairs.get(&(index, addr)).map(|pair| pair.consent)
+	}
+
+	// Handle a packet from addr that arrived on socks[index]
+	pub fn handle(&self, packet: &[u8], index: usize, addr: SocketAddr, buff: &mut [u8], now: Instant) -> Handled {
+		match WebRTC::decode(packet) {
+			Some(WebRTC::IceReq {
+				txid,
+				integrity,
+				username,
+				priority,
+				is_controlling,
+				use_candidate,
+				..
+			}) => {
+				// USERNAME is <our ufrag>:<their ufrag>
+				let Some((ours, theirs)) = username.split_once(':') else { return Handled::Pass };
+				if ours != self.ufrag {
+					return Handled::Pass;
+				}
+				if theirs.is_empty() || !integrity.verify_key(&self.key) {
+					return Handled::Unauthorized;
+				}
+				let integrity = Integrity::Key(&self.key);
+				// A lite agent is always the controlled one (RFC 8445 section 6.1.1), so when the other agent thinks
+				// that it's controlled too, it's the one that has to switch: 487 tells it to.
+				if !is_controlling {
+					let error = Error {
+						code: 487,
+						message: "Role Conflict",
+					};
+					debug!(remote_ufrag = theirs, "role conflict");
+					let res = WebRTC::IceErr { txid, integrity, error };
+					return res.encode_packet(buff).map_or(Handled::Dropped, |len| Handled::Reply(len, Outcome::Error));
+				}
+				let res = WebRTC::IceRes {
+					txid,
+					xmapped: addr,
+					integrity,
+				};
+				let Some(len) = res.encode_packet(buff) else { return Handled::Dropped };
+				self.state.lock().unwrap().checked(self, (index, addr), theirs, priority, use_candidate, now);
+				Handled::Reply(len, Outcome::Success)
+			}
+			Some(WebRTC::Dtls(_)) => {
+				let state = self.state.lock().unwrap();
+				match state.pairs.get(&(index, addr)) {
+					Some(pair) if state.selected.get(&pair.tuple.remote_ufrag) == Some(&(index, addr)) => {
+						self.handler.dtls(&pair.tuple, packet);
+						Handled::Dtls
+					}
+					Some(_) => Handled::Dropped,
+					None => Handled::Pass,
+				}
+			}
+			_ => Handled::Pass,
+		}
+	}
+
+	// The pair for addr on socks[index] lost consent, unless a check has arrived since
+	pub fn expire(&self, index: usize, addr: SocketAddr, now: Instant) {
+		let mut state = self.state.lock().unwrap();
+		if state.pairs.get(&(index, addr)).is_some_and(|pair| pair.consent <= now) {
+			info!(remote = %addr, "ice-lite consent expired");
+			state.remove(self, (index, addr));
+		}
+	}
+}
+impl State {
+	// A check succeeded: its pair is valid (with fresh consent), and nominated if it had USE-CANDIDATE.
+	fn checked(
+		&mut self,
+		ice: &IceLite,
+		key: (usize, SocketAddr),
+		remote_ufrag: &str,
+		priority: u32,
+		use_candidate: bool,
+		now: Instant,
+	) {
+		// An ICE restart changes the remote ufrag, which starts over
+		if self.pairs.get(&key).is_some_and(|pair| &*pair.tuple.remote_ufrag != remote_ufrag) {
+			self.remove(ice, key);
+		}
+		let pair = match self.pairs.entry(key) {
+			Entry::Occupied(entry) => entry.into_mut(),
+			Entry::Vacant(entry) => {
+				let (index, remote) = key;
+				let sock = &ice.socks[index];
+				let local = local_addr(sock, remote).or_else(|_| sock.local_addr()).unwrap_or(remote);
+				entry.insert(Pair {
+					tuple: Tuple {
+						remote,
+						local,
+						remote_ufrag: remote_ufrag.into(),
+						socks: ice.socks.clone(),
+						index,
+					},
+					priority,
+					nominated: false,
+					consent: now,
+				})
+			}
+		};
+		pair.priority = priority;
+		pair.consent = now + ice.consent;
+		pair.nominated |= use_candidate;
+		if !pair.nominated {
+			return;
+		}
+		let remote_ufrag = pair.tuple.remote_ufrag.clone();
+		let better = match self.selected.get(&remote_ufrag).and_then(|k| self.pairs.get(k)) {
+			Some(current) => (current.tuple.index, current.tuple.remote) != key && current.priority < priority,
+			None => true,
+		};
+		if better {
+			self.select(ice, remote_ufrag, key);
+		}
+	}
+
+	fn select(&mut self, ice: &IceLite, remote_ufrag: Arc<str>, key: (usize, SocketAddr)) {
+		let Some(pair) = self.pairs.get(&key) else { return };
+		let tuple = &pair.tuple;
+		let (remote, local, priority) = (tuple.remote, tuple.local, pair.priority);
+		info!(%remote, %local, %remote_ufrag, priority, "ice-lite tuple selected");
+		self.selected.insert(remote_ufrag, key);
+		ice.handler.selected(tuple);
+	}
+
+	// Forget a pair.  If it was selected, the best of its remote ufrag's other nominated pairs takes over.
+	fn remove(&mut self, ice: &IceLite, key: (usize, SocketAddr)) {
+		let Some(pair) = self.pairs.remove(&key) else { return };
+		let remote_ufrag = pair.tuple.remote_ufrag.clone();
+		if self.selected.get(&remote_ufrag) != Some(&key) {
+			return;
+		}
+		self.selected.remove(&remote_ufrag);
+		let next = self
+			.pairs
+			.iter()
+			.filter(|(_, p)| p.nominated && p.tuple.remote_ufrag == remote_ufrag)
+			.max_by_key(|(_, p)| p.priority)
+			.map(|(k, _)| *k);
+		match next {
+			Some(next) => self.select(ice, remote_ufrag, next),
+			None => ice.handler.closed(&pair.tuple),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ID: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+	const PASSWORD: &str = "the/ice/password/constant";
+
+	// A DtlsHandler that records what it's told
+	#[derive(Default)]
+	struct Record(Mutex<Vec<String>>);
+	impl Record {
+		fn take(&self) -> Vec<String> {
+			std::mem::take(&mut self.0.lock().unwrap())
+		}
+	}
+	impl DtlsHandler for Record {
+		fn selected(&self, tuple: &Tuple) {
+			let line = format!("selected {} {} {}", tuple.index, tuple.remote, tuple.remote_ufrag);
+			self.0.lock().unwrap().push(line);
+		}
+		fn dtls(&self, tuple: &Tuple, packet: &[u8]) {
+			self.0.lock().unwrap().push(format!("dtls {} {} {}", tuple.index, tuple.remote, packet.len()));
+		}
+		fn closed(&self, tuple: &Tuple) {
+			self.0.lock().unwrap().push(format!("closed {} {}", tuple.index, tuple.remote));
+		}
+	}
+
+	// An IceLite with two sockets, as if there were two shards
+	async fn ice_lite(handler: Arc<dyn DtlsHandler>) -> IceLite {
+		let mut socks = Vec::new();
+		for _ in 0..2 {
+			socks.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+		}
+		let config = config::IceLite {
+			peer_id: Some(ID.into()),
+			..Default::default()
+		};
+		IceLite::new(&config, handler, socks.into()).unwrap()
+	}
+
+	// A connectivity check from the remote agent
+	fn check(username: &str, password: &str, priority: u32, is_controlling: bool, use_candidate: bool) -> Vec<u8> {
+		let key = IntegrityKey::new(password.as_bytes());
+		let req = WebRTC::IceReq {
+			txid: rand::random(),
+			integrity: Integrity::Key(&key),
+			username,
+			priority,
+			tie_breaker: 1,
+			is_controlling,
+			use_candidate,
+		};
+		let mut buff = [0u8; 512];
+		let len = req.encode_packet(&mut buff).unwrap();
+		buff[..len].to_vec()
+	}
+	fn nominate(priority: u32) -> Vec<u8> {
+		check(&format!("{ID}:theirs"), PASSWORD, priority, true, true)
+	}
+	// A DTLS record (handshake content type)
+	const DTLS: [u8; 13] = [22, 254, 253, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+	#[tokio::test]
+	async fn binding() {
+		let record = Arc::new(Record::default());
+		let ice = ice_lite(record.clone()).await;
+		let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+		let now = Instant::now();
+		let mut buff = [0u8; 512];
+		let packet = check(&format!("{ID}:theirs"), PASSWORD, 100, true, false);
+		let Handled::Reply(len, Outcome::Success) = ice.handle(&packet, 0, addr, &mut buff, now) else { panic!() };
+		let Some(WebRTC::IceRes { xmapped, integrity, .. }) = WebRTC::decode(&buff[..len]) else { panic!() };
+		assert_eq!(xmapped, addr);
+		assert!(integrity.verify_key(&IntegrityKey::new(PASSWORD.as_bytes())));
+		assert_eq!(ice.consent(0, addr), Some(now + Duration::from_secs(30)));
+		assert_eq!(ice.consent(1, addr), None);
+
+		// The pair is valid, but without USE-CANDIDATE it isn't selected
+		assert!(record.take().is_empty());
+		assert!(matches!(ice.handle(&DTLS, 0, addr, &mut buff, now), Handled::Dropped));
+		assert!(matches!(ice.handle(&DTLS, 1, addr, &mut buff, now), Handled::Pass));
+
+		// Its local address is the one that the remote agent sends to, not the wildcard
+		let state = ice.state.lock().unwrap();
+		assert_eq!(state.pairs[&(0, addr)].tuple.local, ice.socks[0].local_addr().unwrap());
+	}
+
+	#[tokio::test]
+	async fn rejected() {
+		let ice = ice_lite(Arc::new(Record::default())).await;
+		let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+		let now = Instant::now();
+		let mut buff = [0u8; 512];
+		let other = "gckU8soOEqYjH2nUkmW3JMtERbUfNZXErmnvxT5BT1I:theirs";
+		assert!(matches!(ice.handle(&check(other, PASSWORD, 100, true, true), 0, addr, &mut buff, now), Handled::Pass));
+		assert!(matches!(ice.handle(&check(ID, PASSWORD, 100, true, true), 0, addr, &mut buff, now), Handled::Pass));
+		let packet = check(&format!("{ID}:"), PASSWORD, 100, true, true);
+		assert!(matches!(ice.handle(&packet, 0, addr, &mut buff, now), Handled::Unauthorized));
+		let packet = check(&format!("{ID}:theirs"), "not/the/ice/password/constant", 100, true, true);
+		assert!(matches!(ice.handle(&packet, 0, addr, &mut buff, now), Handled::Unauthorized));
+		assert_eq!(ice.consent(0, addr), None);
+	}
+
+	#[tokio::test]
+	async fn role_conflict() {
+		let record = Arc::new(Record::default());
+		let ice = ice_lite(record.clone()).await;
+		let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+		let mut buff = [0u8; 512];
+		let packet = check(&format!("{ID}:theirs"), PASSWORD, 100, false, true);
+		let Handled::Reply(len, Outcome::Error) = ice.handle(&packet, 0, addr, &mut buff, Instant::now()) else {
+			panic!()
+		};
+		let Some(WebRTC::IceErr { error, .. }) = WebRTC::decode(&buff[..len]) else { panic!() };
+		assert_eq!(error.code, 487);
+		assert_eq!(ice.consent(0, addr), None);
+		assert!(record.take().is_empty());
+	}
+
+	#[tokio::test]
+	async fn nomination() {
+		let record = Arc::new(Record::default());
+		let ice = ice_lite(record.clone()).await;
+		let (a, b, c): (SocketAddr, SocketAddr, SocketAddr) =
+			("127.0.0.1:5000".parse().unwrap(), "127.0.0.1:5001".parse().unwrap(), "127.0.0.1:5002".parse().unwrap());
+		let now = Instant::now();
+		let mut buff = [0u8; 512];
+
+		assert!(matches!(ice.handle(&nominate(100), 0, a, &mut buff, now), Handled::Reply(..)));
+		assert_eq!(record.take(), ["selected 0 127.0.0.1:5000 theirs"]);
+		assert!(matches!(ice.handle(&DTLS, 0, a, &mut buff, now), Handled::Dtls));
+		assert_eq!(record.take(), ["dtls 0 127.0.0.1:5000 13"]);
+
+		// A higher priority nomination takes over, even when it arrives on another shard's socket
+		assert!(matches!(ice.handle(&nominate(200), 1, b, &mut buff, now), Handled::Reply(..)));
+		assert_eq!(record.take(), ["selected 1 127.0.0.1:5001 theirs"]);
+		assert!(matches!(ice.handle(&DTLS, 0, a, &mut buff, now), Handled::Dropped));
+		assert!(matches!(ice.handle(&DTLS, 1, b, &mut buff, now), Handled::Dtls));
+		assert_eq!(record.take(), ["dtls 1 127.0.0.1:5001 13"]);
+
+		// A lower one doesn't, and checks on the selected pair don't select it again
+		assert!(matches!(ice.handle(&nominate(150), 0, c, &mut buff, now), Handled::Reply(..)));
+		assert!(matches!(ice.handle(&nominate(200), 1, b, &mut buff, now), Handled::Reply(..)));
+		assert!(record.take().is_empty());
+
+		// Another remote agent gets a selected pair of its own
+		let packet = check(&format!("{ID}:other"), PASSWORD, 50, true, true);
+		assert!(matches!(ice.handle(&packet, 0, c, &mut buff, now), Handled::Reply(..)));
+		assert_eq!(record.take(), ["selected 0 127.0.0.1:5002 other"]);
+		assert!(matches!(ice.handle(&DTLS, 0, c, &mut buff, now), Handled::Dtls));
+		assert!(matches!(ice.handle(&DTLS, 1, b, &mut buff, now), Handled::Dtls));
+	}
+
+	#[tokio::test]
+	async fn consent() {
+		let record = Arc::new(Record::default());
+		let ice = ice_lite(record.clone()).await;
+		let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:5000".parse().unwrap(), "127.0.0.1:5001".parse().unwrap());
+		let now = Instant::now();
+		let consent = Duration::from_secs(30);
+		let mut buff = [0u8; 512];
+		ice.handle(&nominate(100), 0, a, &mut buff, now);
+		ice.handle(&nominate(200), 1, b, &mut buff, now);
+		record.take();
+
+		// A check renews consent
+		ice.handle(&nominate(100), 0, a, &mut buff, now + consent);
+		ice.expire(0, a, now + consent);
+		assert_eq!(ice.consent(0, a), Some(now + consent * 2));
+
+		// The selected pair losing consent fails over to the best nominated pair that's left, and the last one to go
+		// closes the session
+		ice.expire(1, b, now + consent);
+		assert_eq!(record.take(), ["selected 0 127.0.0.1:5000 theirs"]);
+		assert!(matches!(ice.handle(&DTLS, 1, b, &mut buff, now), Handled::Pass));
+		ice.expire(0, a, now + consent * 2);
+		assert_eq!(record.take(), ["closed 0 127.0.0.1:5000"]);
+		assert!(matches!(ice.handle(&DTLS, 0, a, &mut buff, now), Handled::Pass));
+	}
+
+	#[tokio::test]
+	async fn forward() {
+		let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let forward = Arc::new(Forward::new(backend.local_addr().unwrap()));
+		let ice = ice_lite(forward.clone()).await;
+		let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = remote.local_addr().unwrap();
+		let mut buff = [0u8; 512];
+		ice.handle(&nominate(100), 0, addr, &mut buff, Instant::now());
+
+		// DTLS from the selected tuple goes to the backend, and its replies go back out of the tuple's socket
+		assert!(matches!(ice.handle(&DTLS, 0, addr, &mut buff, Instant::now()), Handled::Dtls));
+		let (len, session) = backend.recv_from(&mut buff).await.unwrap();
+		assert_eq!(buff[..len], DTLS);
+		backend.send_to(b"\x17reply", session).await.unwrap();
+		let (len, from) = remote.recv_from(&mut buff).await.unwrap();
+		assert_eq!((&buff[..len], from), (&b"\x17reply"[..], ice.socks[0].local_addr().unwrap()));
+
+		// Losing consent closes the session
+		ice.expire(0, addr, Instant::now() + Duration::from_secs(30));
+		assert!(forward.sessions.lock().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+use stun::attr::IntegrityKey;
+
+use crate::auth::{long_term_key, Credentials};
+
+type Entry = (Box<str>, Box<[IntegrityKey]>);
+
+// Caches the long-term credentials (MD5 + HMAC key state) for each username so that they're only computed once.
+// There's one key per password that the username could have (see Credentials).
+// Don't let the cache grow without bound: once it's full we just start over.
+pub struct KeyCache {
+	credentials: Credentials,
+	capacity: usize,
+	// username -> (realm, keys)
+	keys: HashMap<Box<str>, Entry>,
+}
+impl KeyCache {
+	pub fn new(credentials: Credentials, capacity: usize) -> Self {
+		Self {
+			credentials,
+			capacity,
+			keys: HashMap::new(),
+		}
+	}
+	pub fn get(&mut self, username: &str, realm: Option<&str>) -> &[IntegrityKey] {
+		let Some(realm) = realm else { return &[] };
+		// Expiry has to be checked every time, not just when the keys are computed:
+		if self.credentials.expired(username) {
+			return &[];
+		}
+		if self.keys.get(username).is_none_or(|(r, _)| r.as_ref() != realm) {
+			let keys = self
+				.credentials
+				.passwords(username)
+				.map(|password| IntegrityKey::new(&long_term_key(username, realm, &password)))
+				.collect();
+			if self.keys.len() >= self.capacity {
+				self.keys.clear();
+			}
+			self.keys.insert(username.into(), (realm.into(), keys));
+		}
+		&self.keys[username].1
+	}
+}
@@ -0,0 +1,296 @@
+use std::{
+	borrow::Borrow,
+	collections::HashMap,
+	hash::Hash,
+	net::IpAddr,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	auth::Credentials,
+	config::{self, Rate},
+	turn::TurnUsername,
+};
+
+// How often buckets that have filled back up are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// A token bucket.  It starts out full, and refills at rate.rate tokens per second up to rate.burst.
+pub struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+impl Bucket {
+	pub fn new(rate: Rate, now: Instant) -> Self {
+		Self {
+			tokens: rate.burst as f64,
+			updated: now,
+		}
+	}
+	fn refill(&mut self, rate: Rate, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * rate.rate as f64).min(rate.burst as f64);
+		self.updated = now;
+	}
+	// Take n tokens if there are that many
+	pub fn take(&mut self, rate: Rate, n: u64, now: Instant) -> bool {
+		if rate.unlimited() {
+			return true;
+		}
+		self.refill(rate, now);
+		if self.tokens < n as f64 {
+			return false;
+		}
+		self.tokens -= n as f64;
+		true
+	}
+}
+
+// Token buckets that are shared by every shard, one per key.  A full bucket is the same as no bucket, so they're
+// dropped once they've filled back up.
+pub struct Buckets<K> {
+	rate: Rate,
+	buckets: Mutex<HashMap<K, Bucket>>,
+}
+impl<K: Hash + Eq> Buckets<K> {
+	pub fn new(rate: Rate) -> Self {
+		Self {
+			rate,
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+	pub fn take<Q>(&self, key: &Q, n: u64, now: Instant) -> bool
+	where
+		K: Borrow<Q>,
+		Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+	{
+		if self.rate.unlimited() {
+			return true;
+		}
+		let mut buckets = self.buckets.lock().unwrap();
+		match buckets.get_mut(key) {
+			Some(bucket) => bucket.take(self.rate, n, now),
+			None => {
+				let mut bucket = Bucket::new(self.rate, now);
+				let ret = bucket.take(self.rate, n, now);
+				buckets.insert(key.to_owned(), bucket);
+				ret
+			}
+		}
+	}
+	pub fn sweep(&self, now: Instant) {
+		let burst = self.rate.burst as f64;
+		self.buckets.lock().unwrap().retain(|_, bucket| {
+			bucket.refill(self.rate, now);
+			bucket.tokens < burst
+		});
+	}
+}
+
+#[derive(Default)]
+struct Quotas {
+	// Live allocations per client IP and per user
+	ips: HashMap<IpAddr, usize>,
+	users: HashMap<Box<str>, usize>,
+}
+
+// The rate limits and allocation quotas from [limits].  Bandwidth per allocation is kept in each allocation's own
+// Bucket (only its shard touches it); everything else is shared by the shards.
+pub struct Limiter {
+	requests: Buckets<IpAddr>,
+	pairings: Buckets<String>,
+	pub bytes_per_allocation: Rate,
+	allocations_per_ip: usize,
+	allocations_per_username: usize,
+	quotas: Mutex<Quotas>,
+}
+impl Limiter {
+	pub fn new(limits: &config::Limits) -> Self {
+		Self {
+			requests: Buckets::new(limits.requests_per_ip),
+			pairings: Buckets::new(limits.bytes_per_pairing),
+			bytes_per_allocation: limits.bytes_per_allocation,
+			allocations_per_ip: limits.allocations_per_ip,
+			allocations_per_username: limits.allocations_per_username,
+			quotas: Mutex::new(Quotas::default()),
+		}
+	}
+	// Whether a STUN request from ip is within the request rate.  Anything else (ChannelData, indications) isn't
+	// counted here: that's what the bandwidth limits are for.
+	pub fn request(&self, packet: &[u8], ip: IpAddr, now: Instant) -> bool {
+		// The first two bits are 00 for STUN, and the class bits (0x0110) are 00 for requests
+		let request =
+			packet.len() >= 20 && packet[0] < 0x40 && u16::from_be_bytes([packet[0], packet[1]]) & 0x0110 == 0;
+		!request || self.requests.take(&ip, 1, now)
+	}
+	// Whether a pairing can relay len more bytes.  Both sides of a pairing share its bucket, which is keyed by
+	// whichever of their names (dst.src.token and src.dst.token) sorts first.
+	pub fn pairing(&self, pairing: &TurnUsername, len: usize, now: Instant) -> bool {
+		let key = pairing.name().min(pairing.reversed());
+		self.pairings.take(key, len as u64, now)
+	}
+	// Count a new allocation against its IP's and username's quotas, unless either of them is used up.  Every
+	// allocation that's let through has to be released when it goes away.  replacing is the username of the client's
+	// current allocation, if the new one replaces it: that one's about to be released, so it doesn't count.
+	pub fn allocate(&self, ip: IpAddr, username: &str, replacing: Option<&str>) -> bool {
+		let user = Credentials::user(username);
+		let mut quotas = self.quotas.lock().unwrap();
+		let freed_ip = replacing.is_some() as usize;
+		let freed_user = replacing.is_some_and(|old| Credentials::user(old) == user) as usize;
+		let over = |count: Option<&usize>, freed: usize, max: usize| {
+			max != 0 && count.is_some_and(|c| c.saturating_sub(freed) >= max)
+		};
+		if over(quotas.ips.get(&ip), freed_ip, self.allocations_per_ip)
+			|| over(quotas.users.get(user), freed_user, self.allocations_per_username)
+		{
+			return false;
+		}
+		*quotas.ips.entry(ip).or_default() += 1;
+		*quotas.users.entry(user.into()).or_default() += 1;
+		true
+	}
+	pub fn release(&self, ip: IpAddr, username: &str) {
+		let user = Credentials::user(username);
+		let mut quotas = self.quotas.lock().unwrap();
+		if let Some(count) = quotas.ips.get_mut(&ip) {
+			*count -= 1;
+			if *count == 0 {
+				quotas.ips.remove(&ip);
+			}
+		}
+		if let Some(count) = quotas.users.get_mut(user) {
+			*count -= 1;
+			if *count == 0 {
+				quotas.users.remove(user);
+			}
+		}
+	}
+	// Periodically forget buckets that have filled back up, so that the maps only hold recently active keys.
+	pub async fn sweep(&self) {
+		let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+		loop {
+			interval.tick().await;
+			let now = Instant::now();
+			self.requests.sweep(now);
+			self.pairings.sweep(now);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const RATE: Rate = Rate { rate: 10, burst: 100 };
+
+	#[test]
+	fn bucket_burst() {
+		let now = Instant::now();
+		let mut bucket = Bucket::new(RATE, now);
+		assert!(!bucket.take(RATE, 101, now));
+		assert!(bucket.take(RATE, 60, now));
+		assert!(bucket.take(RATE, 40, now));
+		assert!(!bucket.take(RATE, 1, now));
+	}
+
+	#[test]
+	fn bucket_refill() {
+		let now = Instant::now();
+		let mut bucket = Bucket::new(RATE, now);
+		assert!(bucket.take(RATE, 100, now));
+		let now = now + Duration::from_secs(2);
+		assert!(!bucket.take(RATE, 21, now));
+		assert!(bucket.take(RATE, 20, now));
+		// It doesn't fill past the burst, however long it's been
+		let now = now + Duration::from_secs(3600);
+		assert!(!bucket.take(RATE, 101, now));
+		assert!(bucket.take(RATE, 100, now));
+	}
+
+	#[test]
+	fn unlimited() {
+		let now = Instant::now();
+		let mut bucket = Bucket::new(Rate::UNLIMITED, now);
+		assert!(bucket.take(Rate::UNLIMITED, u64::MAX, now));
+		let buckets = Buckets::<IpAddr>::new(Rate::UNLIMITED);
+		let ip = IpAddr::from([192, 0, 2, 1]);
+		assert!(buckets.take(&ip, u64::MAX, now));
+		// Nothing's kept for unlimited rates
+		assert!(buckets.buckets.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn sweep() {
+		let now = Instant::now();
+		let buckets = Buckets::<IpAddr>::new(RATE);
+		let (a, b) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+		assert!(buckets.take(&a, 100, now));
+		assert!(buckets.take(&b, 10, now));
+		// b has filled back up, a hasn't
+		buckets.sweep(now + Duration::from_secs(1));
+		assert_eq!(buckets.buckets.lock().unwrap().keys().collect::<Vec<_>>(), [&a]);
+		assert!(!buckets.take(&a, 11, now + Duration::from_secs(1)));
+	}
+
+	#[test]
+	fn pairings_share_buckets() {
+		let limits = config::Limits {
+			bytes_per_pairing: RATE,
+			..Default::default()
+		};
+		let limiter = Limiter::new(&limits);
+		let (a, b) = ("wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs", "gckU8soOEqYjH2nUkmW3JMtERbUfNZXErmnvxT5BT1I");
+		let forward = TurnUsername::try_from(format!("{a}.{b}.token").as_str()).unwrap();
+		let back = TurnUsername::try_from(format!("1700000000:{b}.{a}.token").as_str()).unwrap();
+		let other = TurnUsername::try_from(format!("{a}.{b}.other").as_str()).unwrap();
+		let now = Instant::now();
+		assert!(limiter.pairing(&forward, 60, now));
+		assert!(!limiter.pairing(&back, 41, now));
+		assert!(limiter.pairing(&back, 40, now));
+		assert!(limiter.pairing(&other, 100, now));
+	}
+
+	#[test]
+	fn requests() {
+		let limits = config::Limits {
+			requests_per_ip: Rate { rate: 1, burst: 2 },
+			..Default::default()
+		};
+		let limiter = Limiter::new(&limits);
+		let (ip, now) = (IpAddr::from([192, 0, 2, 1]), Instant::now());
+		let mut request = [0u8; 20];
+		request[1] = 0x01;
+		let mut indication = request;
+		indication[1] = 0x16;
+		assert!(limiter.request(&request, ip, now));
+		assert!(limiter.request(&request, ip, now));
+		assert!(!limiter.request(&request, ip, now));
+		// Only requests are counted
+		assert!(limiter.request(&indication, ip, now));
+		assert!(limiter.request(&[0x40, 0, 0, 0], ip, now));
+	}
+
+	#[test]
+	fn quotas() {
+		let limits = config::Limits {
+			allocations_per_ip: 2,
+			allocations_per_username: 1,
+			..Default::default()
+		};
+		let limiter = Limiter::new(&limits);
+		let (a, b) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+		assert!(limiter.allocate(a, "1700000000:alice", None));
+		// REST usernames count against their user, whatever their expiry
+		assert!(!limiter.allocate(b, "1700000001:alice", None));
+		assert!(limiter.allocate(a, "bob", None));
+		assert!(!limiter.allocate(a, "carol", None));
+		// The allocation that's being replaced is as good as released, but only for its own user
+		assert!(limiter.allocate(a, "carol", Some("bob")));
+		limiter.release(a, "bob");
+		assert!(!limiter.allocate(a, "1700000001:alice", Some("carol")));
+		limiter.release(a, "carol");
+		limiter.release(a, "1700000000:alice");
+		assert!(limiter.allocate(b, "alice", None));
+	}
+}
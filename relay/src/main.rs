@@ -1,217 +1,389 @@
 use std::{
-	collections::{HashMap, HashSet},
+	collections::BTreeMap,
+	io::IsTerminal,
 	net::SocketAddr,
-	ops::Add,
-	time::{Duration, Instant}
+	sync::Arc,
+	time::{Duration, Instant},
 };
 
-use eyre::Result;
+use eyre::{bail, Result, WrapErr};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+	net::UdpSocket,
+	sync::{mpsc, watch},
+	task::JoinSet,
+};
+use tracing::{info, warn, Instrument};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, Layer};
 
+mod admin;
+use admin::{Admin, Control, LogHandle};
+mod auth;
+use auth::Credentials;
+mod base64;
+mod batch;
+use batch::{RecvBatch, SendBatch, Via};
+mod config;
+use config::{Command, LogFormat};
+mod expiry;
+mod hosted;
+mod http;
+mod ice;
+use ice::{DtlsHandler, IceLite};
+mod keys;
+mod limits;
+mod metrics;
+mod nonce;
+mod peers;
+mod relayed;
+mod server;
+use server::{Server, Shared};
+mod signal;
+use signal::Signaling;
+mod stream;
+use stream::{Certificates, StreamEvent};
 mod turn;
-use stun::attr::Integrity;
-use turn::{TurnReq, TurnRes, TurnUsername};
-
-use crate::webrtc::WebRTC;
 mod webrtc;
 
-fn turn_auth(username: &str, realm: Option<&str>) -> Option<[u8; 16]> {
-	let realm = realm?;
-	let mut hasher = md5::Context::new();
-	hasher.consume(username);
-	hasher.consume(":");
-	hasher.consume(realm);
-	hasher.consume(":");
-	hasher.consume("the/turn/password/constant");
-
-	Some(hasher.compute().into())
+// What's served on one HTTP address
+#[derive(Default)]
+struct Endpoints {
+	ice_servers: Option<(Credentials, config::Rest)>,
+	signaling: Option<Arc<Signaling>>,
+	admin: Option<Arc<Admin>>,
 }
-const ICE_PWD: &[u8] = b"the/ice/password/constant";
-
-#[allow(unused)]
-pub struct Assoc {
-	username: TurnUsername,
-	expires: Instant,
-	ice_username: Option<String>
+async fn route(req: http::Request, endpoints: Arc<Endpoints>) -> http::Response {
+	match (&endpoints.ice_servers, &endpoints.signaling) {
+		(Some((credentials, rest)), _) if req.path == "/ice-servers" => ice_servers(req, credentials, rest),
+		(_, Some(signaling)) if req.path.starts_with("/signal/") => signaling.handle(req).await,
+		_ => match &endpoints.admin {
+			Some(admin) if Admin::serves(&req.path) => admin.handle(req).await,
+			_ => http::Response::not_found(),
+		},
+	}
 }
 
-const TURN_LIFETIME_SEC: u32 = 60;
-
-#[tokio::main]
-async fn main() -> Result<()> {
-	let sock = std::net::UdpSocket::bind("[::]:3478")?;
-	let mut recv_buff = [0u8; 4096];
-	let mut send_buff = [0u8; 4096];
+// GET /ice-servers?user=<user>: issue TURN REST API credentials in the shape of RTCConfiguration.iceServers
+fn ice_servers(req: http::Request, credentials: &Credentials, rest: &config::Rest) -> http::Response {
+	if req.method != "GET" {
+		return http::Response::text(405, "method not allowed\n");
+	}
+	let key = req
+		.query("key")
+		.or_else(|| req.header("authorization")?.strip_prefix("Bearer "));
+	if rest.api_key.as_deref().is_some_and(|api_key| !key.is_some_and(|key| auth::same(key, api_key))) {
+		return http::Response::text(401, "unauthorized\n");
+	}
+	// The user part is usually the relay's dst.src.token username, but anything without a ':' works:
+	let user = req.query("user").unwrap_or_default();
+	if user.is_empty() || user.contains(':') {
+		return http::Response::text(400, "user is missing or invalid\n");
+	}
+	let Some((username, credential)) = credentials.issue(user, rest.credential_ttl) else {
+		return http::Response::text(503, "no rest secret configured\n");
+	};
+	http::Response::json(&serde_json::json!({
+		"iceServers": [{
+			"urls": rest.urls,
+			"username": username,
+			"credential": credential,
+		}],
+		"ttl": rest.credential_ttl,
+	}))
+}
 
-	let mut assocs: HashMap<SocketAddr, Assoc> = HashMap::new();
-	let hosted: HashSet<String> = HashSet::new();
+// SO_REUSEPORT lets each worker have its own socket on the same address, and the kernel spreads clients across them.
+fn bind(addr: SocketAddr, reuse_port: bool) -> Result<UdpSocket> {
+	let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+	if addr.is_ipv6() {
+		sock.set_only_v6(false)?;
+	}
+	#[cfg(unix)]
+	sock.set_reuse_port(reuse_port)?;
+	sock.set_nonblocking(true)?;
+	sock.bind(&addr.into())?;
+	Ok(UdpSocket::from_std(sock.into())?)
+}
 
+// Receive and handle packets from socks[index] (and the TCP / TLS connections assigned to us) until shutdown.  The
+// batch that's being handled when shutdown arrives is finished and flushed.  Errors are logged rather than stopping
+// the shard.
+async fn shard(
+	index: usize,
+	socks: Arc<[UdpSocket]>,
+	shared: Arc<Shared>,
+	mut stream_rx: mpsc::Receiver<StreamEvent>,
+	mut control_rx: mpsc::Receiver<Control>,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	let (peer_tx, mut peer_rx) = mpsc::channel(1024);
+	let mut server = Server::new(shared, peer_tx);
+	let via = Via::Udp(index);
+	let mut recv_batch = RecvBatch::new();
+	let mut send_batch = SendBatch::new();
+	let expiry = tokio::time::sleep(Duration::ZERO);
+	tokio::pin!(expiry);
 	loop {
-		let (len, addr) = sock.recv_from(&mut recv_buff)?;
-		let Some(msg) = TurnReq::decode(&recv_buff[..len], turn_auth) else { continue; };
-		let assoc = assocs.get_mut(&addr);
-
-		let len = match (msg, assoc) {
-			(TurnReq::Binding { txid }, _) => TurnRes::BindingRes {
-				txid,
-				xmapped: addr,
-			}
-			.encode(&mut send_buff),
-			(TurnReq::AllocateNoAuth { txid }, _) => TurnRes::AllocateUseAuth {
-				txid,
-				realm: "realm",
-				nonce: "nonce",
-			}
-			.encode(&mut send_buff),
-			(
-				TurnReq::Allocate {
-					txid,
-					username,
-					key_data,
-					..
-				},
-				Some(assoc),
-			) if assoc.username.as_ref() != username && assoc.expires < Instant::now() => {
-				TurnRes::AllocateMismatch { txid, key_data }.encode(&mut send_buff)
+		// Nothing to expire still needs a deadline: check back in a minute.
+		let next = server
+			.next_deadline()
+			.unwrap_or_else(|| Instant::now() + Duration::from_secs(60));
+		if expiry.deadline() != next.into() {
+			expiry.as_mut().reset(next.into());
+		}
+		tokio::select! {
+			_ = shutdown.changed() => break,
+			_ = &mut expiry => {
+				server.expire(Instant::now());
+				continue;
 			}
-			(
-				TurnReq::Allocate {
-					txid,
-					username,
-					key_data,
-					..
-				},
-				_,
-			) => {
-				let username = username.try_into()?;
-				let lifetime = TURN_LIFETIME_SEC;
-				let expires = Instant::now()
-					.add(Duration::from_secs(lifetime as u64))
-					.into();
-				println!("{addr} {username:?}");
-				assocs.insert(
-					addr,
-					Assoc {
-						username,
-						expires,
-						ice_username: None
-					},
-				);
-				TurnRes::AllocateSuc {
-					txid,
-					key_data,
-					xmapped: addr,
-					xrelayed: addr,
-					lifetime,
+			Some(packet) = peer_rx.recv() => {
+				// Standard mode: handle whatever's queued up from peers (up to a batch's worth)
+				let mut packet = Some(packet);
+				for _ in 0..batch::BATCH {
+					let Some(p) = packet.take().or_else(|| peer_rx.try_recv().ok()) else { break };
+					if let Err(e) = server.handle_peer(p, &mut send_batch, &socks).await {
+						warn!(error = %e, "peer packet failed");
+					}
+				}
+				if let Err(e) = send_batch.flush(&socks).await {
+					warn!(error = %e, "send failed");
 				}
-				.encode(&mut send_buff)
-			}
-			(
-				TurnReq::Refresh {
-					username,
-					lifetime: 0,
-					..
-				},
-				Some(assoc),
-			) if username == assoc.username.as_ref() => {
-				assocs.remove(&addr);
 				continue;
 			}
-			(
-				TurnReq::Refresh {
-					txid,
-					username,
-					key_data,
-					lifetime,
-				},
-				Some(assoc),
-			) if username == assoc.username.as_ref() => {
-				if hosted.contains(assoc.username.dst()) || hosted.contains(assoc.username.src()) {
-					let lifetime = lifetime.min(TURN_LIFETIME_SEC);
-					assoc.expires = Instant::now().add(Duration::from_secs(lifetime as u64));
-					TurnRes::RefreshSuc {
-						txid,
-						key_data,
-						lifetime,
+			Some(control) = control_rx.recv() => {
+				// The admin API: the other end may have given up waiting, which is fine
+				match control {
+					Control::Allocations(reply) => {
+						let _ = reply.send(server.allocations());
 					}
-				} else {
-					// Kick anything that's not in the hosted
-					TurnRes::RefreshKick { txid, key_data }
-				}.encode(&mut send_buff)
-			}
-			(TurnReq::Permission { txid, key_data, .. }, Some(_)) => {
-				TurnRes::PermissionSuc { txid, key_data }.encode(&mut send_buff)
-			}
-			(TurnReq::BindChannel { txid, key_data, .. }, Some(_)) => {
-				TurnRes::BindChannelSuc { txid, key_data }.encode(&mut send_buff)
-			}
-			(TurnReq::Channel { data, .. }, Some(assoc))
-			| (TurnReq::Send { data, .. }, Some(assoc)) => {
-				let Some(mut webrtc) = WebRTC::decode(data) else { continue; };
-				
-				if let WebRTC::IceReq { username, .. } = webrtc {
-					if let Some((ice_pwd, ice_ufrag)) = username.split_once(":") {
-						assoc
-						.ice_username
-						.get_or_insert_with(|| {
-							let ret = format!("{ice_ufrag}:{ice_pwd}");
-							ret
-						});
+					Control::Kick(kick, reply) => {
+						let _ = reply.send(server.kick(&kick));
 					}
 				}
-				
-				// Trade our mutable reference to assoc for an immutable reference to it:
-				let assoc = assocs.get(&addr).unwrap();
-				
-				// TODO: Randomize our traversal of assocs
-				for (paddr, peer_assoc) in assocs.iter() {
-					if assoc.username.dst() != peer_assoc.username.src() { continue; }
-					if assoc.username.src() != peer_assoc.username.dst() { continue; }
-					if assoc.username.token() != peer_assoc.username.token() { continue; }
-					if peer_assoc.expires < Instant::now() { continue; }
-					let Some(ice_username) = peer_assoc.ice_username.as_ref() else { continue; };
-					let (_, ice_pwd) = ice_username.split_once(":").unwrap();
-
-					// Fixup the credentials
-					match webrtc {
-						WebRTC::IceReq {
-							ref mut integrity,
-							ref mut username,
-							ref mut priority,
-							..
-						} if integrity.verify(ICE_PWD) => {
-							*priority = 1;
-							*integrity = Integrity::Set {
-								key_data: ice_pwd.as_bytes(),
-							};
-							*username = ice_username;
-						}
-						WebRTC::IceRes {
-							ref mut integrity, ..
-						}
-						| WebRTC::IceErr {
-							ref mut integrity, ..
-						} => {
-							*integrity = Integrity::Set { key_data: ICE_PWD };
+				continue;
+			}
+			Some(event) = stream_rx.recv() => {
+				let mut event = Some(event);
+				for _ in 0..batch::BATCH {
+					let Some(e) = event.take().or_else(|| stream_rx.try_recv().ok()) else { break };
+					match e {
+						StreamEvent::Packet { addr, via, data } => {
+							if let Err(e) = server.handle(&data, addr, &via, &mut send_batch, &socks).await {
+								warn!(client = %addr, error = %e, "packet failed");
+							}
 						}
-						WebRTC::Rtp(_) => continue, // Don't forward RTP
-						_ => {},
-					};
-					let txid = b"txidtxidtxid"; // TODO: Random?
-					if let Some(len) = (TurnRes::Data {
-						txid: txid.clone(),
-						xpeer: addr,
-						data: webrtc.encode()
-					}
-					.encode(&mut send_buff)) {
-						sock.send_to(&send_buff[..len], paddr)?;
+						StreamEvent::Closed { addr } => server.closed(addr),
 					}
 				}
+				if let Err(e) = send_batch.flush(&socks).await {
+					warn!(error = %e, "send failed");
+				}
+				continue;
+			}
+			ret = recv_batch.recv(&socks[index]) => if let Err(e) = ret {
+				warn!(error = %e, "recv failed");
 				continue;
 			}
-			_ => continue,
+		}
+		for (packet, addr) in recv_batch.iter() {
+			if let Err(e) = server.handle(packet, addr, &via, &mut send_batch, &socks).await {
+				warn!(client = %addr, error = %e, "packet failed");
+			}
+		}
+		if let Err(e) = send_batch.flush(&socks).await {
+			warn!(error = %e, "send failed");
+		}
+	}
+}
+
+async fn shutdown_signal() -> Result<()> {
+	#[cfg(unix)]
+	{
+		let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+		tokio::select! {
+			ret = tokio::signal::ctrl_c() => ret?,
+			_ = term.recv() => {},
+		}
+	}
+	#[cfg(not(unix))]
+	tokio::signal::ctrl_c().await?;
+	Ok(())
+}
+
+fn main() -> Result<()> {
+	let config = match config::from_args(std::env::args().skip(1))? {
+		Command::Run(config) => config,
+		Command::Check(config) => {
+			println!(
+				"config ok: listening on {:?} with realm {:?} and {} workers",
+				config.listen,
+				config.realm,
+				config.workers()
+			);
+			return Ok(());
+		}
+		Command::Help => {
+			print!("{}", config::usage());
+			return Ok(());
+		}
+	};
+	// The filter can be swapped out at runtime through the admin API
+	let (filter, log) = reload::Layer::new(config.log_filter()?);
+	let logs = tracing_subscriber::fmt::layer().with_ansi(std::io::stdout().is_terminal());
+	let logs = match config.log_format {
+		LogFormat::Text => logs.boxed(),
+		LogFormat::Json => logs.json().with_span_list(true).boxed(),
+	};
+	tracing_subscriber::registry().with(filter).with(logs).init();
+	tokio::runtime::Builder::new_multi_thread()
+		.worker_threads(config.workers())
+		.enable_all()
+		.build()?
+		.block_on(run(config, log))
+}
+
+async fn run(config: config::Config, log: LogHandle) -> Result<()> {
+	// Other platforms don't load balance SO_REUSEPORT sockets, so there's just one socket per address there.
+	let shards = if cfg!(target_os = "linux") { config.workers() } else { 1 };
+	let mut socks = Vec::new();
+	for addr in &config.listen {
+		for _ in 0..shards {
+			socks.push(bind(*addr, shards > 1).wrap_err_with(|| format!("unable to bind {addr}"))?);
+		}
+	}
+	let socks: Arc<[UdpSocket]> = socks.into();
+
+	// The relay doesn't terminate DTLS itself: it hands it to ice_lite.dtls_backend if there is one.
+	let dtls: Arc<dyn DtlsHandler> = match config.ice_lite.dtls_backend {
+		Some(backend) => Arc::new(ice::Forward::new(backend)),
+		None => Arc::new(ice::Discard),
+	};
+	let ice = IceLite::new(&config.ice_lite, dtls, socks.clone());
+	let shared = Arc::new(Shared::new(config, ice)?);
+	let config = &shared.config;
+	let limits = shared.clone();
+	tokio::spawn(async move { limits.limits.sweep().await });
+	let (stream_txs, stream_rxs): (Vec<_>, Vec<_>) = (0..socks.len()).map(|_| mpsc::channel(1024)).unzip();
+	let stream_txs: Arc<[mpsc::Sender<StreamEvent>]> = stream_txs.into();
+	let (control_txs, control_rxs): (Vec<_>, Vec<_>) = (0..socks.len()).map(|_| mpsc::channel(16)).unzip();
+
+	// The HTTP endpoints can share an address
+	let mut http_listen: BTreeMap<SocketAddr, Endpoints> = BTreeMap::new();
+	if let Some(addr) = config.rest.http_listen {
+		let ice_servers = (Credentials::new(&config.secrets), config.rest.clone());
+		http_listen.entry(addr).or_default().ice_servers = Some(ice_servers);
+	}
+	if let Some(addr) = config.signaling.http_listen {
+		let credentials = Credentials::new(&config.secrets);
+		let signaling = Arc::new(Signaling::new(config.signaling.clone(), credentials, config.limits.requests_per_ip));
+		tokio::spawn(signaling.clone().sweep());
+		http_listen.entry(addr).or_default().signaling = Some(signaling);
+	}
+	if let Some(addr) = config.admin.http_listen {
+		let admin = Admin::new(shared.clone(), control_txs.into(), log);
+		http_listen.entry(addr).or_default().admin = Some(Arc::new(admin));
+	}
+	for (addr, endpoints) in http_listen {
+		let listener = tokio::net::TcpListener::bind(addr)
+			.await
+			.wrap_err_with(|| format!("unable to bind {addr}"))?;
+		let mut max_body = 0;
+		if endpoints.signaling.is_some() {
+			max_body = config.signaling.max_message;
+		}
+		if endpoints.admin.is_some() {
+			max_body = max_body.max(admin::MAX_BODY);
+		}
+		let endpoints = Arc::new(endpoints);
+		tokio::spawn(http::serve(listener, max_body, move |req| route(req, endpoints.clone())));
+	}
+
+	let certificates = if config.tls_listen.is_empty() {
+		None
+	} else {
+		let certificates = Arc::new(Certificates::load(&config.tls)?);
+		tokio::spawn(certificates.clone().watch());
+		Some(certificates)
+	};
+	let idle = Duration::from_secs(config.lifetimes.idle);
+	let tcp_listen = config.tcp_listen.iter().map(|addr| (addr, None));
+	let tls_listen = config.tls_listen.iter().map(|addr| (addr, certificates.clone()));
+	for (addr, certificates) in tcp_listen.chain(tls_listen) {
+		let listener = tokio::net::TcpListener::bind(addr)
+			.await
+			.wrap_err_with(|| format!("unable to bind {addr}"))?;
+		tokio::spawn(stream::listen(listener, certificates, stream_txs.clone(), idle));
+	}
+
+	let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+	// Shards are tasks on a runtime with a worker thread per shard rather than threads pinned to cores: tokio keeps a
+	// busy shard on the worker that it's running on, and the other workers can still pick up the TCP / TLS / HTTP
+	// tasks, or a shard whose worker is held up by them, where pinned threads would leave those waiting.
+	let mut tasks = JoinSet::new();
+	for (index, (stream_rx, control_rx)) in stream_rxs.into_iter().zip(control_rxs).enumerate() {
+		let span = tracing::info_span!("shard", index);
+		let shard = shard(
+			index,
+			socks.clone(),
+			shared.clone(),
+			stream_rx,
+			control_rx,
+			shutdown_rx.clone(),
+		);
+		tasks.spawn(shard.instrument(span));
+	}
+
+	// Shards only stop early if they panic: exit rather than keep running without one.
+	tokio::select! {
+		r = shutdown_signal() => r?,
+		Some(r) = tasks.join_next() => {
+			r?;
+			bail!("a shard stopped");
+		}
+	}
+	info!("shutting down");
+	let _ = shutdown_tx.send(true);
+	while let Some(r) = tasks.join_next().await {
+		r?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ice_servers_key() {
+		let credentials = Credentials::Rest(vec!["secret".into()]);
+		let rest = config::Rest {
+			api_key: Some("key".into()),
+			..Default::default()
+		};
+		let status = |target: &str, authorization: Option<&str>| {
+			let (path, query) = target.split_once('?').unwrap();
+			let req = http::Request {
+				method: "GET".into(),
+				path: path.into(),
+				query: query.split('&').filter_map(|p| p.split_once('=')).map(|(n, v)| (n.into(), v.into())).collect(),
+				headers: authorization.map(|a| ("Authorization".into(), a.into())).into_iter().collect(),
+				body: Vec::new(),
+				peer: ([127, 0, 0, 1], 5000).into(),
+			};
+			ice_servers(req, &credentials, &rest).status
 		};
-		if let Some(len) = len {
-			sock.send_to(&send_buff[..len], addr)?;
+		assert_eq!(status("/ice-servers?user=a&key=key", None), 200);
+		assert_eq!(status("/ice-servers?user=a", Some("Bearer key")), 200);
+		for (target, authorization) in [
+			("/ice-servers?user=a", None),
+			("/ice-servers?user=a&key=ke", None),
+			("/ice-servers?user=a&key=keys", None),
+			("/ice-servers?user=a", Some("Bearer kez")),
+			("/ice-servers?user=a", Some("key")),
+		] {
+			assert_eq!(status(target, authorization), 401, "{target} {authorization:?}");
 		}
 	}
 }
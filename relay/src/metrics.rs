@@ -0,0 +1,242 @@
+use std::{
+	fmt::Write,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+	turn::{TurnReq, TurnRes},
+	webrtc::WebRTC,
+};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+impl Counter {
+	pub fn inc(&self) {
+		self.add(1);
+	}
+	pub fn add(&self, n: u64) {
+		self.0.fetch_add(n, Ordering::Relaxed);
+	}
+	pub fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+// What one allocation has relayed, for the admin API.  The other side of a pairing adds to received, so these are
+// shared with the allocation's entry in Peers.
+#[derive(Default)]
+pub struct Traffic {
+	pub sent_packets: Counter,
+	pub sent_bytes: Counter,
+	pub received_packets: Counter,
+	pub received_bytes: Counter,
+}
+impl Traffic {
+	pub fn sent(&self, len: usize) {
+		self.sent_packets.inc();
+		self.sent_bytes.add(len as u64);
+	}
+	pub fn received(&self, len: usize) {
+		self.received_packets.inc();
+		self.received_bytes.add(len as u64);
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Method {
+	Binding,
+	Allocate,
+	Refresh,
+	Permission,
+	ChannelBind,
+	Send,
+	ChannelData,
+	// ICE-lite: connectivity checks for the relay itself
+	IceCheck,
+}
+impl Method {
+	const ALL: [Self; 8] = [
+		Self::Binding,
+		Self::Allocate,
+		Self::Refresh,
+		Self::Permission,
+		Self::ChannelBind,
+		Self::Send,
+		Self::ChannelData,
+		Self::IceCheck,
+	];
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Binding => "binding",
+			Self::Allocate => "allocate",
+			Self::Refresh => "refresh",
+			Self::Permission => "permission",
+			Self::ChannelBind => "channel_bind",
+			Self::Send => "send",
+			Self::ChannelData => "channel_data",
+			Self::IceCheck => "ice_check",
+		}
+	}
+}
+
+impl From<&TurnReq<'_>> for Method {
+	fn from(req: &TurnReq<'_>) -> Self {
+		match req {
+			TurnReq::Binding { .. } => Self::Binding,
+			TurnReq::AllocateNoAuth { .. } | TurnReq::Allocate { .. } => Self::Allocate,
+			TurnReq::Refresh { .. } => Self::Refresh,
+			TurnReq::Permission { .. } => Self::Permission,
+			TurnReq::BindChannel { .. } => Self::ChannelBind,
+			TurnReq::Send { .. } => Self::Send,
+			TurnReq::Channel { .. } => Self::ChannelData,
+			TurnReq::StaleNonce { method, .. } => match method {
+				0x004 => Self::Refresh,
+				0x008 => Self::Permission,
+				0x009 => Self::ChannelBind,
+				_ => Self::Allocate,
+			},
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+	// A success response, or data that was forwarded
+	Success,
+	// 401 / 438: the client has to retry with (new) credentials
+	Challenge,
+	// Any other error response
+	Error,
+	// No response: data without a permission / channel, or a request for an allocation that doesn't exist
+	Dropped,
+}
+impl Outcome {
+	const ALL: [Self; 4] = [Self::Success, Self::Challenge, Self::Error, Self::Dropped];
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Success => "success",
+			Self::Challenge => "challenge",
+			Self::Error => "error",
+			Self::Dropped => "dropped",
+		}
+	}
+}
+impl From<&TurnRes<'_>> for Outcome {
+	fn from(res: &TurnRes<'_>) -> Self {
+		match res {
+			TurnRes::Channel { .. }
+			| TurnRes::Data { .. }
+			| TurnRes::BindingRes { .. }
+			| TurnRes::AllocateSuc { .. }
+			| TurnRes::PermissionSuc { .. }
+			| TurnRes::RefreshSuc { .. }
+			| TurnRes::BindChannelSuc { .. } => Self::Success,
+			TurnRes::AllocateUseAuth { .. } | TurnRes::StaleNonce { .. } => Self::Challenge,
+			TurnRes::AllocateMismatch { .. }
+			| TurnRes::AllocateCapacity { .. }
+			| TurnRes::AllocateTransport { .. }
+			| TurnRes::AllocateQuota { .. }
+			| TurnRes::RefreshKick { .. }
+			| TurnRes::BadRequest { .. } => Self::Error,
+		}
+	}
+}
+
+// What forwarded packets carried.  RTP isn't forwarded in rendezvous mode, so it's only ever counted as dropped.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+	Ice,
+	Dtls,
+	RtpDropped,
+	// Standard mode: whatever clients and their peers send each other
+	Relayed,
+}
+impl Kind {
+	const ALL: [Self; 4] = [Self::Ice, Self::Dtls, Self::RtpDropped, Self::Relayed];
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Ice => "ice",
+			Self::Dtls => "dtls",
+			Self::RtpDropped => "rtp_dropped",
+			Self::Relayed => "relayed",
+		}
+	}
+}
+impl From<&WebRTC<'_>> for Kind {
+	fn from(webrtc: &WebRTC<'_>) -> Self {
+		match webrtc {
+			WebRTC::IceReq { .. } | WebRTC::IceRes { .. } | WebRTC::IceErr { .. } => Self::Ice,
+			WebRTC::Dtls(_) => Self::Dtls,
+			WebRTC::Rtp(_) => Self::RtpDropped,
+		}
+	}
+}
+
+// Counters shared by every shard, served in the Prometheus text format.  Gauges (allocations, pairings) aren't
+// kept here: they're read from the live state when scraped.
+#[derive(Default)]
+pub struct Metrics {
+	requests: [[Counter; Outcome::ALL.len()]; Method::ALL.len()],
+	forwarded_packets: [Counter; Kind::ALL.len()],
+	forwarded_bytes: [Counter; Kind::ALL.len()],
+	pub auth_failures: Counter,
+	pub kicks: Counter,
+	pub decode_errors: Counter,
+	pub unsupported: Counter,
+	// Requests and forwarded packets dropped by the rate limits
+	pub requests_limited: Counter,
+	pub bytes_limited: Counter,
+}
+impl Metrics {
+	pub fn request(&self, method: Method, outcome: Outcome) {
+		self.requests[method as usize][outcome as usize].inc();
+	}
+	pub fn forwarded(&self, kind: Kind, bytes: usize) {
+		self.forwarded_packets[kind as usize].inc();
+		self.forwarded_bytes[kind as usize].add(bytes as u64);
+	}
+	pub fn render(&self, allocations: usize, pairings: usize) -> String {
+		fn header(out: &mut String, name: &str, typ: &str, help: &str) {
+			let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {typ}");
+		}
+		let mut out = String::new();
+
+		header(&mut out, "relay_requests_total", "counter", "STUN / TURN requests and data by method and outcome.");
+		for method in Method::ALL {
+			for outcome in Outcome::ALL {
+				let _ = writeln!(
+					out,
+					"relay_requests_total{{method=\"{}\",outcome=\"{}\"}} {}",
+					method.name(),
+					outcome.name(),
+					self.requests[method as usize][outcome as usize].get()
+				);
+			}
+		}
+		header(&mut out, "relay_allocations", "gauge", "Active allocations.");
+		let _ = writeln!(out, "relay_allocations {allocations}");
+		header(&mut out, "relay_pairings", "gauge", "Pairings with published allocations on both sides.");
+		let _ = writeln!(out, "relay_pairings {pairings}");
+		for (name, counters, help) in [
+			("relay_forwarded_packets_total", &self.forwarded_packets, "Forwarded packets by kind."),
+			("relay_forwarded_bytes_total", &self.forwarded_bytes, "Forwarded bytes by kind."),
+		] {
+			header(&mut out, name, "counter", help);
+			for kind in Kind::ALL {
+				let _ = writeln!(out, "{name}{{kind=\"{}\"}} {}", kind.name(), counters[kind as usize].get());
+			}
+		}
+		for (name, counter, help) in [
+			("relay_auth_failures_total", &self.auth_failures, "Requests whose credentials didn't verify."),
+			("relay_kicks_total", &self.kicks, "Refreshes refused because neither side of the pairing is hosted."),
+			("relay_decode_errors_total", &self.decode_errors, "Packets that weren't valid STUN or ChannelData."),
+			("relay_unsupported_total", &self.unsupported, "Well-formed packets that the relay doesn't handle."),
+			("relay_requests_limited_total", &self.requests_limited, "Requests dropped by the per-IP rate limit."),
+			("relay_bytes_limited_total", &self.bytes_limited, "Packets dropped by the bandwidth limits."),
+		] {
+			header(&mut out, name, "counter", help);
+			let _ = writeln!(out, "{name} {}", counter.get());
+		}
+		out
+	}
+}
@@ -0,0 +1,164 @@
+use std::{
+	net::{IpAddr, SocketAddr},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+
+// RFC 8489 section 9.2: A nonce that starts with this cookie is followed by 4 base64 characters of security feature
+// bits.  We don't support password algorithms or username anonymity, so the bits are all zero.
+const COOKIE: &str = "obMatJos2AAAA";
+
+// Length of the truncated HMAC tag that we put into nonces:
+const TAG_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceCheck {
+	Valid,
+	Stale,
+	Invalid,
+}
+
+// Nonces are stateless: `obMatJos2<features><expiry>.<tag>` where expiry is in hex unix seconds and tag is a
+// truncated HMAC over everything before it plus the client's address.  A nonce is only valid from the address
+// that it was issued to, and becomes stale once the expiry passes.
+pub struct Nonces {
+	key: [u8; 20],
+	lifetime: Duration,
+}
+impl Nonces {
+	pub fn new(lifetime: Duration) -> Self {
+		let mut key = [0u8; 20];
+		rand::thread_rng().fill_bytes(&mut key);
+		Self { key, lifetime }
+	}
+	fn tag(&self, body: &str, addr: SocketAddr) -> Hmac<Sha1> {
+		let mut hmac = Hmac::<Sha1>::new_from_slice(&self.key).expect("bad nonce key");
+		hmac.update(body.as_bytes());
+		match addr.ip() {
+			IpAddr::V4(ip) => hmac.update(&ip.octets()),
+			IpAddr::V6(ip) => hmac.update(&ip.octets()),
+		}
+		hmac.update(&addr.port().to_be_bytes());
+		hmac
+	}
+	pub fn issue(&self, addr: SocketAddr) -> String {
+		let expiry = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs()
+			.saturating_add(self.lifetime.as_secs());
+		let mut ret = format!("{COOKIE}{expiry:x}.");
+		let tag = self.tag(&ret, addr).finalize().into_bytes();
+		for b in &tag[..TAG_LEN] {
+			ret.push_str(&format!("{b:02x}"));
+		}
+		ret
+	}
+	pub fn check(&self, nonce: &str, addr: SocketAddr) -> NonceCheck {
+		let Some((body, tag)) = nonce.rsplit_once('.') else { return NonceCheck::Invalid };
+		let body_len = body.len() + 1;
+		let Some(expiry) = body.strip_prefix(COOKIE) else { return NonceCheck::Invalid };
+		let Ok(expiry) = u64::from_str_radix(expiry, 16) else { return NonceCheck::Invalid };
+		let Some(tag) = decode_hex::<TAG_LEN>(tag) else { return NonceCheck::Invalid };
+
+		if self.tag(&nonce[..body_len], addr).verify_truncated_left(&tag).is_err() {
+			return NonceCheck::Invalid;
+		}
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		if expiry < now {
+			NonceCheck::Stale
+		} else {
+			NonceCheck::Valid
+		}
+	}
+}
+
+fn decode_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+	if s.len() != N * 2 {
+		return None;
+	}
+	let mut ret = [0u8; N];
+	for (i, b) in ret.iter_mut().enumerate() {
+		*b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+	}
+	Some(ret)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 3478);
+
+	// A correctly tagged nonce with any body after the cookie
+	fn sign(nonces: &Nonces, rest: &str) -> String {
+		let body = format!("{COOKIE}{rest}.");
+		let tag = nonces.tag(&body, ADDR).finalize().into_bytes();
+		tag[..TAG_LEN].iter().fold(body, |s, b| s + &format!("{b:02x}"))
+	}
+
+	#[test]
+	fn valid() {
+		let nonces = Nonces::new(Duration::from_secs(60));
+		assert_eq!(nonces.check(&nonces.issue(ADDR), ADDR), NonceCheck::Valid);
+	}
+
+	#[test]
+	fn long_lifetime() {
+		let nonces = Nonces::new(Duration::MAX);
+		assert_eq!(nonces.check(&nonces.issue(ADDR), ADDR), NonceCheck::Valid);
+	}
+
+	#[test]
+	fn stale() {
+		let nonces = Nonces::new(Duration::from_secs(60));
+		assert_eq!(nonces.check(&sign(&nonces, "1"), ADDR), NonceCheck::Stale);
+	}
+
+	#[test]
+	fn other_address_or_key() {
+		let nonces = Nonces::new(Duration::from_secs(60));
+		let nonce = nonces.issue(ADDR);
+		let other = SocketAddr::new(ADDR.ip(), 3479);
+		assert_eq!(nonces.check(&nonce, other), NonceCheck::Invalid);
+		assert_eq!(Nonces::new(Duration::from_secs(60)).check(&nonce, ADDR), NonceCheck::Invalid);
+	}
+
+	#[test]
+	fn malformed() {
+		let nonces = Nonces::new(Duration::from_secs(60));
+		let nonce = nonces.issue(ADDR);
+		let (body, tag) = nonce.rsplit_once('.').unwrap();
+		for bad in [
+			"",
+			".",
+			COOKIE,
+			"obMatJos2",
+			&nonce[1..],
+			body,
+			&format!("{body}.{}", &tag[1..]),
+			&format!("{body}.{tag}0"),
+			&format!("{body}.{}é", &tag[..tag.len() - 2]),
+			// Security feature bits that we don't use
+			&nonce.replacen(COOKIE, "obMatJos2gAAA", 1),
+		] {
+			assert_eq!(nonces.check(bad, ADDR), NonceCheck::Invalid, "{bad:?}");
+		}
+	}
+
+	#[test]
+	fn non_ascii() {
+		// Multibyte characters where the feature bits and expiry would be (a slice through one would panic)
+		let nonces = Nonces::new(Duration::from_secs(60));
+		for nonce in ["obMatJos2AAé.00", "obMatJos2AAAé.00", "obMatJos2é", "obMatJos2AAAAfé.", "é.é"] {
+			assert_eq!(nonces.check(nonce, ADDR), NonceCheck::Invalid, "{nonce:?}");
+		}
+		assert_eq!(nonces.check(&sign(&nonces, "fé"), ADDR), NonceCheck::Invalid);
+	}
+}
@@ -0,0 +1,144 @@
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, RwLock,
+	},
+	time::Instant,
+};
+
+use stun::attr::IntegrityKey;
+
+use crate::{batch::Via, metrics::Traffic, turn::TurnUsername};
+
+// An allocation that packets can be forwarded to.
+#[derive(Clone)]
+pub struct Peer {
+	pub addr: SocketAddr,
+	// How to reach the allocation's client
+	pub via: Via,
+	pub expires: Instant,
+	pub ice_username: Box<str>,
+	pub ice_key: IntegrityKey,
+	// The allocation's traffic counters, which forwarding to it adds to
+	pub traffic: Arc<Traffic>,
+}
+
+// One side of a pairing, as listed by the admin API
+pub struct Side {
+	pub username: Box<str>,
+	pub clients: Vec<SocketAddr>,
+}
+
+#[derive(Default)]
+struct Directory {
+	// dst.src.token -> allocations
+	pairs: HashMap<Box<str>, Vec<Arc<Peer>>>,
+	// allocation -> (peer -> channel)
+	channels: HashMap<SocketAddr, HashMap<SocketAddr, u16>>,
+}
+
+// Allocations are owned by the shard whose socket their packets arrive on, but the other side of a pairing can be
+// on any shard.  Shards publish allocations here once they can be forwarded to (we've seen their ICE credentials),
+// keyed by their dst.src.token username, along with their channel bindings.  This is also where the total number
+// of allocations is kept.
+pub struct Peers {
+	directory: RwLock<Directory>,
+	allocations: AtomicUsize,
+}
+impl Peers {
+	pub fn new() -> Self {
+		Self {
+			directory: RwLock::new(Directory::default()),
+			allocations: AtomicUsize::new(0),
+		}
+	}
+	pub fn allocations(&self) -> usize {
+		self.allocations.load(Ordering::Relaxed)
+	}
+	pub fn allocated(&self) {
+		self.allocations.fetch_add(1, Ordering::Relaxed);
+	}
+	// Called when an allocation goes away, whether or not it was ever published.  Allocations that don't take part
+	// in pairing (standard mode) don't have a username here.
+	pub fn deallocated(&self, username: Option<&TurnUsername>, addr: SocketAddr) {
+		self.allocations.fetch_sub(1, Ordering::Relaxed);
+		let Some(username) = username else { return };
+		let mut directory = self.directory.write().unwrap();
+		if let Some(peers) = directory.pairs.get_mut(username.name()) {
+			peers.retain(|p| p.addr != addr);
+			if peers.is_empty() {
+				directory.pairs.remove(username.name());
+			}
+		}
+		directory.channels.remove(&addr);
+	}
+	// Pairings that have allocations published on both sides
+	pub fn pairings(&self) -> usize {
+		self.list().len()
+	}
+	// Pairings that have allocations published on both sides, each listed once (peers paired with themselves have
+	// the same username on both sides).
+	pub fn list(&self) -> Vec<(Side, Side)> {
+		let directory = self.directory.read().unwrap();
+		let side = |name: &str| Side {
+			username: name.into(),
+			clients: directory.pairs[name].iter().map(|p| p.addr).collect(),
+		};
+		let mut ret = Vec::new();
+		for name in directory.pairs.keys() {
+			let Some((dst, rest)) = name.split_once('.') else { continue };
+			let Some((src, token)) = rest.split_once('.') else { continue };
+			let reversed = format!("{src}.{dst}.{token}");
+			if dst <= src && directory.pairs.contains_key(reversed.as_str()) {
+				ret.push((side(name), side(&reversed)));
+			}
+		}
+		ret
+	}
+	pub fn bind(&self, addr: SocketAddr, peer: SocketAddr, channel: u16) {
+		let mut directory = self.directory.write().unwrap();
+		directory.channels.entry(addr).or_default().insert(peer, channel);
+	}
+	pub fn unbind(&self, addr: SocketAddr, peer: SocketAddr) {
+		let mut directory = self.directory.write().unwrap();
+		if let Some(channels) = directory.channels.get_mut(&addr) {
+			channels.remove(&peer);
+			if channels.is_empty() {
+				directory.channels.remove(&addr);
+			}
+		}
+	}
+	pub fn publish(&self, username: &TurnUsername, peer: Peer) {
+		let mut directory = self.directory.write().unwrap();
+		let peers = directory.pairs.entry(username.name().into()).or_default();
+		peers.retain(|p| p.addr != peer.addr);
+		peers.push(Arc::new(peer));
+	}
+	pub fn refresh(&self, username: &TurnUsername, addr: SocketAddr, expires: Instant) {
+		let mut directory = self.directory.write().unwrap();
+		let peers = directory.pairs.get_mut(username.name()).into_iter().flatten();
+		for peer in peers.filter(|p| p.addr == addr) {
+			*peer = Arc::new(Peer { expires, ..Peer::clone(peer) });
+		}
+	}
+	// The unexpired allocations on the other side of username's pairing (src.dst.token), along with the channel
+	// that each of them has bound to from (if any).  This is on every forwarded packet: found is the shard's to
+	// reuse, and the lock is only held for the lookup, not while the packets are sent.
+	pub fn find(
+		&self,
+		username: &TurnUsername,
+		from: SocketAddr,
+		now: Instant,
+		found: &mut Vec<(Arc<Peer>, Option<u16>)>,
+	) {
+		found.clear();
+		let directory = self.directory.read().unwrap();
+		let peers = directory.pairs.get(username.reversed()).into_iter().flatten();
+		for peer in peers.filter(|p| p.expires >= now) {
+			let channel = directory.channels.get(&peer.addr).and_then(|c| c.get(&from)).copied();
+			found.push((peer.clone(), channel));
+		}
+	}
+}
@@ -0,0 +1,138 @@
+use std::{
+	io,
+	net::{SocketAddr, UdpSocket as StdUdpSocket},
+	sync::Arc,
+};
+
+use rand::Rng;
+use tokio::{
+	net::UdpSocket,
+	sync::mpsc::{self, error::TrySendError},
+	task::JoinHandle,
+};
+use tracing::warn;
+
+use crate::{batch::BUFF_LEN, config::Relaying};
+
+// How many random ports to try before giving up on an allocation
+const BIND_ATTEMPTS: usize = 64;
+
+// A datagram that a peer sent to an allocation's relayed address.
+pub struct PeerPacket {
+	pub client: SocketAddr,
+	pub peer: SocketAddr,
+	pub data: Vec<u8>,
+}
+
+// Standard TURN relaying: an allocation's own UDP socket.  Whatever arrives on it is handed to the shard that owns
+// the allocation, which checks permissions before wrapping it in a Data indication or ChannelData.  The socket is
+// closed when this is dropped.
+pub struct Relayed {
+	// The relayed transport address that we advertise
+	pub addr: SocketAddr,
+	sock: Arc<UdpSocket>,
+	task: JoinHandle<()>,
+}
+impl Relayed {
+	pub fn bind(config: &Relaying, client: SocketAddr, tx: mpsc::Sender<PeerPacket>) -> io::Result<Self> {
+		let mut rng = rand::thread_rng();
+		let mut ret = Err(io::Error::new(io::ErrorKind::AddrInUse, "no free relay port"));
+		for _ in 0..BIND_ATTEMPTS {
+			let port = rng.gen_range(config.min_port..=config.max_port);
+			ret = StdUdpSocket::bind((config.address, port));
+			if ret.is_ok() {
+				break;
+			}
+		}
+		let sock = ret?;
+		sock.set_nonblocking(true)?;
+		let sock = Arc::new(UdpSocket::from_std(sock)?);
+		let port = sock.local_addr()?.port();
+		let addr = SocketAddr::new(config.external_address.unwrap_or(config.address), port);
+
+		let task = tokio::spawn(Self::recv(sock.clone(), client, tx));
+		Ok(Self { addr, sock, task })
+	}
+	async fn recv(sock: Arc<UdpSocket>, client: SocketAddr, tx: mpsc::Sender<PeerPacket>) {
+		let mut buff = [0u8; BUFF_LEN];
+		loop {
+			let (len, peer) = match sock.recv_from(&mut buff).await {
+				Ok(received) => received,
+				Err(e) if transient(&e) => continue,
+				// Anything else won't go away by trying again: the allocation stops relaying rather than spin.
+				Err(e) => {
+					warn!(%client, error = %e, "relayed socket failed");
+					break;
+				}
+			};
+			let packet = PeerPacket {
+				client,
+				peer,
+				data: buff[..len].to_vec(),
+			};
+			// If the shard is behind, drop the packet like a full socket buffer would.
+			if let Err(TrySendError::Closed(_)) = tx.try_send(packet) {
+				break;
+			}
+		}
+	}
+	// Send to a peer.  Like any UDP send, this can silently drop the datagram.
+	pub fn send_to(&self, data: &[u8], peer: SocketAddr) {
+		let _ = self.sock.try_send_to(data, peer);
+	}
+}
+impl Drop for Relayed {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+// Whether a UDP receive error is about a single datagram rather than the socket: mostly ICMP errors from earlier
+// sends, which don't matter to us.
+pub fn transient(e: &io::Error) -> bool {
+	use io::ErrorKind::*;
+	matches!(
+		e.kind(),
+		ConnectionRefused | ConnectionReset | HostUnreachable | NetworkUnreachable | WouldBlock | Interrupted
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{net::Ipv4Addr, time::Duration};
+
+	use super::*;
+
+	#[test]
+	fn transient_errors() {
+		for kind in [io::ErrorKind::ConnectionRefused, io::ErrorKind::WouldBlock] {
+			assert!(transient(&kind.into()));
+		}
+		for kind in [io::ErrorKind::InvalidInput, io::ErrorKind::PermissionDenied, io::ErrorKind::Other] {
+			assert!(!transient(&kind.into()));
+		}
+	}
+
+	// Sending to a closed port (which some platforms report on the next receive) doesn't stop the relaying
+	#[tokio::test]
+	async fn icmp_errors() {
+		let config = Relaying {
+			address: Ipv4Addr::LOCALHOST.into(),
+			..Default::default()
+		};
+		let client = SocketAddr::from(([192, 0, 2, 1], 5000));
+		let (tx, mut rx) = mpsc::channel(1);
+		let relayed = Relayed::bind(&config, client, tx).unwrap();
+		let closed = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+		let closed_addr = closed.local_addr().unwrap();
+		drop(closed);
+		relayed.send_to(b"nobody", closed_addr);
+
+		let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		peer.send_to(b"hello", relayed.addr).await.unwrap();
+		let packet = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+		assert_eq!((packet.client, packet.peer, &packet.data[..]), (client, peer.local_addr().unwrap(), &b"hello"[..]));
+		assert!(!relayed.task.is_finished());
+	}
+}
@@ -0,0 +1,909 @@
+use std::{
+	collections::HashMap,
+	net::{IpAddr, SocketAddr},
+	ops::Add,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use eyre::Result;
+use stun::attr::{Integrity, IntegrityKey};
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::{debug, field, info, trace, Instrument, Span};
+
+use crate::{
+	auth::Credentials,
+	batch::{SendBatch, Via},
+	config::{Config, Mode},
+	expiry::Deadlines,
+	hosted::Hosted,
+	ice::{Handled, IceLite},
+	keys::KeyCache,
+	limits::{Bucket, Limiter},
+	metrics::{Kind, Method, Metrics, Outcome, Traffic},
+	nonce::Nonces,
+	peers::{Peer, Peers},
+	relayed::{PeerPacket, Relayed},
+	turn::{Rejected, TurnReq, TurnRes, TurnUsername},
+	webrtc::WebRTC,
+};
+
+// RFC 5766 sections 8 and 11: these aren't negotiable.
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const CHANNEL_LIFETIME: Duration = Duration::from_secs(600);
+
+pub struct Assoc {
+	username: Box<str>,
+	// The pairing's (or, in standard mode, the allocation's) span, which its transactions are logged under
+	span: Span,
+	// Where the client's packets arrive from
+	via: Via,
+	// Rendezvous mode: who this allocation pairs with
+	pairing: Option<TurnUsername>,
+	// Standard mode: the allocation's own socket
+	relayed: Option<Relayed>,
+	expires: Instant,
+	ice_username: Option<String>,
+	ice_key: Option<IntegrityKey>,
+	// peer IP -> expiry
+	permissions: HashMap<IpAddr, Instant>,
+	// channel -> (peer, expiry)
+	channels: HashMap<u16, (SocketAddr, Instant)>,
+	// Bytes relayed, for limits.bytes_per_allocation
+	bandwidth: Bucket,
+	traffic: Arc<Traffic>,
+}
+
+// An allocation, as listed by the admin API
+pub struct Allocation {
+	pub client: SocketAddr,
+	pub stream: bool,
+	pub username: Box<str>,
+	pub pairing: Option<TurnUsername>,
+	pub relayed: Option<SocketAddr>,
+	pub expires: Instant,
+	pub ice_username: Option<String>,
+	pub permissions: usize,
+	pub channels: usize,
+	pub traffic: Arc<Traffic>,
+}
+
+// Which allocations the admin API wants gone
+#[derive(Clone)]
+pub enum Kick {
+	Client(SocketAddr),
+	// Rendezvous allocations on either side of a peer's pairings (a canonical PeerId)
+	Peer(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Timer {
+	Allocation(SocketAddr),
+	Permission(SocketAddr, IpAddr),
+	Channel(SocketAddr, u16),
+	// ICE-lite: when the tuple from addr to socks[index] loses consent
+	Consent(usize, SocketAddr),
+}
+
+// State that's shared by every shard.
+pub struct Shared {
+	pub config: Config,
+	nonces: Nonces,
+	pub peers: Peers,
+	ice_key: IntegrityKey,
+	pub metrics: Metrics,
+	pub limits: Limiter,
+	pub hosted: Hosted,
+	// ICE-lite endpoint mode, for the clients that land on our UDP sockets
+	pub ice: Option<IceLite>,
+}
+impl Shared {
+	pub fn new(config: Config, ice: Option<IceLite>) -> Result<Self> {
+		Ok(Self {
+			ice,
+			nonces: Nonces::new(Duration::from_secs(config.lifetimes.nonce)),
+			peers: Peers::new(),
+			ice_key: IntegrityKey::new(config.secrets.ice_password.as_bytes()),
+			metrics: Metrics::default(),
+			limits: Limiter::new(&config.limits),
+			hosted: Hosted::load(&config.hosted)?,
+			config,
+		})
+	}
+	// An allocation went away (expired, deleted or replaced)
+	fn deallocated(&self, addr: SocketAddr, assoc: &Assoc) {
+		self.peers.deallocated(assoc.pairing.as_ref(), addr);
+		self.limits.release(addr.ip(), &assoc.username);
+	}
+}
+
+// A shard owns one socket and the allocations whose packets arrive on it: the kernel spreads clients across the
+// SO_REUSEPORT sockets by hashing their address, so a client always lands on the same shard.  TCP and TLS
+// connections are spread across shards the same way.
+pub struct Server {
+	shared: Arc<Shared>,
+	assocs: HashMap<SocketAddr, Assoc>,
+	keys: KeyCache,
+	timers: Deadlines<Timer>,
+	// For our allocations' relayed sockets to hand us what peers send them
+	peer_tx: mpsc::Sender<PeerPacket>,
+	// Peers::find's results, kept so that forwarding doesn't allocate
+	found: Vec<(Arc<Peer>, Option<u16>)>,
+}
+impl Server {
+	pub fn new(shared: Arc<Shared>, peer_tx: mpsc::Sender<PeerPacket>) -> Self {
+		Self {
+			peer_tx,
+			keys: KeyCache::new(Credentials::new(&shared.config.secrets), shared.config.limits.key_cache),
+			shared,
+			assocs: HashMap::new(),
+			timers: Deadlines::new(),
+			found: Vec::new(),
+		}
+	}
+
+	fn remove(&mut self, addr: SocketAddr) {
+		if let Some(assoc) = self.assocs.remove(&addr) {
+			self.shared.deallocated(addr, &assoc);
+		}
+	}
+
+	pub fn allocations(&self) -> Vec<Allocation> {
+		self.assocs
+			.iter()
+			.map(|(client, assoc)| Allocation {
+				client: *client,
+				stream: matches!(assoc.via, Via::Stream(_)),
+				username: assoc.username.clone(),
+				pairing: assoc.pairing.clone(),
+				relayed: assoc.relayed.as_ref().map(|r| r.addr),
+				expires: assoc.expires,
+				ice_username: assoc.ice_username.clone(),
+				permissions: assoc.permissions.len(),
+				channels: assoc.channels.len(),
+				traffic: assoc.traffic.clone(),
+			})
+			.collect()
+	}
+	// Remove allocations for the admin API.  Their clients aren't told: they find out when they next refresh.
+	pub fn kick(&mut self, kick: &Kick) -> usize {
+		let kicked: Vec<SocketAddr> = match kick {
+			Kick::Client(addr) => self.assocs.contains_key(addr).then_some(*addr).into_iter().collect(),
+			Kick::Peer(peer) => self
+				.assocs
+				.iter()
+				.filter(|(_, assoc)| {
+					let pairing = assoc.pairing.as_ref();
+					pairing.is_some_and(|p| p.dst() == peer || p.src() == peer)
+				})
+				.map(|(addr, _)| *addr)
+				.collect(),
+		};
+		for addr in &kicked {
+			if let Some(assoc) = self.assocs.get(addr) {
+				info!(parent: &assoc.span, client = %addr, "kicked by the admin api");
+			}
+			self.remove(*addr);
+		}
+		kicked.len()
+	}
+
+	// A TCP / TLS connection closed: RFC 5766 section 2.1, its allocation goes with it.
+	pub fn closed(&mut self, addr: SocketAddr) {
+		if self.assocs.get(&addr).is_some_and(|assoc| matches!(assoc.via, Via::Stream(_))) {
+			self.remove(addr);
+		}
+	}
+
+	fn deadline(assocs: &HashMap<SocketAddr, Assoc>, ice: Option<&IceLite>, timer: &Timer) -> Option<Instant> {
+		match timer {
+			Timer::Allocation(addr) => assocs.get(addr).map(|assoc| assoc.expires),
+			Timer::Permission(addr, peer) => assocs.get(addr)?.permissions.get(peer).copied(),
+			Timer::Channel(addr, channel) => assocs.get(addr)?.channels.get(channel).map(|(_, expires)| *expires),
+			Timer::Consent(index, addr) => ice?.consent(*index, *addr),
+		}
+	}
+	// When expire next needs to be called
+	pub fn next_deadline(&self) -> Option<Instant> {
+		self.timers.next()
+	}
+	// Remove everything that has expired by now.  Removing an allocation removes its permissions and channels with it.
+	pub fn expire(&mut self, now: Instant) {
+		let (assocs, ice) = (&self.assocs, self.shared.ice.as_ref());
+		for timer in self.timers.due(now, |t| Self::deadline(assocs, ice, t)) {
+			match timer {
+				Timer::Allocation(addr) => {
+					let Some(assoc) = self.assocs.remove(&addr) else { continue };
+					self.shared.deallocated(addr, &assoc);
+					info!(parent: &assoc.span, client = %addr, "allocation expired");
+				}
+				Timer::Permission(addr, peer) => {
+					let Some(assoc) = self.assocs.get_mut(&addr) else { continue };
+					assoc.permissions.remove(&peer);
+					debug!(parent: &assoc.span, client = %addr, %peer, "permission expired");
+				}
+				Timer::Channel(addr, channel) => {
+					let Some(assoc) = self.assocs.get_mut(&addr) else { continue };
+					let Some((peer, _)) = assoc.channels.remove(&channel) else { continue };
+					self.shared.peers.unbind(addr, peer);
+					debug!(parent: &assoc.span, client = %addr, channel, %peer, "channel expired");
+				}
+				Timer::Consent(index, addr) => {
+					if let Some(ice) = &self.shared.ice {
+						ice.expire(index, addr, now);
+					}
+				}
+			}
+		}
+		self.compact();
+	}
+	fn schedule(&mut self, deadline: Instant, timer: Timer) {
+		self.timers.schedule(deadline, timer);
+		self.compact();
+	}
+	fn compact(&mut self) {
+		let (assocs, ice) = (&self.assocs, self.shared.ice.as_ref());
+		self.timers.compact(|t| Self::deadline(assocs, ice, t));
+	}
+
+	// Handle one packet that arrived from addr via one of our sockets or connections.  Responses (and forwarded
+	// packets) are queued in send.  Each packet is a transaction with its own span, under the allocation's span if
+	// there is one.
+	pub async fn handle(
+		&mut self,
+		packet: &[u8],
+		addr: SocketAddr,
+		via: &Via,
+		send: &mut SendBatch,
+		socks: &[UdpSocket],
+	) -> Result<()> {
+		let parent = self.assocs.get(&addr).and_then(|assoc| assoc.span.id());
+		let span = tracing::debug_span!(parent: parent, "txn", client = %addr, method = field::Empty);
+		self.transaction(packet, addr, via, send, socks).instrument(span).await
+	}
+	async fn transaction(
+		&mut self,
+		packet: &[u8],
+		addr: SocketAddr,
+		via: &Via,
+		send: &mut SendBatch,
+		socks: &[UdpSocket],
+	) -> Result<()> {
+		let shared = self.shared.clone();
+		let config = &shared.config;
+		let nonces = &shared.nonces;
+		let metrics = &shared.metrics;
+		let limits = &shared.limits;
+		// Rate limit requests before spending anything on them (like checking their credentials):
+		if !limits.request(packet, addr.ip(), Instant::now()) {
+			metrics.requests_limited.inc();
+			debug!("rate limited");
+			return Ok(());
+		}
+		// ICE-lite gets first look at what arrives on our UDP socket: checks for our ufrag, and DTLS from its tuples
+		if let (Some(ice), &Via::Udp(index)) = (&shared.ice, via) {
+			let now = Instant::now();
+			let handled = ice.handle(packet, index, addr, send.buff(), now);
+			let consent = ice.consent(index, addr);
+			match handled {
+				Handled::Pass => {}
+				Handled::Unauthorized => {
+					Span::current().record("method", Method::IceCheck.name());
+					metrics.auth_failures.inc();
+					info!("ice check failed authentication");
+					done(metrics, Method::IceCheck, Outcome::Dropped);
+					return Ok(());
+				}
+				Handled::Reply(len, outcome) => {
+					Span::current().record("method", Method::IceCheck.name());
+					if let Some(consent) = consent {
+						self.schedule(consent, Timer::Consent(index, addr));
+					}
+					done(metrics, Method::IceCheck, outcome);
+					send.push(len, addr, via, socks).await?;
+					return Ok(());
+				}
+				Handled::Dtls => {
+					metrics.forwarded(Kind::Dtls, packet.len());
+					return Ok(());
+				}
+				Handled::Dropped => return Ok(()),
+			}
+		}
+		let msg = match TurnReq::decode(packet, |u, r| self.keys.get(u, r), |n| nonces.check(n, addr)) {
+			Ok(msg) => msg,
+			Err(rejected) => {
+				match rejected {
+					Rejected::Malformed => metrics.decode_errors.inc(),
+					Rejected::Unauthorized => metrics.auth_failures.inc(),
+					Rejected::Unsupported => metrics.unsupported.inc(),
+				}
+				match rejected {
+					Rejected::Unauthorized => info!(?rejected, "rejected"),
+					_ => debug!(?rejected, "rejected"),
+				}
+				return Ok(());
+			}
+		};
+		let method = Method::from(&msg);
+		Span::current().record("method", method.name());
+		if let TurnReq::AllocateNoAuth { unauthorized: true, .. } = msg {
+			metrics.auth_failures.inc();
+			info!("authentication failed");
+		}
+		let at_capacity = shared.peers.allocations() >= config.limits.max_allocations;
+		let assoc = self.assocs.get_mut(&addr);
+
+		// ChannelData is only accepted on channels that have been bound:
+		if let (TurnReq::Channel { channel, .. }, Some(assoc)) = (&msg, &assoc) {
+			if !assoc.channels.contains_key(channel) {
+				done(metrics, method, Outcome::Dropped);
+				return Ok(());
+			}
+		}
+
+		let nonce;
+		let res = match (msg, assoc) {
+			(TurnReq::Binding { txid }, _) => TurnRes::BindingRes {
+				txid,
+				xmapped: addr,
+			},
+			(TurnReq::AllocateNoAuth { txid, .. }, _) => {
+				nonce = nonces.issue(addr);
+				TurnRes::AllocateUseAuth {
+					txid,
+					realm: &config.realm,
+					nonce: &nonce,
+				}
+			}
+			(TurnReq::StaleNonce { txid, method }, _) => {
+				nonce = nonces.issue(addr);
+				TurnRes::StaleNonce {
+					txid,
+					method,
+					realm: &config.realm,
+					nonce: &nonce,
+				}
+			}
+			(
+				TurnReq::Allocate {
+					txid,
+					username,
+					key,
+					..
+				},
+				Some(assoc),
+			) if assoc.username.as_ref() != username && assoc.expires >= Instant::now() => {
+				TurnRes::AllocateMismatch { txid, key }
+			}
+			(TurnReq::Allocate { txid, key, .. }, None) if at_capacity => TurnRes::AllocateCapacity { txid, key },
+			(
+				TurnReq::Allocate {
+					txid,
+					username,
+					key,
+					requested_transport,
+				},
+				_,
+			) => 'allocate: {
+				let standard = config.relaying.mode == Mode::Standard;
+				let pairing = if standard {
+					None
+				} else {
+					match TurnUsername::try_from(username) {
+						Ok(pairing) => Some(pairing),
+						Err(e) => {
+							info!(username, error = format!("{e:#}"), "invalid username");
+							break 'allocate TurnRes::BadRequest {
+								txid,
+								method: 0x003,
+								key,
+							};
+						}
+					}
+				};
+				// We only relay UDP
+				if standard && requested_transport != 17 {
+					break 'allocate TurnRes::AllocateTransport { txid, key };
+				}
+				// Whatever allocation addr already has is replaced, so it doesn't count against the quotas.  It's only
+				// removed once the new one is sure to go ahead, so a failed Allocate leaves it working.
+				let replacing = self.assocs.get(&addr).map(|assoc| assoc.username.clone());
+				if !limits.allocate(addr.ip(), username, replacing.as_deref()) {
+					info!("allocation quota reached");
+					break 'allocate TurnRes::AllocateQuota { txid, key };
+				}
+				let relayed = if standard {
+					match Relayed::bind(&config.relaying, addr, self.peer_tx.clone()) {
+						Ok(relayed) => Some(relayed),
+						Err(_) => {
+							limits.release(addr.ip(), username);
+							break 'allocate TurnRes::AllocateCapacity { txid, key };
+						}
+					}
+				} else {
+					None
+				};
+				self.remove(addr);
+				let xrelayed = relayed.as_ref().map_or(addr, |r| r.addr);
+				let lifetime = config.lifetimes.allocation;
+				let expires = Instant::now().add(Duration::from_secs(lifetime as u64));
+				let span = match &pairing {
+					Some(pairing) => tracing::info_span!(
+						parent: None,
+						"pairing",
+						client = %addr,
+						dst = pairing.dst(),
+						src = pairing.src()
+					),
+					None => tracing::info_span!(parent: None, "allocation", client = %addr, username),
+				};
+				info!(parent: &span, relayed = %xrelayed, "allocated");
+				shared.peers.allocated();
+				self.assocs.insert(
+					addr,
+					Assoc {
+						username: username.into(),
+						span,
+						via: via.clone(),
+						pairing,
+						relayed,
+						expires,
+						ice_username: None,
+						ice_key: None,
+						permissions: HashMap::new(),
+						channels: HashMap::new(),
+						bandwidth: Bucket::new(limits.bytes_per_allocation, Instant::now()),
+						traffic: Arc::default(),
+					},
+				);
+				self.schedule(expires, Timer::Allocation(addr));
+				TurnRes::AllocateSuc {
+					txid,
+					key,
+					xmapped: addr,
+					xrelayed,
+					lifetime,
+				}
+			}
+			(
+				TurnReq::Refresh {
+					username,
+					lifetime: 0,
+					..
+				},
+				Some(assoc),
+			) if username == assoc.username.as_ref() => {
+				self.remove(addr);
+				done(metrics, method, Outcome::Success);
+				return Ok(());
+			}
+			(
+				TurnReq::Refresh {
+					txid,
+					username,
+					key,
+					lifetime,
+				},
+				Some(assoc),
+			) if username == assoc.username.as_ref() => {
+				// Rendezvous allocations only stay up if one side of them is hosted, and then they can stay up for
+				// longer
+				let (hosted, max_lifetime) = match &assoc.pairing {
+					Some(pairing) => (
+						shared.hosted.contains(pairing.dst()) || shared.hosted.contains(pairing.src()),
+						config.lifetimes.hosted,
+					),
+					None => (true, config.lifetimes.allocation),
+				};
+				if hosted {
+					let lifetime = lifetime.min(max_lifetime);
+					assoc.expires = Instant::now().add(Duration::from_secs(lifetime as u64));
+					if let Some(pairing) = &assoc.pairing {
+						shared.peers.refresh(pairing, addr, assoc.expires);
+					}
+					let expires = assoc.expires;
+					self.schedule(expires, Timer::Allocation(addr));
+					TurnRes::RefreshSuc {
+						txid,
+						key,
+						lifetime,
+					}
+				} else {
+					// Kick anything that's not in the hosted
+					metrics.kicks.inc();
+					info!("kicked: neither side of the pairing is hosted");
+					TurnRes::RefreshKick { txid, key }
+				}
+			}
+			(TurnReq::Permission { txid, key, xpeer, .. }, Some(assoc)) => {
+				let expires = Instant::now() + PERMISSION_LIFETIME;
+				assoc.permissions.insert(xpeer.ip(), expires);
+				self.schedule(expires, Timer::Permission(addr, xpeer.ip()));
+				TurnRes::PermissionSuc { txid, key }
+			}
+			(
+				TurnReq::BindChannel {
+					txid,
+					key,
+					channel,
+					xpeer,
+					..
+				},
+				Some(assoc),
+			) => {
+				if !can_bind(&assoc.channels, channel, xpeer) {
+					TurnRes::BadRequest {
+						txid,
+						method: 0x009,
+						key,
+					}
+				} else {
+					// Binding a channel also installs / refreshes a permission for the peer:
+					let now = Instant::now();
+					assoc.channels.insert(channel, (xpeer, now + CHANNEL_LIFETIME));
+					assoc.permissions.insert(xpeer.ip(), now + PERMISSION_LIFETIME);
+					shared.peers.bind(addr, xpeer, channel);
+					self.schedule(now + CHANNEL_LIFETIME, Timer::Channel(addr, channel));
+					self.schedule(now + PERMISSION_LIFETIME, Timer::Permission(addr, xpeer.ip()));
+					TurnRes::BindChannelSuc { txid, key }
+				}
+			}
+			// Standard mode: straight out the allocation's socket, if there's a permission for the peer
+			(
+				TurnReq::Send { xpeer, data, .. },
+				Some(Assoc {
+					relayed: Some(relayed),
+					permissions,
+					bandwidth,
+					traffic,
+					..
+				}),
+			) => {
+				let now = Instant::now();
+				if permissions.get(&xpeer.ip()).is_none_or(|expires| *expires < now) {
+					done(metrics, method, Outcome::Dropped);
+				} else if !bandwidth.take(limits.bytes_per_allocation, data.len() as u64, now) {
+					metrics.bytes_limited.inc();
+					done(metrics, method, Outcome::Dropped);
+				} else {
+					relayed.send_to(data, xpeer);
+					traffic.sent(data.len());
+					metrics.forwarded(Kind::Relayed, data.len());
+					done(metrics, method, Outcome::Success);
+				}
+				return Ok(());
+			}
+			(
+				TurnReq::Channel { channel, data },
+				Some(Assoc {
+					relayed: Some(relayed),
+					channels,
+					bandwidth,
+					traffic,
+					..
+				}),
+			) => {
+				let Some((peer, _)) = channels.get(&channel) else {
+					done(metrics, method, Outcome::Dropped);
+					return Ok(());
+				};
+				if bandwidth.take(limits.bytes_per_allocation, data.len() as u64, Instant::now()) {
+					relayed.send_to(data, *peer);
+					traffic.sent(data.len());
+					metrics.forwarded(Kind::Relayed, data.len());
+					done(metrics, method, Outcome::Success);
+				} else {
+					metrics.bytes_limited.inc();
+					done(metrics, method, Outcome::Dropped);
+				}
+				return Ok(());
+			}
+			(TurnReq::Channel { data, .. }, Some(assoc))
+			| (TurnReq::Send { data, .. }, Some(assoc)) => {
+				let (Some(pairing), Some(mut webrtc)) = (assoc.pairing.as_ref(), WebRTC::decode(data)) else {
+					done(metrics, method, Outcome::Dropped);
+					return Ok(());
+				};
+
+				if let WebRTC::IceReq { username, .. } = webrtc {
+					if let Some((ice_pwd, ice_ufrag)) = username.split_once(":") {
+						if assoc.ice_username.is_none() {
+							let ice_username = format!("{ice_ufrag}:{ice_pwd}");
+							let ice_key = IntegrityKey::new(ice_pwd.as_bytes());
+							shared.peers.publish(
+								pairing,
+								Peer {
+									addr,
+									via: via.clone(),
+									expires: assoc.expires,
+									ice_username: ice_username.as_str().into(),
+									ice_key: ice_key.clone(),
+									traffic: assoc.traffic.clone(),
+								},
+							);
+							assoc.ice_username = Some(ice_username);
+							assoc.ice_key = Some(ice_key);
+						}
+					}
+				}
+
+				// The bandwidth limits apply to what's sent into the pairing, whether or not anyone's there to get it
+				let now = Instant::now();
+				if !assoc.bandwidth.take(limits.bytes_per_allocation, data.len() as u64, now)
+					|| !limits.pairing(pairing, data.len(), now)
+				{
+					metrics.bytes_limited.inc();
+					done(metrics, method, Outcome::Dropped);
+					return Ok(());
+				}
+
+				// TODO: Randomize our traversal of peers
+				shared.peers.find(pairing, addr, now, &mut self.found);
+				let kind = Kind::from(&webrtc);
+				let mut forwarded = false;
+				for (peer, channel) in &self.found {
+					let ice_username = &peer.ice_username;
+					let peer_ice_key = &peer.ice_key;
+
+					// Fixup the credentials
+					match webrtc {
+						WebRTC::IceReq {
+							ref mut integrity,
+							ref mut username,
+							ref mut priority,
+							..
+						} if integrity.verify_key(&shared.ice_key) => {
+							*priority = 1;
+							*integrity = Integrity::Key(peer_ice_key);
+							*username = ice_username;
+						}
+						WebRTC::IceRes {
+							ref mut integrity, ..
+						}
+						| WebRTC::IceErr {
+							ref mut integrity, ..
+						} => {
+							*integrity = Integrity::Key(&shared.ice_key);
+						}
+						WebRTC::Rtp(_) => {
+							// Don't forward RTP
+							metrics.forwarded(kind, data.len());
+							continue;
+						}
+						_ => {},
+					};
+					// Peers that have bound a channel to us get ChannelData instead of a Data indication:
+					let txid = b"txidtxidtxid"; // TODO: Random?
+					let len = match channel {
+						Some(channel) => TurnRes::Channel {
+							channel: *channel,
+							data: &webrtc
+						},
+						None => TurnRes::Data {
+							txid: *txid,
+							xpeer: addr,
+							data: &webrtc
+						},
+					}
+					.encode(send.buff());
+					if let Some(len) = len {
+						send.push(len, peer.addr, &peer.via, socks).await?;
+						metrics.forwarded(kind, len);
+						peer.traffic.received(len);
+						trace!(peer = %peer.addr, kind = kind.name(), len, "forwarded");
+						forwarded = true;
+					}
+				}
+				// Don't keep the peers (and their connections' writers) alive until the next packet
+				self.found.clear();
+				if forwarded {
+					assoc.traffic.sent(data.len());
+				}
+				done(metrics, method, if forwarded { Outcome::Success } else { Outcome::Dropped });
+				return Ok(());
+			}
+			_ => {
+				done(metrics, method, Outcome::Dropped);
+				return Ok(());
+			}
+		};
+		done(metrics, method, Outcome::from(&res));
+		if let Some(len) = res.encode(send.buff()) {
+			send.push(len, addr, via, socks).await?;
+		}
+		Ok(())
+	}
+
+	// Something a peer sent to one of our standard mode allocations: pass it on to the client if it has a permission
+	// for the peer, as ChannelData if it has bound a channel to the peer.
+	pub async fn handle_peer(&mut self, packet: PeerPacket, send: &mut SendBatch, socks: &[UdpSocket]) -> Result<()> {
+		let Some(assoc) = self.assocs.get_mut(&packet.client) else { return Ok(()) };
+		let now = Instant::now();
+		let permitted = assoc
+			.permissions
+			.get(&packet.peer.ip())
+			.is_some_and(|expires| *expires >= now);
+		if !permitted {
+			return Ok(());
+		}
+		if !assoc.bandwidth.take(self.shared.limits.bytes_per_allocation, packet.data.len() as u64, now) {
+			self.shared.metrics.bytes_limited.inc();
+			return Ok(());
+		}
+		let channel = assoc
+			.channels
+			.iter()
+			.find(|(_, (peer, _))| *peer == packet.peer)
+			.map(|(channel, _)| *channel);
+		let data = packet.data.as_slice();
+		let len = match channel {
+			Some(channel) => TurnRes::Channel { channel, data: &data },
+			None => TurnRes::Data {
+				txid: rand::random(),
+				xpeer: packet.peer,
+				data: &data,
+			},
+		}
+		.encode(send.buff());
+		if let Some(len) = len {
+			send.push(len, packet.client, &assoc.via, socks).await?;
+			self.shared.metrics.forwarded(Kind::Relayed, packet.data.len());
+			assoc.traffic.received(packet.data.len());
+			trace!(parent: &assoc.span, peer = %packet.peer, len, "relayed to client");
+		}
+		Ok(())
+	}
+}
+
+// Count a handled request and log how it went.
+fn done(metrics: &Metrics, method: Method, outcome: Outcome) {
+	metrics.request(method, outcome);
+	debug!(outcome = outcome.name(), "done");
+}
+
+// RFC 5766 section 11.2: the channel has to be in range, and neither the channel nor the peer can already be bound
+// to something else.  Binding the same channel to the same peer again refreshes it.
+fn can_bind(channels: &HashMap<u16, (SocketAddr, Instant)>, channel: u16, xpeer: SocketAddr) -> bool {
+	(0x4000..=0x7FFF).contains(&channel) && !channels.iter().any(|(c, (peer, _))| (*c == channel) != (*peer == xpeer))
+}
+
+#[cfg(test)]
+pub mod tests {
+	use stun::{
+		attr::{RequestedTransport, StunAttr},
+		attrs::typed,
+		encoder::StunEncoder,
+		view::StunView,
+		StunTyp,
+	};
+
+	use super::*;
+	use crate::{auth::long_term_key, config::Rate};
+
+	pub const USERNAME: &str =
+		"wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs.gckU8soOEqYjH2nUkmW3JMtERbUfNZXErmnvxT5BT1I.token";
+
+	// Make an authenticated allocation from addr and return the response's error code, if it has one
+	pub async fn allocate(server: &mut Server, addr: SocketAddr) -> Option<u16> {
+		allocate_as(server, addr, USERNAME).await
+	}
+	pub async fn allocate_as(server: &mut Server, addr: SocketAddr, username: &str) -> Option<u16> {
+		let config = &server.shared.config;
+		let nonce = server.shared.nonces.issue(addr);
+		let key = long_term_key(username, &config.realm, &config.secrets.turn_password);
+		let mut packet = [0u8; 512];
+		let len = StunEncoder::encode(
+			&mut packet,
+			&StunTyp::Req(0x003),
+			&rand::random(),
+			&[
+				StunAttr::RequestedTransport(RequestedTransport(17)),
+				StunAttr::Username(username),
+				StunAttr::Realm(&config.realm),
+				StunAttr::Nonce(&nonce),
+				StunAttr::Integrity(Integrity::Set { key_data: &key }),
+				StunAttr::Fingerprint,
+			],
+		)
+		.unwrap();
+		let (tx, mut rx) = mpsc::channel(1);
+		let mut send = SendBatch::new();
+		server.handle(&packet[..len], addr, &Via::Stream(tx), &mut send, &[]).await.unwrap();
+		let res = rx.try_recv().unwrap();
+		let res = StunView::decode(&res).unwrap();
+		res.get::<typed::Error>().map(|e| e.code)
+	}
+
+	pub fn server(config: Config) -> Server {
+		let (peer_tx, _) = mpsc::channel(1);
+		Server::new(Arc::new(Shared::new(config, None).unwrap()), peer_tx)
+	}
+
+	#[tokio::test]
+	async fn allocation_quota() {
+		let mut config = Config::default();
+		config.limits.requests_per_ip = Rate::UNLIMITED;
+		config.limits.allocations_per_ip = 1;
+		let mut server = server(config);
+		let (a, b) = (SocketAddr::from(([192, 0, 2, 1], 5000)), SocketAddr::from(([192, 0, 2, 1], 5001)));
+		assert_eq!(allocate(&mut server, a).await, None);
+		assert_eq!(allocate(&mut server, b).await, Some(486));
+		// Replacing an allocation doesn't count against the quota
+		assert_eq!(allocate(&mut server, a).await, None);
+		assert_eq!(server.allocations().len(), 1);
+		server.kick(&Kick::Client(a));
+		assert_eq!(allocate(&mut server, b).await, None);
+
+		// An expired allocation that's replaced by one that's over its quota is left as it was
+		let mut config = Config::default();
+		config.limits.requests_per_ip = Rate::UNLIMITED;
+		config.limits.allocations_per_username = 1;
+		let mut server = self::server(config);
+		let c = SocketAddr::from(([192, 0, 2, 2], 5000));
+		let other = USERNAME.replace(".token", ".other");
+		assert_eq!(allocate(&mut server, a).await, None);
+		assert_eq!(allocate_as(&mut server, c, &other).await, None);
+		server.assocs.get_mut(&a).unwrap().expires = Instant::now();
+		assert_eq!(allocate_as(&mut server, a, &other).await, Some(486));
+		let allocation = server.allocations().into_iter().find(|allocation| allocation.client == a).unwrap();
+		assert_eq!(&*allocation.username, USERNAME);
+		assert_eq!(allocate(&mut server, a).await, None);
+	}
+
+	#[tokio::test]
+	async fn bad_usernames() {
+		let mut config = Config::default();
+		config.limits.requests_per_ip = Rate::UNLIMITED;
+		let mut server = server(config.clone());
+		let addr = SocketAddr::from(([192, 0, 2, 1], 5000));
+		for username in ["user", "a.b.token", &USERNAME.replace(".token", "")] {
+			assert_eq!(allocate_as(&mut server, addr, username).await, Some(400), "{username}");
+		}
+		assert!(server.allocations().is_empty());
+		// Standard mode doesn't care what usernames look like
+		config.relaying.mode = Mode::Standard;
+		config.relaying.address = [127, 0, 0, 1].into();
+		let mut server = self::server(config);
+		assert_eq!(allocate_as(&mut server, addr, "user").await, None);
+	}
+
+	#[tokio::test]
+	async fn insufficient_capacity() {
+		let mut config = Config::default();
+		config.limits.requests_per_ip = Rate::UNLIMITED;
+		config.limits.max_allocations = 1;
+		let mut server = server(config);
+		let (a, b) = (SocketAddr::from(([192, 0, 2, 1], 5000)), SocketAddr::from(([192, 0, 2, 2], 5000)));
+		assert_eq!(allocate(&mut server, a).await, None);
+		assert_eq!(allocate(&mut server, b).await, Some(508));
+		assert_eq!(allocate(&mut server, a).await, None);
+		server.kick(&Kick::Client(a));
+		assert_eq!(allocate(&mut server, b).await, None);
+	}
+
+	#[test]
+	fn channel_range() {
+		let peer = SocketAddr::from(([192, 0, 2, 1], 5000));
+		for channel in [0x4000, 0x5000, 0x7FFF] {
+			assert!(can_bind(&HashMap::new(), channel, peer), "{channel:#x}");
+		}
+		for channel in [0, 0x3FFF, 0x8000, 0xFFFF] {
+			assert!(!can_bind(&HashMap::new(), channel, peer), "{channel:#x}");
+		}
+	}
+
+	#[test]
+	fn channel_conflicts() {
+		let (peer, other) = (SocketAddr::from(([192, 0, 2, 1], 5000)), SocketAddr::from(([192, 0, 2, 1], 5001)));
+		let channels = HashMap::from([(0x4000, (peer, Instant::now()))]);
+		// Refreshing the same binding
+		assert!(can_bind(&channels, 0x4000, peer));
+		// The channel is taken by another peer
+		assert!(!can_bind(&channels, 0x4000, other));
+		// The peer is already bound to another channel
+		assert!(!can_bind(&channels, 0x4001, peer));
+		// Neither is bound yet
+		assert!(can_bind(&channels, 0x4001, other));
+	}
+}
@@ -0,0 +1,296 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	net::IpAddr,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use peerid::PeerId;
+use tokio::sync::Notify;
+
+use crate::{
+	auth::{self, Credentials},
+	config::{self, Rate},
+	http,
+	limits::Buckets,
+};
+
+// The longest peer id that we'll parse (a tagged sha-512 PeerId is ~100 characters)
+const MAX_ID: usize = 256;
+
+#[derive(Default)]
+struct Mailbox {
+	// (expiry, sender, message)
+	messages: VecDeque<(Instant, IpAddr, String)>,
+	// Woken when a message arrives.  Anyone holding a clone is waiting on the mailbox.
+	notify: Arc<Notify>,
+}
+impl Mailbox {
+	fn expire(&mut self, now: Instant) {
+		self.messages.retain(|(expires, ..)| *expires > now);
+	}
+	fn unused(&self) -> bool {
+		self.messages.is_empty() && Arc::strong_count(&self.notify) == 1
+	}
+}
+
+// A rendezvous for signaling messages: whoever wants to connect to a peer POSTs their message to the peer's mailbox,
+// and the peer long-polls its mailbox to collect them.  Messages are opaque text to us (peercon's SigMsg, usually);
+// the HTTP server limits their size, and they expire if they aren't collected in time.
+//
+// Messages carry ICE credentials, so only the peer can collect them: it has to show TURN REST API credentials that
+// were issued for its PeerId (e.g. by GET /ice-servers?user=<peer id>, or by the app's own backend).  Mailboxes are
+// keyed by the canonical PeerId, so every spelling of a peer's id reaches the same one.
+//
+// Anyone can leave messages, so senders are kept in check by IP: POSTs count against a request rate (like TURN
+// requests do), and a mailbox only takes so many of its messages from one IP.
+pub struct Signaling {
+	config: config::Signaling,
+	credentials: Credentials,
+	mailboxes: Mutex<HashMap<String, Mailbox>>,
+	posts: Buckets<IpAddr>,
+}
+impl Signaling {
+	pub fn new(config: config::Signaling, credentials: Credentials, requests_per_ip: Rate) -> Self {
+		Self {
+			config,
+			credentials,
+			mailboxes: Mutex::new(HashMap::new()),
+			posts: Buckets::new(requests_per_ip),
+		}
+	}
+	// POST /signal/<peer id>: leave a message.  GET /signal/<peer id>?username=<u>&credential=<c>&wait=<sec>: collect
+	// messages, waiting up to wait seconds for one to arrive.
+	pub async fn handle(&self, req: http::Request) -> http::Response {
+		let Some(id) = req.path.strip_prefix("/signal/") else {
+			return http::Response::not_found();
+		};
+		let Some(id) = (id.len() <= MAX_ID).then(|| id.parse::<PeerId>().ok()).flatten() else {
+			return http::Response::text(400, "invalid peer id\n");
+		};
+		let id = id.to_string();
+		match req.method.as_str() {
+			"POST" => self.post(&id, req.body, req.peer.ip()),
+			"GET" => {
+				if !self.owns(&id, req.query("username"), req.query("credential")) {
+					return http::Response::text(403, "credentials for the peer id are required\n");
+				}
+				let wait = req.query("wait").map_or(Some(self.config.max_wait), |w| w.parse().ok());
+				let Some(wait) = wait else {
+					return http::Response::text(400, "invalid wait\n");
+				};
+				self.poll(&id, Duration::from_secs(wait.min(self.config.max_wait))).await
+			}
+			_ => http::Response::text(405, "method not allowed\n"),
+		}
+	}
+	// Whether username / credential are unexpired TURN REST API credentials for the (canonical) peer id
+	fn owns(&self, id: &str, username: Option<&str>, credential: Option<&str>) -> bool {
+		let (Some(username), Some(credential)) = (username, credential) else { return false };
+		if !matches!(self.credentials, Credentials::Rest(_)) || self.credentials.expired(username) {
+			return false;
+		}
+		let user = Credentials::user(username);
+		if !user.parse::<PeerId>().is_ok_and(|user| user.to_string() == id) {
+			return false;
+		}
+		let mut passwords = self.credentials.passwords(username);
+		passwords.any(|password| auth::same(&password, credential))
+	}
+	fn post(&self, id: &str, body: Vec<u8>, ip: IpAddr) -> http::Response {
+		let now = Instant::now();
+		if !self.posts.take(&ip, 1, now) {
+			return http::Response::text(429, "too many requests\n");
+		}
+		let Ok(message) = String::from_utf8(body) else {
+			return http::Response::text(400, "message must be utf-8\n");
+		};
+		if message.is_empty() {
+			return http::Response::text(400, "message is empty\n");
+		}
+		let mut mailboxes = self.mailboxes.lock().unwrap();
+		if !mailboxes.contains_key(id) && mailboxes.len() >= self.config.max_mailboxes {
+			return http::Response::text(503, "too many mailboxes\n");
+		}
+		let mailbox = mailboxes.entry(id.into()).or_default();
+		mailbox.expire(now);
+		let from_ip = mailbox.messages.iter().filter(|(_, sender, _)| *sender == ip).count();
+		if mailbox.messages.len() >= self.config.max_pending || from_ip >= self.config.max_pending_per_ip {
+			return http::Response::text(429, "mailbox is full\n");
+		}
+		let expires = now + Duration::from_secs(self.config.ttl);
+		mailbox.messages.push_back((expires, ip, message));
+		mailbox.notify.notify_waiters();
+		http::Response::text(202, "")
+	}
+	async fn poll(&self, id: &str, wait: Duration) -> http::Response {
+		let deadline = tokio::time::Instant::now() + wait;
+		let notify = {
+			let mut mailboxes = self.mailboxes.lock().unwrap();
+			if !mailboxes.contains_key(id) && mailboxes.len() >= self.config.max_mailboxes {
+				return http::Response::text(503, "too many mailboxes\n");
+			}
+			mailboxes.entry(id.into()).or_default().notify.clone()
+		};
+		loop {
+			// Register for the notification before checking, so that a message can't slip in between.
+			let notified = notify.notified();
+			tokio::pin!(notified);
+			notified.as_mut().enable();
+
+			let messages = self.take(id);
+			if !messages.is_empty() {
+				return http::Response::json(&serde_json::json!({ "messages": messages }));
+			}
+			if tokio::time::timeout_at(deadline, notified).await.is_err() {
+				return http::Response::text(204, "");
+			}
+		}
+	}
+	fn take(&self, id: &str) -> Vec<String> {
+		let now = Instant::now();
+		let mut mailboxes = self.mailboxes.lock().unwrap();
+		let Some(mailbox) = mailboxes.get_mut(id) else { return Vec::new() };
+		mailbox
+			.messages
+			.drain(..)
+			.filter(|(expires, ..)| *expires > now)
+			.map(|(.., message)| message)
+			.collect()
+	}
+	// Periodically drop expired messages, and mailboxes that nobody is using.
+	pub async fn sweep(self: Arc<Self>) {
+		let mut interval = tokio::time::interval(Duration::from_secs(self.config.ttl));
+		loop {
+			interval.tick().await;
+			let now = Instant::now();
+			self.mailboxes.lock().unwrap().retain(|_, mailbox| {
+				mailbox.expire(now);
+				!mailbox.unused()
+			});
+			self.posts.sweep(now);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use super::*;
+	use crate::http::client::{encode, request, serve};
+
+	const A: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+	const B: &str = "gckU8soOEqYjH2nUkmW3JMtERbUfNZXErmnvxT5BT1I";
+
+	fn credentials() -> Credentials {
+		Credentials::Rest(vec!["secret".into()])
+	}
+	async fn start(config: config::Signaling) -> SocketAddr {
+		let signaling = Arc::new(Signaling::new(config, credentials(), Rate::UNLIMITED));
+		serve(4096, move |req| {
+			let signaling = signaling.clone();
+			async move { signaling.handle(req).await }
+		})
+		.await
+	}
+	fn get(id: &str, wait: u64, (username, credential): &(String, String)) -> String {
+		format!("/signal/{id}?wait={wait}&username={}&credential={}", encode(username), encode(credential))
+	}
+
+	#[tokio::test]
+	async fn post_and_collect() {
+		let addr = start(config::Signaling::default()).await;
+		let creds = credentials().issue(A, 60).unwrap();
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "first").await.0, 202);
+		// The same PeerId, spelled with its algorithm
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}%7Csha-256"), &[], "second").await.0, 202);
+		let (status, body) = request(addr, "GET", &get(A, 0, &creds), &[], "").await;
+		assert_eq!(status, 200);
+		assert_eq!(body, r#"{"messages":["first","second"]}"#);
+		// Collected messages are gone
+		assert_eq!(request(addr, "GET", &get(A, 0, &creds), &[], "").await.0, 204);
+	}
+
+	#[tokio::test]
+	async fn long_poll() {
+		let addr = start(config::Signaling::default()).await;
+		let creds = credentials().issue(A, 60).unwrap();
+		let poll = tokio::spawn(async move { request(addr, "GET", &get(A, 5, &creds), &[], "").await });
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "hello").await.0, 202);
+		assert_eq!(poll.await.unwrap(), (200, r#"{"messages":["hello"]}"#.into()));
+	}
+
+	#[tokio::test]
+	async fn only_the_peer_collects() {
+		let addr = start(config::Signaling::default()).await;
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "secret").await.0, 202);
+		let (username, credential) = credentials().issue(A, 60).unwrap();
+		let expired = format!("1:{A}");
+		let expired = (expired.clone(), credentials().passwords(&expired).next().unwrap());
+		let others = Credentials::Rest(vec!["other".into()]).issue(A, 60).unwrap();
+		for target in [
+			format!("/signal/{A}?wait=0"),
+			format!("/signal/{A}?wait=0&username={}", encode(&username)),
+			get(A, 0, &(username.clone(), "wrong".into())),
+			get(A, 0, &(username.clone(), credential[1..].into())),
+			get(A, 0, &credentials().issue(B, 60).unwrap()),
+			get(A, 0, &expired),
+			get(A, 0, &others),
+		] {
+			assert_eq!(request(addr, "GET", &target, &[], "").await.0, 403, "{target}");
+		}
+		// Still there for the peer
+		let (status, body) = request(addr, "GET", &get(A, 0, &(username, credential)), &[], "").await;
+		assert_eq!((status, body.as_str()), (200, r#"{"messages":["secret"]}"#));
+	}
+
+	#[tokio::test]
+	async fn invalid_requests() {
+		let addr = start(config::Signaling {
+			max_pending: 2,
+			..Default::default()
+		})
+		.await;
+		for id in ["", "nope", "a/b", &"A".repeat(300), &format!("{A}/x")] {
+			let (status, _) = request(addr, "POST", &format!("/signal/{id}"), &[], "x").await;
+			assert_eq!(status, 400, "{id:?}");
+		}
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "").await.0, 400);
+		assert_eq!(request(addr, "PUT", &format!("/signal/{A}"), &[], "x").await.0, 405);
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "1").await.0, 202);
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "2").await.0, 202);
+		assert_eq!(request(addr, "POST", &format!("/signal/{A}"), &[], "3").await.0, 429);
+		let creds = credentials().issue(A, 60).unwrap();
+		let target = get(A, 0, &creds).replace("wait=0", "wait=soon");
+		assert_eq!(request(addr, "GET", &target, &[], "").await.0, 400);
+	}
+
+	#[tokio::test]
+	async fn senders_share_mailboxes() {
+		let config = config::Signaling {
+			max_pending: 4,
+			max_pending_per_ip: 2,
+			..Default::default()
+		};
+		let signaling = Signaling::new(config, credentials(), Rate { rate: 1, burst: 3 });
+		let (a, b) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+		let post = |id: &str, ip| signaling.post(id, b"hi".to_vec(), ip).status;
+		assert_eq!(post(A, a), 202);
+		assert_eq!(post(A, a), 202);
+		// One sender can only take its share of a mailbox, and only post so often
+		assert_eq!(post(A, a), 429);
+		assert_eq!(post(B, a), 429);
+		assert_eq!(post(A, b), 202);
+		assert_eq!(post(A, b), 202);
+		assert_eq!(signaling.take(A), ["hi"; 4]);
+	}
+
+	#[tokio::test]
+	async fn too_large() {
+		let addr = start(config::Signaling::default()).await;
+		let (status, _) = request(addr, "POST", &format!("/signal/{A}"), &[], &"x".repeat(5000)).await;
+		assert_eq!(status, 413);
+	}
+}
@@ -0,0 +1,233 @@
+use std::{
+	hash::{DefaultHasher, Hash, Hasher},
+	io,
+	net::SocketAddr,
+	path::PathBuf,
+	sync::{Arc, RwLock},
+	time::{Duration, SystemTime},
+};
+
+use eyre::{eyre, Result, WrapErr};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+	net::TcpListener,
+	sync::mpsc,
+	time::timeout,
+};
+use tokio_rustls::{
+	rustls::{
+		pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+		ServerConfig,
+	},
+	TlsAcceptor,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+	batch::{Via, BUFF_LEN},
+	config::Tls,
+};
+
+// How many packets can be waiting on a connection's writer before we start dropping them
+const WRITE_QUEUE: usize = 256;
+// How often to check the certificate files for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+// How long a TLS client gets to finish its handshake, which is much less than the idle timeout so that clients that
+// never finish can't hold on to sockets
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a closed connection gets to write out the replies that are still queued for it
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// What connections hand to the shard that they're assigned to.
+pub enum StreamEvent {
+	Packet { addr: SocketAddr, via: Via, data: Vec<u8> },
+	Closed { addr: SocketAddr },
+}
+
+// The TLS listeners' certificate, which is swapped out when its files change.
+pub struct Certificates {
+	certificate: PathBuf,
+	private_key: PathBuf,
+	acceptor: RwLock<TlsAcceptor>,
+}
+impl Certificates {
+	pub fn load(tls: &Tls) -> Result<Self> {
+		let (Some(certificate), Some(private_key)) = (tls.certificate.clone(), tls.private_key.clone()) else {
+			return Err(eyre!("tls.certificate and tls.private_key are required"));
+		};
+		let acceptor = RwLock::new(Self::acceptor(&certificate, &private_key)?);
+		Ok(Self {
+			certificate,
+			private_key,
+			acceptor,
+		})
+	}
+	fn acceptor(certificate: &PathBuf, private_key: &PathBuf) -> Result<TlsAcceptor> {
+		let chain = CertificateDer::pem_file_iter(certificate)
+			.and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+			.wrap_err_with(|| format!("unable to read certificates from {}", certificate.display()))?;
+		let key = PrivateKeyDer::from_pem_file(private_key)
+			.wrap_err_with(|| format!("unable to read private key from {}", private_key.display()))?;
+		let config = ServerConfig::builder()
+			.with_no_client_auth()
+			.with_single_cert(chain, key)
+			.wrap_err("invalid certificate or private key")?;
+		Ok(TlsAcceptor::from(Arc::new(config)))
+	}
+	fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+		let certificate = std::fs::metadata(&self.certificate).and_then(|m| m.modified()).ok()?;
+		let private_key = std::fs::metadata(&self.private_key).and_then(|m| m.modified()).ok()?;
+		Some((certificate, private_key))
+	}
+	fn reload(&self) -> Result<()> {
+		let acceptor = Self::acceptor(&self.certificate, &self.private_key)?;
+		*self.acceptor.write().unwrap() = acceptor;
+		Ok(())
+	}
+	// Reload the certificate whenever its files change, or on SIGHUP.  If the new files don't load (say they've only
+	// been half written), we keep using the old certificate and try again next time.
+	pub async fn watch(self: Arc<Self>) -> Result<()> {
+		#[cfg(unix)]
+		let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+		let mut modified = self.modified();
+		let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+		loop {
+			#[cfg(unix)]
+			tokio::select! {
+				_ = interval.tick() => {},
+				_ = hup.recv() => modified = None,
+			}
+			#[cfg(not(unix))]
+			interval.tick().await;
+
+			let now = self.modified();
+			if now.is_none() || now == modified {
+				continue;
+			}
+			match self.reload() {
+				Ok(()) => {
+					modified = now;
+					info!(certificate = %self.certificate.display(), "reloaded tls certificate");
+				}
+				Err(e) => warn!(error = format!("{e:#}"), "tls certificate reload failed"),
+			}
+		}
+	}
+}
+
+// Accept TURN connections (TCP, or TLS if there are certificates) and hand them to shards by hashing the client's
+// address, so that each client always lands on the same shard.
+pub async fn listen(
+	listener: TcpListener,
+	certificates: Option<Arc<Certificates>>,
+	shards: Arc<[mpsc::Sender<StreamEvent>]>,
+	idle: Duration,
+) {
+	loop {
+		let (stream, addr) = match listener.accept().await {
+			Ok(accepted) => accepted,
+			Err(e) => {
+				// Probably out of file descriptors: back off rather than spin.
+				warn!(error = %e, "accept failed");
+				tokio::time::sleep(Duration::from_millis(100)).await;
+				continue;
+			}
+		};
+		let _ = stream.set_nodelay(true);
+		let mut hasher = DefaultHasher::new();
+		addr.hash(&mut hasher);
+		let shard = shards[hasher.finish() as usize % shards.len()].clone();
+		let certificates = certificates.clone();
+		tokio::spawn(async move {
+			let ret = match certificates {
+				Some(certificates) => {
+					let acceptor = certificates.acceptor.read().unwrap().clone();
+					match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+						Ok(Ok(stream)) => connection(stream, addr, shard, idle).await,
+						Ok(Err(e)) => Err(e),
+						Err(_) => Err(io::ErrorKind::TimedOut.into()),
+					}
+				}
+				None => connection(stream, addr, shard, idle).await,
+			};
+			match ret {
+				Ok(()) => debug!(client = %addr, "connection closed"),
+				Err(e) => debug!(client = %addr, error = %e, "connection closed"),
+			}
+		});
+	}
+}
+
+// Pass the connection's packets to its shard until it's closed or goes idle.  The shard's replies come back through
+// the connection's Via.
+async fn connection<S>(stream: S, addr: SocketAddr, shard: mpsc::Sender<StreamEvent>, idle: Duration) -> io::Result<()>
+where
+	S: AsyncRead + AsyncWrite + Send + 'static,
+{
+	let (reader, mut writer) = tokio::io::split(stream);
+	let (tx, mut rx) = mpsc::channel::<Vec<u8>>(WRITE_QUEUE);
+	let mut write = tokio::spawn(async move {
+		while let Some(mut data) = rx.recv().await {
+			// RFC 5766 section 11.5: over streams, ChannelData is padded to a multiple of 4 bytes.
+			if data.first().is_some_and(|b| b >> 6 == 1) {
+				data.resize(data.len().next_multiple_of(4), 0);
+			}
+			if writer.write_all(&data).await.is_err() {
+				break;
+			}
+		}
+		let _ = writer.shutdown().await;
+	});
+
+	let via = Via::Stream(tx);
+	let mut reader = BufReader::new(reader);
+	let ret = loop {
+		let data = match timeout(idle, read_frame(&mut reader)).await {
+			Ok(Ok(Some(data))) => data,
+			Ok(Ok(None)) => break Ok(()),
+			Ok(Err(e)) => break Err(e),
+			Err(_) => break Err(io::ErrorKind::TimedOut.into()),
+		};
+		let packet = StreamEvent::Packet {
+			addr,
+			via: via.clone(),
+			data,
+		};
+		if shard.send(packet).await.is_err() {
+			break Ok(());
+		}
+	};
+	// The writer finishes once the shard has let go of the connection (and so of its other Vias) and whatever it
+	// queued, like a final error response, has been written.  A client that stopped reading doesn't get to hold it up.
+	let _ = shard.send(StreamEvent::Closed { addr }).await;
+	drop(via);
+	if timeout(FLUSH_TIMEOUT, &mut write).await.is_err() {
+		write.abort();
+	}
+	ret
+}
+
+// Read the next STUN message or ChannelData (without its padding) from a stream.  None at the end of the stream.
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<Vec<u8>>> {
+	let mut header = [0u8; 4];
+	match reader.read_exact(&mut header).await {
+		Ok(_) => {}
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	}
+	let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+	// The first two bits are 00 for STUN and 01 for ChannelData
+	let (len, padded) = match header[0] >> 6 {
+		0 => (20 + len, 20 + len),
+		1 => (4 + len, 4 + len.next_multiple_of(4)),
+		_ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not STUN or ChannelData")),
+	};
+	if padded > BUFF_LEN {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "frame is too large"));
+	}
+	let mut data = vec![0u8; padded];
+	data[..4].copy_from_slice(&header);
+	reader.read_exact(&mut data[4..]).await?;
+	data.truncate(len);
+	Ok(Some(data))
+}
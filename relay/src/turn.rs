@@ -1,46 +1,76 @@
-use eyre::eyre;
-use std::net::SocketAddr;
+use eyre::{bail, WrapErr};
+use peerid::PeerId;
+use std::{borrow::Borrow, net::SocketAddr};
 
+use crate::nonce::NonceCheck;
 use stun::{
-	attr::{AttrContext, Data, Error, StunAttr, StunAttrValue},
-	Stun, StunTyp,
+	attr::{AttrContext, Error, Integrity, IntegrityKey, StunAttr, StunAttrValue},
+	attrs::typed,
+	encoder::StunEncoder,
+	view::StunView,
+	StunTyp,
 };
 
+// `dst.src.token`, optionally behind a TURN REST API expiry: `<expiry>:dst.src.token`.  dst and src are PeerIds,
+// which are kept in their canonical form so that usernames for the same pairing always match.
 #[derive(Debug, Clone)]
 pub struct TurnUsername {
 	full: Box<str>,
+	// The other side of the pairing's name (src.dst.token), which forwarding looks up on every packet
+	reversed: Box<str>,
+	offset: usize,
 	len_1: usize,
 	len_2: usize,
 	len_3: usize
 }
 impl TurnUsername {
 	pub fn dst(&self) -> &str {
-		&self.full[0..self.len_1]
+		&self.full[self.offset..][..self.len_1]
 	}
 	pub fn src(&self) -> &str {
-		&self.full[self.len_1 + 1..][..self.len_2]
+		&self.full[self.offset + self.len_1 + 1..][..self.len_2]
 	}
 	pub fn token(&self) -> &str {
-		&self.full[self.len_1 + 1 + self.len_2 + 1..][..self.len_3]
+		&self.full[self.offset + self.len_1 + 1 + self.len_2 + 1..][..self.len_3]
+	}
+	// The username without the expiry: dst.src.token
+	pub fn name(&self) -> &str {
+		&self.full[self.offset..]
+	}
+	pub fn reversed(&self) -> &str {
+		&self.reversed
 	}
 }
 impl TryFrom<&str> for TurnUsername {
 	type Error = eyre::Report;
 	fn try_from(value: &str) -> Result<Self, Self::Error> {
-		let mut split = value.split(".");
-		let dst = split.next().ok_or(eyre!("too short"))?;
-		let src = split.next().ok_or(eyre!("too short"))?;
-		let token = split.next().ok_or(eyre!("too short"))?;
-		if dst.is_empty() || src.is_empty() || token.is_empty() {
-			Err(eyre!("too empty"))
-		} else {
-			Ok(Self {
-				full: value.to_string().into_boxed_str(),
-				len_1: dst.len(),
-				len_2: src.len(),
-				len_3: token.len(),
-			})
+		let (expiry, name) = match value.split_once(':') {
+			Some((expiry, name)) if !expiry.is_empty() && expiry.bytes().all(|b| b.is_ascii_digit()) => {
+				(Some(expiry), name)
+			}
+			_ => (None, value),
+		};
+		let mut split = name.split(".");
+		let (Some(dst), Some(src), Some(token), None) = (split.next(), split.next(), split.next(), split.next()) else {
+			bail!("not dst.src.token");
+		};
+		if token.is_empty() {
+			bail!("empty token");
 		}
+		let dst = dst.parse::<PeerId>().wrap_err("dst isn't a PeerId")?.to_string();
+		let src = src.parse::<PeerId>().wrap_err("src isn't a PeerId")?.to_string();
+		let (full, offset) = match expiry {
+			Some(expiry) => (format!("{expiry}:{dst}.{src}.{token}"), expiry.len() + 1),
+			None => (format!("{dst}.{src}.{token}"), 0),
+		};
+		Ok(Self {
+			full: full.into_boxed_str(),
+			reversed: format!("{src}.{dst}.{token}").into_boxed_str(),
+			offset,
+			len_1: dst.len(),
+			len_2: src.len(),
+			len_3: token.len(),
+		})
 	}
 }
 impl AsRef<str> for TurnUsername {
@@ -56,7 +86,6 @@ pub enum TurnReq<'i> {
 		data: &'i [u8],
 	},
 	Send {
-		txid: [u8; 12],
 		xpeer: SocketAddr,
 		data: &'i [u8],
 	},
@@ -65,41 +94,60 @@ pub enum TurnReq<'i> {
 	},
 	AllocateNoAuth {
 		txid: [u8; 12],
+		// The request had credentials, but they didn't check out
+		unauthorized: bool,
+	},
+	StaleNonce {
+		txid: [u8; 12],
+		method: u16,
 	},
 	Allocate {
 		txid: [u8; 12],
 		username: &'i str,
-		key_data: [u8; 16],
+		key: IntegrityKey,
 		requested_transport: u8,
 		// dont_fragment, even_port, reservation_token
 	},
 	Permission {
 		txid: [u8; 12],
-		username: &'i str,
-		key_data: [u8; 16],
+		key: IntegrityKey,
 		xpeer: SocketAddr,
 	},
 	Refresh {
 		txid: [u8; 12],
 		username: &'i str,
-		key_data: [u8; 16],
+		key: IntegrityKey,
 		lifetime: u32,
 	},
 	BindChannel {
 		txid: [u8; 12],
-		username: &'i str,
-		key_data: [u8; 16],
+		key: IntegrityKey,
 		channel: u16,
 		xpeer: SocketAddr,
 	},
 }
+// Why a packet didn't decode into a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+	// Not STUN or ChannelData, or missing attributes that the request needs
+	Malformed,
+	// The message integrity didn't verify with any of the username's keys
+	Unauthorized,
+	// Something that we don't handle (responses, other methods, unauthenticated requests that need auth, etc.)
+	Unsupported,
+}
+
 impl<'i> TurnReq<'i> {
-	pub fn decode<F: FnOnce(&str, Option<&str>) -> Option<[u8; 16]>>(
-		buff: &'i [u8],
-		f: F,
-	) -> Option<Self> {
+	pub fn decode<F, I, K, N>(buff: &'i [u8], f: F, n: N) -> Result<Self, Rejected>
+	where
+		F: FnOnce(&str, Option<&str>) -> I,
+		I: IntoIterator<Item = K>,
+		K: Borrow<IntegrityKey>,
+		N: FnOnce(&str) -> NonceCheck,
+	{
+		use Rejected::*;
 		if buff.len() < 4 {
-			return None;
+			return Err(Malformed);
 		}
 		let typ = u16::from_be_bytes((&buff[0..][..2]).try_into().unwrap());
 		let length = u16::from_be_bytes((&buff[2..][..2]).try_into().unwrap());
@@ -107,73 +155,85 @@ impl<'i> TurnReq<'i> {
 			// ChannelData:
 			0x4000..=0x7fff => {
 				if buff.len() < (4 + length as usize) {
-					return None;
+					return Err(Malformed);
 				}
-				Some(Self::Channel {
+				Ok(Self::Channel {
 					channel: typ,
 					data: &buff[4..][..length as usize],
 				})
 			}
 			// Stun:
 			0..=0x3fff => {
-				let msg = Stun::decode(buff).ok()?;
-				let txid = msg.txid;
-				let flat = msg.flat();
-				let auth = flat.check_auth(f);
-				Some(match (&msg.typ, auth) {
+				let msg = StunView::decode(buff).map_err(|_| Malformed)?;
+				msg.try_get::<typed::Fingerprint>().map_err(|_| Malformed)?;
+				let txid = msg.txid();
+				let typ = msg.typ();
+				// Only a nonce that we issued lets a request authenticate, and like RFC 8489 section 9.2.4 we only
+				// answer 438 Stale Nonce once the message integrity has checked out:
+				let mut unauthorized = false;
+				let auth = match msg.get::<typed::Nonce>().map(n) {
+					Some(check @ (NonceCheck::Valid | NonceCheck::Stale)) => {
+						let auth = msg.check_auth(f).map(|(username, key)| (username, key.borrow().clone()));
+						unauthorized = auth.is_none() && msg.has::<typed::Integrity>();
+						match typ {
+							_ if check == NonceCheck::Valid => auth,
+							StunTyp::Req(method @ (0x003 | 0x004 | 0x008 | 0x009)) if auth.is_some() => {
+								return Ok(Self::StaleNonce { txid, method })
+							}
+							_ => None,
+						}
+					}
+					_ => None,
+				};
+				Ok(match (&typ, auth) {
 					(StunTyp::Req(0x001), _) => Self::Binding { txid },
-					(StunTyp::Req(0x003), None) => Self::AllocateNoAuth { txid },
-					(StunTyp::Req(0x003), Some((username, key_data))) => Self::Allocate {
+					(StunTyp::Req(0x003), None) => Self::AllocateNoAuth { txid, unauthorized },
+					(StunTyp::Req(0x003), Some((username, key))) => Self::Allocate {
 						txid,
 						username,
-						key_data,
-						requested_transport: flat.requested_transport?,
+						key,
+						requested_transport: msg.get::<typed::RequestedTransport>().ok_or(Malformed)?.0,
 					},
-					(StunTyp::Req(0x008), Some((username, key_data))) => Self::Permission {
+					(StunTyp::Req(0x008), Some((_, key))) => Self::Permission {
 						txid,
-						username,
-						key_data,
-						xpeer: flat.xpeer?,
+						key,
+						xpeer: msg.get::<typed::XPeer>().ok_or(Malformed)?,
 					},
-					(StunTyp::Req(0x004), Some((username, key_data))) => Self::Refresh {
+					(StunTyp::Req(0x004), Some((username, key))) => Self::Refresh {
 						txid,
 						username,
-						key_data,
-						lifetime: flat.lifetime.unwrap_or(3600),
+						key,
+						lifetime: msg.get::<typed::Lifetime>().unwrap_or(3600),
 					},
-					(StunTyp::Req(0x009), Some((username, key_data))) => Self::BindChannel {
+					(StunTyp::Req(0x009), Some((_, key))) => Self::BindChannel {
 						txid,
-						username,
-						key_data,
-						channel: flat.channel?,
-						xpeer: flat.xpeer?,
+						key,
+						channel: msg.get::<typed::Channel>().ok_or(Malformed)?.into(),
+						xpeer: msg.get::<typed::XPeer>().ok_or(Malformed)?,
 					},
 					(StunTyp::Ind(0x006), None) => Self::Send {
-						txid,
-						xpeer: flat.xpeer?,
-						data: flat.data?,
+						xpeer: msg.get::<typed::XPeer>().ok_or(Malformed)?,
+						data: msg.get::<typed::Data>().ok_or(Malformed)?,
 					},
-					_ => {
-						return None;
-					}
+					_ if unauthorized => return Err(Unauthorized),
+					_ => return Err(Unsupported),
 				})
 			}
-			_ => None,
+			_ => Err(Malformed),
 		}
 	}
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum TurnRes<'i> {
-	#[allow(unused)]
 	Channel {
 		channel: u16,
-		data: Data<'i>,
+		data: &'i (dyn StunAttrValue<'i> + Sync),
 	},
 	Data {
 		txid: [u8; 12],
 		xpeer: SocketAddr,
-		data: Data<'i>,
+		data: &'i (dyn StunAttrValue<'i> + Sync),
 	},
 	BindingRes {
 		txid: [u8; 12],
@@ -184,33 +244,56 @@ pub enum TurnRes<'i> {
 		realm: &'i str,
 		nonce: &'i str,
 	},
+	StaleNonce {
+		txid: [u8; 12],
+		method: u16,
+		realm: &'i str,
+		nonce: &'i str,
+	},
 	AllocateSuc {
 		txid: [u8; 12],
-		key_data: [u8; 16],
+		key: IntegrityKey,
 		xmapped: SocketAddr,
 		xrelayed: SocketAddr,
 		lifetime: u32,
 	},
 	AllocateMismatch {
 		txid: [u8; 12],
-		key_data: [u8; 16],
+		key: IntegrityKey,
+	},
+	AllocateCapacity {
+		txid: [u8; 12],
+		key: IntegrityKey,
+	},
+	AllocateTransport {
+		txid: [u8; 12],
+		key: IntegrityKey,
+	},
+	AllocateQuota {
+		txid: [u8; 12],
+		key: IntegrityKey,
 	},
 	PermissionSuc {
 		txid: [u8; 12],
-		key_data: [u8; 16],
+		key: IntegrityKey,
 	},
 	RefreshSuc {
 		txid: [u8; 12],
-		key_data: [u8; 16],
+		key: IntegrityKey,
 		lifetime: u32,
 	},
 	RefreshKick {
 		txid: [u8; 12],
-		key_data: [u8; 16]
+		key: IntegrityKey
 	},
 	BindChannelSuc {
 		txid: [u8; 12],
-		key_data: [u8; 16],
+		key: IntegrityKey,
+	},
+	BadRequest {
+		txid: [u8; 12],
+		method: u16,
+		key: IntegrityKey,
 	},
 }
 impl<'i> TurnRes<'i> {
@@ -225,7 +308,7 @@ impl<'i> TurnRes<'i> {
 				buff[0..][..2].copy_from_slice(&channel.to_be_bytes());
 				buff[2..][..2].copy_from_slice(&length.to_be_bytes());
 				data.encode(
-					&mut buff[4..],
+					&mut buff[4..][..length as usize],
 					AttrContext {
 						header: &[0u8; 20],
 						zero_xor_bytes: false,
@@ -233,144 +316,279 @@ impl<'i> TurnRes<'i> {
 						attr_len: 0,
 					},
 				);
-				return Some(len);
+				Some(len)
 			}
 			Self::Data { txid, xpeer, data } => {
-				Stun {
-					typ: StunTyp::Ind(0x007),
-					txid,
-					attrs: vec![
-						StunAttr::XPeer(xpeer),
-						StunAttr::Data(data),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
-			}
-			Self::BindingRes { txid, xmapped } => {
-				Stun {
-					typ: StunTyp::Res(0x001),
-					txid,
-					attrs: vec![StunAttr::XMapped(xmapped), StunAttr::Fingerprint]
-				}
-				.encode(buff)
-			}
-			Self::AllocateUseAuth { txid, realm, nonce } => {
-				Stun {
-					typ: StunTyp::Err(0x003),
-					txid,
-					attrs: vec![
-						StunAttr::Error(Error {
-							code: 401,
-							message: "",
-						}),
-						StunAttr::Realm(realm),
-						StunAttr::Nonce(nonce),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
+				let mut enc = StunEncoder::new(buff, &StunTyp::Ind(0x007), &txid)?;
+				enc.push(&StunAttr::XPeer(xpeer))?
+					.push_value(0x0013, data)?
+					.push(&StunAttr::Fingerprint)?;
+				Some(enc.finish())
 			}
+			Self::BindingRes { txid, xmapped } => StunEncoder::encode(
+				buff,
+				&StunTyp::Res(0x001),
+				&txid,
+				&[StunAttr::XMapped(xmapped), StunAttr::Fingerprint],
+			),
+			Self::AllocateUseAuth { txid, realm, nonce } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(0x003),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 401,
+						message: "",
+					}),
+					StunAttr::Realm(realm),
+					StunAttr::Nonce(nonce),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::StaleNonce {
+				txid,
+				method,
+				realm,
+				nonce,
+			} => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(method),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 438,
+						message: "Stale Nonce",
+					}),
+					StunAttr::Realm(realm),
+					StunAttr::Nonce(nonce),
+					StunAttr::Fingerprint,
+				],
+			),
 			Self::AllocateSuc {
 				txid,
-				key_data,
+				key,
 				xmapped,
 				xrelayed,
 				lifetime,
-			} => {
-				Stun {
-					typ: StunTyp::Res(0x003),
-					txid,
-					attrs: vec![
-						StunAttr::XMapped(xmapped),
-						StunAttr::XRelayed(xrelayed),
-						StunAttr::Lifetime(lifetime),
-						StunAttr::Integrity(stun::attr::Integrity::Set {
-							key_data: &key_data,
-						}),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
-			}
-			Self::AllocateMismatch { txid, key_data } => {
-				Stun {
-					typ: StunTyp::Err(0x003),
-					txid,
-					attrs: vec![
-						StunAttr::Error(Error {
-							code: 437,
-							message: "",
-						}),
-						StunAttr::Integrity(stun::attr::Integrity::Set {
-							key_data: &key_data,
-						}),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
-			}
-			Self::PermissionSuc { txid, key_data } => {
-				Stun {
-					typ: StunTyp::Res(0x008),
-					txid,
-					attrs: vec![
-						StunAttr::Integrity(stun::attr::Integrity::Set {
-							key_data: &key_data,
-						}),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
-			}
+			} => StunEncoder::encode(
+				buff,
+				&StunTyp::Res(0x003),
+				&txid,
+				&[
+					StunAttr::XMapped(xmapped),
+					StunAttr::XRelayed(xrelayed),
+					StunAttr::Lifetime(lifetime),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::AllocateMismatch { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(0x003),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 437,
+						message: "",
+					}),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::AllocateCapacity { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(0x003),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 508,
+						message: "Insufficient Capacity",
+					}),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::AllocateTransport { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(0x003),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 442,
+						message: "Unsupported Transport Protocol",
+					}),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::AllocateQuota { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(0x003),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 486,
+						message: "Allocation Quota Reached",
+					}),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::PermissionSuc { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Res(0x008),
+				&txid,
+				&[
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
 			Self::RefreshSuc {
 				txid,
-				key_data,
+				key,
 				lifetime,
-			} => {
-				Stun {
-					typ: StunTyp::Res(0x004),
-					txid,
-					attrs: vec![
-						StunAttr::Lifetime(lifetime),
-						StunAttr::Integrity(stun::attr::Integrity::Set {
-							key_data: &key_data,
-						}),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
-			}
-			Self::RefreshKick {
-				txid,
-				key_data
-			} => {
-				Stun {
-					typ: StunTyp::Err(0x004),
-					txid,
-					attrs: vec![
-						StunAttr::Error(Error { code: 500, message: "Get kicked!" }),
-						StunAttr::Integrity(stun::attr::Integrity::Set {
-							key_data: &key_data,
-						}),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
-			}
-			Self::BindChannelSuc { txid, key_data } => {
-				Stun {
-					typ: StunTyp::Res(0x009),
-					txid,
-					attrs: vec![
-						StunAttr::Integrity(stun::attr::Integrity::Set {
-							key_data: &key_data,
-						}),
-						StunAttr::Fingerprint,
-					]
-				}
-				.encode(buff)
+			} => StunEncoder::encode(
+				buff,
+				&StunTyp::Res(0x004),
+				&txid,
+				&[
+					StunAttr::Lifetime(lifetime),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::RefreshKick { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(0x004),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 500,
+						message: "Get kicked!",
+					}),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::BindChannelSuc { txid, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Res(0x009),
+				&txid,
+				&[
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+			Self::BadRequest { txid, method, key } => StunEncoder::encode(
+				buff,
+				&StunTyp::Err(method),
+				&txid,
+				&[
+					StunAttr::Error(Error {
+						code: 400,
+						message: "Bad Request",
+					}),
+					StunAttr::Integrity(Integrity::Key(&key)),
+					StunAttr::Fingerprint,
+				],
+			),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn decode(buff: &[u8]) -> Result<TurnReq<'_>, Rejected> {
+		TurnReq::decode(buff, |_, _| None::<IntegrityKey>, |_| NonceCheck::Invalid)
+	}
+
+	const A: &str = "wDllzbRpwuC2iAW6Stk2wQZtYToScc3IQhVPpCjkiUs";
+	const B: &str = "gckU8soOEqYjH2nUkmW3JMtERbUfNZXErmnvxT5BT1I";
+
+	#[test]
+	fn usernames() {
+		let username = TurnUsername::try_from(format!("{A}.{B}.token").as_str()).unwrap();
+		assert_eq!((username.dst(), username.src(), username.token()), (A, B, "token"));
+		assert_eq!(username.name(), format!("{A}.{B}.token"));
+		assert_eq!(username.reversed(), format!("{B}.{A}.token"));
+		assert_eq!(username.as_ref(), format!("{A}.{B}.token"));
+
+		// The expiry is kept in the username (it's what the credentials were issued for), but not in the name
+		let username = TurnUsername::try_from(format!("1700000000:{A}.{B}.to:ken").as_str()).unwrap();
+		assert_eq!((username.dst(), username.src(), username.token()), (A, B, "to:ken"));
+		assert_eq!(username.name(), format!("{A}.{B}.to:ken"));
+		assert_eq!(username.as_ref(), format!("1700000000:{A}.{B}.to:ken"));
+	}
+
+	#[test]
+	fn canonical_usernames() {
+		// Tagged, percent encoded and multi-fingerprint PeerIds all come out as the untagged id
+		let sha1 = "qUqP5cyxm6YcTAhz05Hph5gvu9M";
+		for (dst, src) in [
+			(format!("{A}|sha-256"), B.to_string()),
+			(A.to_string(), format!("{B}%7Csha-256")),
+			(format!("{A},{sha1}"), format!("{sha1}|sha-1,{B}|sha-256")),
+		] {
+			let username = TurnUsername::try_from(format!("{dst}.{src}.token").as_str()).unwrap();
+			assert_eq!((username.dst(), username.src()), (A, B), "{dst}.{src}");
+			assert_eq!(username.reversed(), format!("{B}.{A}.token"));
+		}
+		let username = TurnUsername::try_from(format!("42:{A}|sha-256.{B}.token").as_str()).unwrap();
+		assert_eq!(username.as_ref(), format!("42:{A}.{B}.token"));
+	}
+
+	#[test]
+	fn malformed_usernames() {
+		for username in [
+			String::new(),
+			"user".into(),
+			"1700000000:user".into(),
+			format!("{A}.{B}"),
+			format!("{A}.{B}."),
+			format!("{A}.{B}.token.more"),
+			format!("{A}.nonsense.token"),
+			format!("{A}|md5.{B}.token"),
+			format!("{A}.{B}%ZZ.token"),
+			format!("{}.{B}.token", &A[1..]),
+		] {
+			assert!(TurnUsername::try_from(username.as_str()).is_err(), "{username}");
+		}
+	}
+
+	#[test]
+	fn channel_data_range() {
+		for channel in [0x4000u16, 0x5000, 0x7FFF] {
+			let mut buff = [0u8; 8];
+			buff[..2].copy_from_slice(&channel.to_be_bytes());
+			buff[2..4].copy_from_slice(&3u16.to_be_bytes());
+			buff[4..7].copy_from_slice(b"abc");
+			match decode(&buff) {
+				Ok(TurnReq::Channel { channel: c, data }) => assert_eq!((c, data), (channel, &b"abc"[..])),
+				other => panic!("{channel:#x}: {other:?}"),
 			}
 		}
+		for channel in [0x8000u16, 0xBFFF, 0xFFFF] {
+			let mut buff = [0u8; 8];
+			buff[..2].copy_from_slice(&channel.to_be_bytes());
+			assert!(matches!(decode(&buff), Err(Rejected::Malformed)), "{channel:#x}");
+		}
+		// Shorter than its length says
+		assert!(matches!(decode(&[0x40, 0x00, 0x00, 0x08, 1, 2, 3]), Err(Rejected::Malformed)));
+	}
+
+	#[test]
+	fn channel_data_encode() {
+		let mut buff = [0u8; 16];
+		let data: &[u8] = b"hello";
+		let len = TurnRes::Channel { channel: 0x4001, data: &data }.encode(&mut buff).unwrap();
+		assert_eq!(&buff[..len], b"\x40\x01\x00\x05hello");
+		match decode(&buff[..len]) {
+			Ok(TurnReq::Channel { channel, data }) => assert_eq!((channel, data), (0x4001, &b"hello"[..])),
+			other => panic!("{other:?}"),
+		}
+		// Doesn't fit
+		assert_eq!(TurnRes::Channel { channel: 0x4001, data: &data }.encode(&mut buff[..8]), None);
 	}
 }
@@ -1,8 +1,11 @@
 use std::net::SocketAddr;
 
 use stun::{
-	attr::{Data, Error, Integrity, StunAttr},
-	Stun, StunTyp,
+	attr::{AttrContext, Error, Integrity, StunAttr, StunAttrDecodeErr, StunAttrValue},
+	attrs::typed,
+	encoder::StunEncoder,
+	view::StunView,
+	StunTyp,
 };
 
 #[derive(Debug, Clone)]
@@ -32,30 +35,34 @@ pub enum WebRTC<'i> {
 }
 impl<'i> WebRTC<'i> {
 	pub fn decode(buff: &'i [u8]) -> Option<Self> {
-		let first_byte = buff.get(0)?;
+		let first_byte = buff.first()?;
 		Some(match first_byte {
 			0..=3 => {
-				let msg = Stun::decode(buff).ok()?;
-				let flat = msg.flat();
-				match msg.typ {
-					StunTyp::Req(0x001) => Self::IceReq {
-						txid: msg.txid,
-						integrity: flat.integrity?,
-						username: flat.username?,
-						priority: flat.priority?,
-						tie_breaker: flat.ice_controlling.or(flat.ice_controlled)?,
-						is_controlling: flat.ice_controlling.is_some(),
-						use_candidate: flat.use_candidate.is_some(),
-					},
+				let msg = StunView::decode(buff).ok()?;
+				msg.try_get::<typed::Fingerprint>().ok()?;
+				let txid = msg.txid();
+				match msg.typ() {
+					StunTyp::Req(0x001) => {
+						let ice_controlling = msg.get::<typed::IceControlling>();
+						Self::IceReq {
+							txid,
+							integrity: msg.get::<typed::Integrity>()?,
+							username: msg.get::<typed::Username>()?,
+							priority: msg.get::<typed::Priority>()?,
+							tie_breaker: ice_controlling.or_else(|| msg.get::<typed::IceControlled>())?,
+							is_controlling: ice_controlling.is_some(),
+							use_candidate: msg.has::<typed::UseCandidate>(),
+						}
+					}
 					StunTyp::Res(0x001) => Self::IceRes {
-						txid: msg.txid,
-						xmapped: flat.xmapped?,
-						integrity: flat.integrity?,
+						txid,
+						xmapped: msg.get::<typed::XMapped>()?,
+						integrity: msg.get::<typed::Integrity>()?,
 					},
 					StunTyp::Err(0x001) => Self::IceErr {
-						txid: msg.txid,
-						integrity: flat.integrity?,
-						error: flat.error?,
+						txid,
+						integrity: msg.get::<typed::Integrity>()?,
+						error: msg.get::<typed::Error>()?,
 					},
 					_ => return None,
 				}
@@ -67,10 +74,20 @@ impl<'i> WebRTC<'i> {
 			}
 		})
 	}
-	pub fn encode(&self) -> Data<'i> {
+	// Encode the packet on its own (rather than as the data of a TURN message), e.g. to answer a check directly.
+	pub fn encode_packet(&self, buff: &mut [u8]) -> Option<usize> {
 		match self {
-			Self::Dtls(b) => Data::Slice(b),
-			Self::Rtp(b) => Data::Slice(b),
+			Self::Dtls(b) | Self::Rtp(b) => {
+				buff.get_mut(..b.len())?.copy_from_slice(b);
+				Some(b.len())
+			}
+			_ => self.with_msg(|typ, txid, attrs| StunEncoder::encode(buff, &typ, txid, attrs))?,
+		}
+	}
+	// Calls f with the STUN message for ICE packets, the attributes live on the stack.
+	fn with_msg<R>(&self, f: impl FnOnce(StunTyp, &[u8; 12], &[StunAttr<'_>]) -> R) -> Option<R> {
+		Some(match self {
+			Self::Dtls(_) | Self::Rtp(_) => return None,
 			Self::IceReq {
 				txid,
 				integrity,
@@ -85,51 +102,64 @@ impl<'i> WebRTC<'i> {
 				} else {
 					StunAttr::IceControlled(*tie_breaker)
 				};
-				let typ = StunTyp::Req(0x001);
-				let mut attrs = vec![
+				let attrs = [
+					StunAttr::UseCandidate,
 					StunAttr::Username(username),
 					ice_cont,
 					StunAttr::Priority(*priority),
 					StunAttr::Integrity(integrity.clone()),
-					StunAttr::Fingerprint
+					StunAttr::Fingerprint,
 				];
-				if *use_candidate { attrs.insert(2, StunAttr::UseCandidate) }
-				Data::Nested(Stun {
-					typ,
-					txid: txid.clone(),
-					attrs
-				})
+				// Skip the UseCandidate if it isn't set:
+				f(StunTyp::Req(0x001), txid, &attrs[!use_candidate as usize..])
 			}
 			Self::IceRes {
 				txid,
 				xmapped,
 				integrity,
-			} => {
-				Data::Nested(Stun {
-					typ: StunTyp::Res(0x001), 
-					txid: txid.clone(), 
-					attrs: vec![
-						StunAttr::XMapped(*xmapped),
-						StunAttr::Integrity(integrity.clone()),
-						StunAttr::Fingerprint
-					]
-				})
-			}
+			} => f(
+				StunTyp::Res(0x001),
+				txid,
+				&[
+					StunAttr::XMapped(*xmapped),
+					StunAttr::Integrity(integrity.clone()),
+					StunAttr::Fingerprint,
+				],
+			),
 			Self::IceErr {
 				txid,
 				integrity,
 				error,
-			} => {
-				Data::Nested(Stun {
-					typ: StunTyp::Err(0x001),
-					txid: txid.clone(),
-					attrs: vec![
-						StunAttr::Error(error.clone()),
-						StunAttr::Integrity(integrity.clone()),
-						StunAttr::Fingerprint
-					]
-				})
+			} => f(
+				StunTyp::Err(0x001),
+				txid,
+				&[
+					StunAttr::Error(error.clone()),
+					StunAttr::Integrity(integrity.clone()),
+					StunAttr::Fingerprint,
+				],
+			),
+		})
+	}
+}
+impl<'i> StunAttrValue<'i> for WebRTC<'i> {
+	fn length(&self) -> u16 {
+		match self {
+			Self::Dtls(b) | Self::Rtp(b) => b.len() as u16,
+			_ => self
+				.with_msg(|_, _, attrs| 20 + StunEncoder::length(attrs))
+				.unwrap(),
+		}
+	}
+	fn encode(&self, buff: &mut [u8], _: AttrContext<'_>) {
+		match self {
+			Self::Dtls(b) | Self::Rtp(b) => buff.copy_from_slice(b),
+			_ => {
+				self.with_msg(|typ, txid, attrs| StunEncoder::encode(buff, &typ, txid, attrs));
 			}
 		}
 	}
+	fn decode(buff: &'i [u8], _: AttrContext<'i>) -> Result<Self, StunAttrDecodeErr> {
+		Self::decode(buff).ok_or(StunAttrDecodeErr::BadValue)
+	}
 }
@@ -0,0 +1,83 @@
+use std::{
+	hint::black_box,
+	net::SocketAddr,
+	time::{Duration, Instant},
+};
+
+use stun::{
+	attr::{Integrity, StunAttr},
+	encoder::StunEncoder,
+	Stun, StunTyp,
+};
+
+const ITERS: u32 = 1_000_000;
+
+fn bench(name: &str, mut f: impl FnMut()) -> Duration {
+	// Warm up:
+	for _ in 0..ITERS / 10 {
+		f();
+	}
+	let start = Instant::now();
+	for _ in 0..ITERS {
+		f();
+	}
+	let elapsed = start.elapsed();
+	println!("{name:>24}: {:>6.1} ns/msg", elapsed.as_nanos() as f64 / ITERS as f64);
+	elapsed
+}
+
+fn main() {
+	let mut buff = [0u8; 4096];
+	let txid = [7u8; 12];
+	let key_data = [3u8; 16];
+	let addr: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+
+	// Binding responses are the hot path: no HMAC, so the allocation is a large part of the cost.
+	let vec = bench("binding: Vec<StunAttr>", || {
+		let msg = Stun {
+			typ: StunTyp::Res(0x001),
+			txid: black_box(txid),
+			attrs: vec![StunAttr::XMapped(addr), StunAttr::Fingerprint],
+		};
+		black_box(msg.encode(&mut buff));
+	});
+	let stack = bench("binding: StunEncoder", || {
+		black_box(StunEncoder::encode(
+			&mut buff,
+			&StunTyp::Res(0x001),
+			&black_box(txid),
+			&[StunAttr::XMapped(addr), StunAttr::Fingerprint],
+		));
+	});
+	println!("speedup: {:.2}x\n", vec.as_secs_f64() / stack.as_secs_f64());
+
+	let vec = bench("allocate: Vec<StunAttr>", || {
+		let msg = Stun {
+			typ: StunTyp::Res(0x003),
+			txid: black_box(txid),
+			attrs: vec![
+				StunAttr::XMapped(addr),
+				StunAttr::XRelayed(addr),
+				StunAttr::Lifetime(600),
+				StunAttr::Integrity(Integrity::Set { key_data: &key_data }),
+				StunAttr::Fingerprint,
+			],
+		};
+		black_box(msg.encode(&mut buff));
+	});
+	let stack = bench("allocate: StunEncoder", || {
+		black_box(StunEncoder::encode(
+			&mut buff,
+			&StunTyp::Res(0x003),
+			&black_box(txid),
+			&[
+				StunAttr::XMapped(addr),
+				StunAttr::XRelayed(addr),
+				StunAttr::Lifetime(600),
+				StunAttr::Integrity(Integrity::Set { key_data: &key_data }),
+				StunAttr::Fingerprint,
+			],
+		));
+	});
+	println!("speedup: {:.2}x", vec.as_secs_f64() / stack.as_secs_f64());
+}
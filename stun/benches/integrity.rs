@@ -0,0 +1,71 @@
+use std::{
+	hint::black_box,
+	time::{Duration, Instant},
+};
+
+use stun::{
+	attr::{Integrity, IntegrityKey, StunAttr},
+	attrs::typed,
+	encoder::StunEncoder,
+	view::StunView,
+	StunTyp,
+};
+
+const ITERS: u32 = 1_000_000;
+
+fn bench(name: &str, mut f: impl FnMut()) -> Duration {
+	// Warm up:
+	for _ in 0..ITERS / 10 {
+		f();
+	}
+	let start = Instant::now();
+	for _ in 0..ITERS {
+		f();
+	}
+	let elapsed = start.elapsed();
+	println!("{name:>24}: {:>6.1} ns/msg", elapsed.as_nanos() as f64 / ITERS as f64);
+	elapsed
+}
+
+fn main() {
+	let mut buff = [0u8; 4096];
+	let txid = [7u8; 12];
+	let ice_pwd = b"the/ice/password/constant";
+	let key = IntegrityKey::new(ice_pwd);
+
+	// A typical connectivity check:
+	let sign = |buff: &mut [u8], integrity: Integrity<'_>| {
+		StunEncoder::encode(
+			buff,
+			&StunTyp::Req(0x001),
+			&black_box(txid),
+			&[
+				StunAttr::Username("remote_ufrag:local_ufrag"),
+				StunAttr::IceControlling(0x1234_5678_9abc_def0),
+				StunAttr::Priority(1),
+				StunAttr::Integrity(integrity),
+				StunAttr::Fingerprint,
+			],
+		)
+	};
+
+	let set = bench("sign: key_data", || {
+		black_box(sign(&mut buff, Integrity::Set { key_data: ice_pwd }));
+	});
+	let cached = bench("sign: IntegrityKey", || {
+		black_box(sign(&mut buff, Integrity::Key(&key)));
+	});
+	println!("speedup: {:.2}x\n", set.as_secs_f64() / cached.as_secs_f64());
+
+	let len = sign(&mut buff, Integrity::Key(&key)).unwrap();
+	let msg = StunView::decode(&buff[..len]).unwrap();
+	let integrity = msg.get::<typed::Integrity>().unwrap();
+
+	let set = bench("verify: key_data", || {
+		assert!(black_box(&integrity).verify(ice_pwd));
+	});
+	let cached = bench("verify: IntegrityKey", || {
+		assert!(black_box(&integrity).verify_key(&key));
+	});
+	println!("speedup: {:.2}x", set.as_secs_f64() / cached.as_secs_f64());
+}
@@ -15,6 +15,7 @@ pub enum StunAttrDecodeErr {
 	BadUtf8(Utf8Error),
 	UnexpectedLength(TryFromSliceError),
 	BadFingerprint,
+	BadValue,
 }
 impl From<Utf8Error> for StunAttrDecodeErr {
 	fn from(value: Utf8Error) -> Self {
@@ -126,7 +127,7 @@ impl StunAttrValue<'_> for () {
 	}
 	fn encode(&self, _: &mut [u8], _: AttrContext<'_>) {}
 	fn decode(buff: &[u8], _: AttrContext<'_>) -> Result<Self, StunAttrDecodeErr> {
-		if buff.len() == 0 {
+		if buff.is_empty() {
 			Ok(())
 		} else {
 			Err(StunAttrDecodeErr::ValueUnexpectedLength)
@@ -242,7 +243,7 @@ impl<'i> StunAttrValue<'i> for UnknownAttributes<'i> {
 		}
 	}
 	fn decode(buff: &'i [u8], _: AttrContext<'_>) -> Result<Self, StunAttrDecodeErr> {
-		if buff.len() % 2 != 0 {
+		if !buff.len().is_multiple_of(2) {
 			Err(StunAttrDecodeErr::ValueUnexpectedLength)
 		} else {
 			Ok(Self::Parse(buff))
@@ -354,6 +355,29 @@ impl StunAttrValue<'_> for Fingerprint {
 		actual.encode(buff, ctx)
 	}
 }
+// The HMAC state after absorbing the key, so that it doesn't have to be recomputed for every message.
+#[derive(Clone)]
+pub struct IntegrityKey(hmac::Hmac<Sha1>);
+impl IntegrityKey {
+	pub fn new(key_data: &[u8]) -> Self {
+		Self(hmac::Hmac::<Sha1>::new_from_slice(key_data).expect("bad key_data"))
+	}
+	fn mac(&self, ctx: &AttrContext<'_>) -> hmac::Hmac<Sha1> {
+		let mut hmac = self.0.clone();
+		ctx.reduce_over_prefix(|buf| hmac.update(buf));
+		hmac
+	}
+	// Two keys are the same if they produce the same MAC (over an empty message).
+	fn matches(&self, other: &Self) -> bool {
+		self.0.clone().finalize() == other.0.clone().finalize()
+	}
+}
+impl std::fmt::Debug for IntegrityKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("IntegrityKey")
+	}
+}
+
 #[derive(Debug, Clone)]
 pub enum Integrity<'i> {
 	Check {
@@ -363,6 +387,7 @@ pub enum Integrity<'i> {
 	Set {
 		key_data: &'i [u8],
 	},
+	Key(&'i IntegrityKey),
 }
 impl<'i> Integrity<'i> {
 	pub fn verify(&self, key_data: &[u8]) -> bool {
@@ -370,12 +395,14 @@ impl<'i> Integrity<'i> {
 			Self::Set {
 				key_data: actual_key_data,
 			} => key_data == *actual_key_data,
-			Self::Check { val: actual, ctx } => {
-				let mut hmac = hmac::Hmac::<Sha1>::new_from_slice(key_data).expect("bad key_data");
-				ctx.reduce_over_prefix(|buf| hmac.update(buf));
-				let expected = hmac.finalize().into_bytes();
-				expected.as_slice() == actual.as_slice()
-			}
+			_ => self.verify_key(&IntegrityKey::new(key_data)),
+		}
+	}
+	pub fn verify_key(&self, key: &IntegrityKey) -> bool {
+		match self {
+			Self::Set { key_data } => IntegrityKey::new(key_data).matches(key),
+			Self::Key(actual) => actual.matches(key),
+			Self::Check { val: actual, ctx } => key.mac(ctx).verify_slice(actual.as_slice()).is_ok(),
 		}
 	}
 }
@@ -394,14 +421,9 @@ impl<'i> StunAttrValue<'i> for Integrity<'i> {
 		match self {
 			Self::Check { val, .. } => val.encode(buff, ctx),
 			Self::Set { key_data } => {
-				let mut hmac = hmac::Hmac::<Sha1>::new_from_slice(key_data)
-					.expect("Unable to create Hmac key");
-				ctx.reduce_over_prefix(|buf| hmac.update(buf));
-				let actual = hmac.finalize().into_bytes();
-				<&[u8; 20]>::try_from(actual.as_slice())
-					.unwrap()
-					.encode(buff, ctx);
+				buff.copy_from_slice(&IntegrityKey::new(key_data).mac(&ctx).finalize().into_bytes())
 			}
+			Self::Key(key) => buff.copy_from_slice(&key.mac(&ctx).finalize().into_bytes()),
 		}
 	}
 }
@@ -551,9 +573,10 @@ impl<'i> StunAttr<'i> {
 	pub fn length(&self) -> u16 {
 		self.value().length()
 	}
+	#[allow(clippy::len_without_is_empty)]
 	pub fn len(&self) -> u16 {
 		let mut ret = 4 + self.length();
-		while ret % 4 != 0 {
+		while !ret.is_multiple_of(4) {
 			ret += 1;
 		}
 		ret
@@ -563,7 +586,7 @@ impl<'i> StunAttr<'i> {
 		buff[2..][..2].copy_from_slice(&self.length().to_be_bytes());
 		let mut length = self.length();
 		self.value().encode(&mut buff[4..][..length as usize], ctx);
-		while length % 4 != 0 {
+		while !length.is_multiple_of(4) {
 			buff[4 + length as usize] = 0;
 			length += 1;
 		}
@@ -611,3 +634,95 @@ impl<'i> StunAttr<'i> {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{attrs::typed, encoder::StunEncoder, view::StunView, StunTyp};
+
+	// RFC 5769 section 2.1: a short-term credential Binding request
+	const REQUEST: [u8; 108] = [
+		0x00, 0x01, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+		0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x10, 0x53, 0x54, 0x55, 0x4e, 0x20, 0x74, 0x65, 0x73,
+		0x74, 0x20, 0x63, 0x6c, 0x69, 0x65, 0x6e, 0x74, 0x00, 0x24, 0x00, 0x04, 0x6e, 0x00, 0x01, 0xff,
+		0x80, 0x29, 0x00, 0x08, 0x93, 0x2f, 0xf9, 0xb1, 0x51, 0x26, 0x3b, 0x36, 0x00, 0x06, 0x00, 0x09,
+		0x65, 0x76, 0x74, 0x6a, 0x3a, 0x68, 0x36, 0x76, 0x59, 0x20, 0x20, 0x20, 0x00, 0x08, 0x00, 0x14,
+		0x9a, 0xea, 0xa7, 0x0c, 0xbf, 0xd8, 0xcb, 0x56, 0x78, 0x1e, 0xf2, 0xb5, 0xb2, 0xd3, 0xf2, 0x49,
+		0xc1, 0xb5, 0x71, 0xa2, 0x80, 0x28, 0x00, 0x04, 0xe5, 0x7a, 0x3b, 0xcf,
+	];
+	// Section 2.3: a response with an IPv6 XOR-MAPPED-ADDRESS, with the same password
+	const RESPONSE: [u8; 92] = [
+		0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+		0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+		0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa,
+		0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9, 0x00, 0x08, 0x00, 0x14,
+		0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9, 0x7c, 0x82, 0x92, 0xc2, 0x75,
+		0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb, 0x0b, 0x4c,
+	];
+	const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+	// Section 2.4: a long-term credential request from the user "マトリックス" in the realm "example.org"
+	const LONG_TERM: [u8; 116] = [
+		0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42, 0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72, 0xc0,
+		0x29, 0xda, 0x41, 0x2e, 0x00, 0x06, 0x00, 0x12, 0xe3, 0x83, 0x9e, 0xe3, 0x83, 0x88, 0xe3, 0x83,
+		0xaa, 0xe3, 0x83, 0x83, 0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9, 0x00, 0x00, 0x00, 0x15, 0x00, 0x1c,
+		0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39, 0x35, 0x34, 0x64, 0x36, 0x4f, 0x4c, 0x33, 0x34,
+		0x6f, 0x4c, 0x39, 0x46, 0x53, 0x54, 0x76, 0x79, 0x36, 0x34, 0x73, 0x41, 0x00, 0x14, 0x00, 0x0b,
+		0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72, 0x67, 0x00, 0x00, 0x08, 0x00, 0x14,
+		0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02, 0xb8, 0xe0, 0x71, 0x2e, 0x85, 0xc9, 0xa2,
+		0x8c, 0xa8, 0x96, 0x66,
+	];
+	// MD5("マトリックス:example.org:TheMatrIX"), with the username and password already SASLprep'd
+	const LONG_TERM_KEY: [u8; 16] = [
+		0xe8, 0xca, 0x7a, 0xd5, 0x9d, 0x5e, 0xb0, 0x51, 0x8e, 0x31, 0x29, 0x11, 0xd2, 0xda, 0xb2, 0xa9,
+	];
+
+	#[test]
+	fn short_term_vectors() {
+		let key = IntegrityKey::new(PASSWORD);
+		let wrong = IntegrityKey::new(b"VOkJxbRl1RmTxUk/WvJxBu");
+		for packet in [&REQUEST[..], &RESPONSE] {
+			let view = StunView::decode(packet).unwrap();
+			assert!(view.verify_key(&key));
+			assert!(!view.verify_key(&wrong));
+			assert!(view.try_get::<typed::Fingerprint>().unwrap().is_some());
+			let integrity = view.get::<typed::Integrity>().unwrap();
+			assert!(integrity.verify(PASSWORD));
+			assert!(!integrity.verify(b""));
+		}
+		let request = StunView::decode(&REQUEST).unwrap();
+		let auth = request.check_auth(|username, _| [IntegrityKey::new(username.as_bytes()), key.clone()]);
+		assert_eq!(auth.unwrap().0, "evtj:h6vY");
+		let response = StunView::decode(&RESPONSE).unwrap();
+		let mapped = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+		assert_eq!(response.get::<typed::XMapped>(), Some(mapped));
+	}
+
+	#[test]
+	fn long_term_vector() {
+		let view = StunView::decode(&LONG_TERM).unwrap();
+		assert_eq!(view.get::<typed::Username>(), Some("マトリックス"));
+		assert_eq!(view.get::<typed::Realm>(), Some("example.org"));
+		assert_eq!(view.get::<typed::Nonce>(), Some("f//499k954d6OL34oL9FSTvy64sA"));
+		assert!(view.verify_key(&IntegrityKey::new(&LONG_TERM_KEY)));
+		assert!(!view.verify_key(&IntegrityKey::new(PASSWORD)));
+	}
+
+	#[test]
+	fn precomputed_key() {
+		// A precomputed key gives the same MAC as its key data, and keys match if their key data does
+		let key = IntegrityKey::new(PASSWORD);
+		assert!(key.matches(&IntegrityKey::new(PASSWORD)));
+		assert!(!key.matches(&IntegrityKey::new(b"VOkJxbRl1RmTxUk/WvJxBu")));
+		assert!(Integrity::Key(&key).verify(PASSWORD));
+		assert!(Integrity::Set { key_data: PASSWORD }.verify_key(&key));
+		let encode = |integrity| {
+			let mut buff = [0u8; 128];
+			let attrs = [StunAttr::Username("evtj:h6vY"), StunAttr::Integrity(integrity), StunAttr::Fingerprint];
+			let len = StunEncoder::encode(&mut buff, &StunTyp::Req(0x001), &[7; 12], &attrs).unwrap();
+			buff[..len].to_vec()
+		};
+		let packet = encode(Integrity::Key(&key));
+		assert_eq!(packet, encode(Integrity::Set { key_data: PASSWORD }));
+		assert!(StunView::decode(&packet).unwrap().verify_key(&key));
+	}
+}
@@ -1,6 +1,7 @@
 use crate::attr::{AttrContext, StunAttr, StunAttrDecodeErr};
 
 pub mod flat;
+pub mod typed;
 
 #[derive(Debug, Clone)]
 pub enum StunAttrs<'i> {
@@ -57,7 +58,7 @@ impl<'i, 'a> IntoIterator for &'a StunAttrs<'i> {
 				header,
 				length: 0,
 			},
-			StunAttrs::List(l) => StunAttrsIter::List(l.into_iter()),
+			StunAttrs::List(l) => StunAttrsIter::List(l.iter()),
 		}
 	}
 }
@@ -106,10 +107,10 @@ impl<'i, 'a> Iterator for StunAttrsIter<'i, 'a> {
 				});
 
 				let mut padded_len = attr_len;
-				while padded_len % 4 != 0 {
+				while !padded_len.is_multiple_of(4) {
 					padded_len += 1;
 				}
-				*length = *length + padded_len as usize;
+				*length += padded_len as usize;
 
 				ret
 			}
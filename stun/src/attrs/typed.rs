@@ -0,0 +1,56 @@
+use std::net::SocketAddr;
+
+use crate::attr::{self, AttrContext, StunAttrDecodeErr, StunAttrValue, ZeroXor};
+
+// Marker types for looking up a single attribute without decoding the rest of the message (see StunView::get)
+pub trait TypedAttr<'i> {
+	const TYP: u16;
+	type Value;
+	fn decode(buff: &'i [u8], ctx: AttrContext<'i>) -> Result<Self::Value, StunAttrDecodeErr>;
+}
+
+macro_rules! typed_attrs {
+	($($name:ident = $typ:literal => $value:ty;)*) => {$(
+		#[derive(Debug, Clone, Copy)]
+		pub struct $name;
+		impl<'i> TypedAttr<'i> for $name {
+			const TYP: u16 = $typ;
+			type Value = $value;
+			fn decode(buff: &'i [u8], ctx: AttrContext<'i>) -> Result<Self::Value, StunAttrDecodeErr> {
+				<$value as StunAttrValue<'i>>::decode(buff, ctx)
+			}
+		}
+	)*};
+}
+
+typed_attrs! {
+	// RFC 5389:
+	Mapped = 0x0001 => ZeroXor<SocketAddr>;
+	Username = 0x0006 => &'i str;
+	Integrity = 0x0008 => attr::Integrity<'i>;
+	Error = 0x0009 => attr::Error<'i>;
+	UnknownAttributes = 0x000A => attr::UnknownAttributes<'i>;
+	Realm = 0x0014 => &'i str;
+	Nonce = 0x0015 => &'i str;
+	XMapped = 0x0020 => SocketAddr;
+	Software = 0x8022 => &'i str;
+	AlternateServer = 0x8023 => ZeroXor<SocketAddr>;
+	Fingerprint = 0x8028 => attr::Fingerprint;
+
+	// RFC 5766:
+	Channel = 0x000C => attr::Channel;
+	Lifetime = 0x000D => u32;
+	XPeer = 0x0012 => SocketAddr;
+	Data = 0x0013 => &'i [u8];
+	XRelayed = 0x0016 => SocketAddr;
+	EvenPort = 0x0018 => attr::EvenPort;
+	RequestedTransport = 0x0019 => attr::RequestedTransport;
+	DontFragment = 0x001A => ();
+	ReservationToken = 0x0022 => u32;
+
+	// RFC 5245 / 8445:
+	Priority = 0x0024 => u32;
+	UseCandidate = 0x0025 => ();
+	IceControlled = 0x8029 => u64;
+	IceControlling = 0x802A => u64;
+}
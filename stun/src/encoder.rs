@@ -0,0 +1,175 @@
+use crate::{
+	attr::{AttrContext, StunAttr, StunAttrValue},
+	StunTyp,
+};
+
+// Writes a STUN message directly into the output buffer, one attribute at a time.  Integrity and fingerprint
+// attributes are computed over whatever was pushed before them, so push them last.
+pub struct StunEncoder<'b> {
+	buff: &'b mut [u8],
+	length: usize,
+}
+impl<'b> StunEncoder<'b> {
+	pub fn new(buff: &'b mut [u8], typ: &StunTyp, txid: &[u8; 12]) -> Option<Self> {
+		if buff.len() < 20 {
+			return None;
+		}
+		buff[0..][..2].copy_from_slice(&u16::from(typ).to_be_bytes());
+		buff[2..][..2].copy_from_slice(&0u16.to_be_bytes());
+		buff[4..][..4].copy_from_slice(&0x2112A442u32.to_be_bytes());
+		buff[8..][..12].copy_from_slice(txid);
+		Some(Self { buff, length: 0 })
+	}
+	pub fn push(&mut self, attr: &StunAttr<'_>) -> Option<&mut Self> {
+		self.push_value(attr.typ(), attr.value())
+	}
+	pub fn push_value(&mut self, typ: u16, value: &dyn StunAttrValue<'_>) -> Option<&mut Self> {
+		let length = value.length();
+		let mut attr_len = 4 + length as usize;
+		while !attr_len.is_multiple_of(4) {
+			attr_len += 1;
+		}
+		let (header, rest) = self.buff.split_at_mut(20);
+		let (attrs_prefix, to_write) = rest.split_at_mut(self.length);
+		let to_write = to_write.get_mut(..attr_len)?;
+
+		to_write[0..][..2].copy_from_slice(&typ.to_be_bytes());
+		to_write[2..][..2].copy_from_slice(&length.to_be_bytes());
+		let (value_buff, padding) = to_write[4..].split_at_mut(length as usize);
+		let ctx = AttrContext {
+			header: <&[u8; 20]>::try_from(&*header).unwrap(),
+			attrs_prefix,
+			attr_len: attr_len as u16,
+			zero_xor_bytes: false,
+		};
+		value.encode(value_buff, ctx);
+		padding.fill(0);
+
+		self.length += attr_len;
+		self.buff[2..][..2].copy_from_slice(&(self.length as u16).to_be_bytes());
+		Some(self)
+	}
+	pub fn finish(self) -> usize {
+		20 + self.length
+	}
+
+	// Encode a whole message from a list of attributes (which can live on the stack)
+	pub fn encode(buff: &mut [u8], typ: &StunTyp, txid: &[u8; 12], attrs: &[StunAttr<'_>]) -> Option<usize> {
+		let mut enc = StunEncoder::new(buff, typ, txid)?;
+		for attr in attrs {
+			enc.push(attr)?;
+		}
+		Some(enc.finish())
+	}
+	pub fn length(attrs: &[StunAttr<'_>]) -> u16 {
+		attrs.iter().map(StunAttr::len).sum()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use super::*;
+	use crate::{
+		attr::{Data, Error, Integrity, IntegrityKey, RequestedTransport},
+		attrs::typed,
+		view::StunView,
+		Stun,
+	};
+
+	const TXID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+	#[test]
+	fn round_trip() {
+		let v4: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+		let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+		let attrs = [
+			StunAttr::Username("user"),
+			StunAttr::Realm("realm"),
+			StunAttr::Nonce("a nonce"),
+			StunAttr::RequestedTransport(RequestedTransport(17)),
+			StunAttr::Lifetime(600),
+			StunAttr::XPeer(v4),
+			StunAttr::XRelayed(v6),
+			// Odd lengths get padded
+			StunAttr::Data(Data::Slice(b"hello")),
+			StunAttr::Error(Error {
+				code: 438,
+				message: "Stale Nonce",
+			}),
+			StunAttr::UseCandidate,
+			StunAttr::IceControlling(u64::MAX - 1),
+			StunAttr::Integrity(Integrity::Set { key_data: b"key" }),
+			StunAttr::Fingerprint,
+		];
+		let mut buff = [0xffu8; 512];
+		let len = StunEncoder::encode(&mut buff, &StunTyp::Req(0x003), &TXID, &attrs).unwrap();
+		assert_eq!(len, 20 + StunEncoder::length(&attrs) as usize);
+		// The same bytes as encoding a Stun
+		let mut other = [0u8; 512];
+		let stun = Stun {
+			typ: StunTyp::Req(0x003),
+			txid: TXID,
+			attrs: attrs.to_vec(),
+		};
+		assert_eq!(stun.encode(&mut other), Some(len));
+		assert_eq!(buff[..len], other[..len]);
+
+		let view = StunView::decode(&buff[..len]).unwrap();
+		assert!(matches!(view.typ(), StunTyp::Req(0x003)));
+		assert_eq!(view.txid(), TXID);
+		assert_eq!(view.len(), len);
+		assert_eq!(view.get::<typed::Username>(), Some("user"));
+		assert_eq!(view.get::<typed::Realm>(), Some("realm"));
+		assert_eq!(view.get::<typed::Nonce>(), Some("a nonce"));
+		assert_eq!(view.get::<typed::RequestedTransport>().map(|t| t.0), Some(17));
+		assert_eq!(view.get::<typed::Lifetime>(), Some(600));
+		assert_eq!(view.get::<typed::XPeer>(), Some(v4));
+		assert_eq!(view.get::<typed::XRelayed>(), Some(v6));
+		assert_eq!(view.get::<typed::Data>(), Some(&b"hello"[..]));
+		let error = view.get::<typed::Error>().unwrap();
+		assert_eq!((error.code, error.message), (438, "Stale Nonce"));
+		assert!(view.has::<typed::UseCandidate>());
+		assert_eq!(view.get::<typed::IceControlling>(), Some(u64::MAX - 1));
+		assert!(view.try_get::<typed::Fingerprint>().unwrap().is_some());
+		assert!(view.verify(b"key"));
+		assert!(!view.verify(b"other key"));
+		let auth = view.check_auth(|username, realm| {
+			assert_eq!((username, realm), ("user", Some("realm")));
+			[IntegrityKey::new(b"other key"), IntegrityKey::new(b"key")]
+		});
+		assert_eq!(auth.map(|(username, _)| username), Some("user"));
+		assert!(!view.has::<typed::Priority>());
+		// Padding is zeroed
+		let data = buff[..len].windows(9).position(|w| w == b"\x00\x13\x00\x05hello").unwrap();
+		assert_eq!(buff[data + 9..data + 12], [0, 0, 0]);
+	}
+
+	#[test]
+	fn ignored_after_integrity() {
+		// Only a fingerprint counts after the integrity
+		let attrs = [
+			StunAttr::Integrity(Integrity::Set { key_data: b"key" }),
+			StunAttr::Username("user"),
+			StunAttr::Fingerprint,
+			StunAttr::Realm("realm"),
+		];
+		let mut buff = [0u8; 128];
+		let len = StunEncoder::encode(&mut buff, &StunTyp::Req(0x001), &TXID, &attrs).unwrap();
+		let view = StunView::decode(&buff[..len]).unwrap();
+		assert!(view.verify(b"key"));
+		assert!(!view.has::<typed::Username>());
+		assert!(!view.has::<typed::Fingerprint>());
+		assert!(!view.has::<typed::Realm>());
+	}
+
+	#[test]
+	fn too_small() {
+		let attrs = [StunAttr::Username("user"), StunAttr::Fingerprint];
+		let mut buff = [0u8; 36];
+		assert_eq!(StunEncoder::encode(&mut buff[..19], &StunTyp::Req(0x001), &TXID, &attrs), None);
+		assert_eq!(StunEncoder::encode(&mut buff[..35], &StunTyp::Req(0x001), &TXID, &attrs), None);
+		assert_eq!(StunEncoder::encode(&mut buff, &StunTyp::Req(0x001), &TXID, &attrs), Some(36));
+	}
+}
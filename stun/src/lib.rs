@@ -1,12 +1,13 @@
 use attr::StunAttrDecodeErr;
-use bytes::Buf;
 
 pub mod attr;
 pub mod attrs;
-use attr::AttrContext;
+pub mod encoder;
+pub mod view;
 use attr::StunAttr;
+use encoder::StunEncoder;
 use attrs::flat::Flat;
-use attrs::StunAttrs;
+use view::StunView;
 
 #[derive(Debug, Clone)]
 pub enum StunDecodeErr {
@@ -34,6 +35,7 @@ impl StunTyp {
 		}
 	}
 }
+#[allow(clippy::unusual_byte_groupings, clippy::identity_op)]
 impl TryFrom<u16> for StunTyp {
 	type Error = StunDecodeErr;
 	fn try_from(value: u16) -> Result<Self, StunDecodeErr> {
@@ -52,6 +54,7 @@ impl TryFrom<u16> for StunTyp {
 		})
 	}
 }
+#[allow(clippy::unusual_byte_groupings, clippy::identity_op)]
 impl From<&StunTyp> for u16 {
 	fn from(value: &StunTyp) -> Self {
 		let (class, method) = match value {
@@ -78,12 +81,9 @@ impl<'i> Stun<'i> {
 		Flat::from_iter(self)
 	}
 	pub fn length(&self) -> u16 {
-		let mut ret = 0;
-		for a in &self.attrs {
-			ret += a.len();
-		}
-		ret
+		StunEncoder::length(&self.attrs)
 	}
+	#[allow(clippy::len_without_is_empty)]
 	pub fn len(&self) -> usize {
 		20 + self.length() as usize
 	}
@@ -102,69 +102,21 @@ impl<'i> Stun<'i> {
 		}
 	}
 	pub fn decode(buff: &'i [u8]) -> Result<Self, StunDecodeErr> {
-		let mut buf = buff;
-		if buf.remaining() < 20 { return Err(StunDecodeErr::PacketTooSmall) }
-		let typ = buf.get_u16();
-		let typ = StunTyp::try_from(typ)?;
-
-		let length = buf.get_u16();
-		if length % 4 != 0 {
-			return Err(StunDecodeErr::UnalignedLength);
-		}
-
-		let magic = buf.get_u32();
-		if magic != 0x2112A442 {
-			return Err(StunDecodeErr::BadMagic);
-		}
-
-		let mut txid = [0u8; 12];
-		buf.copy_to_slice(&mut txid);
-
-		if buf.remaining() < length as usize {
-			return Err(StunDecodeErr::PacketTooSmall);
-		}
+		let view = StunView::decode(buff)?;
 
 		let mut attrs = Vec::new();
-
-		for a in &(StunAttrs::Parse {
-			buff: &buff[20..][..length as usize],
-			header: (&buff[0..][..20]).try_into().unwrap(),
-		}) {
-			attrs.push(a.map_err(|e| StunDecodeErr::AttrErr(e))?);
+		for a in &view.attrs() {
+			attrs.push(a.map_err(StunDecodeErr::AttrErr)?);
 		}
 
-		Ok(Self { typ, txid, attrs })
+		Ok(Self {
+			typ: view.typ(),
+			txid: view.txid(),
+			attrs,
+		})
 	}
 	pub fn encode(&self, buff: &mut [u8]) -> Option<usize> {
-		let length = self.length();
-		let len = 20 + length as usize;
-		if buff.len() < len {
-			return None;
-		}
-		buff[0..][..2].copy_from_slice(&u16::from(&self.typ).to_be_bytes());
-		buff[2..][..2].copy_from_slice(&length.to_be_bytes());
-		buff[4..][..4].copy_from_slice(&0x2112A442u32.to_be_bytes());
-		buff[8..][..12].copy_from_slice(&self.txid);
-		let (header, buff) = buff.split_at_mut(20);
-		let header = <&[u8; 20]>::try_from(&*header).unwrap();
-
-		let mut length = 0;
-		let (mut attrs_prefix, mut to_write) = buff.split_at_mut(length);
-		for attr in &self.attrs {
-			let attr_len = attr.len();
-			let ctx = AttrContext {
-				header,
-				attrs_prefix,
-				attr_len,
-				zero_xor_bytes: false,
-			};
-			attr.encode(&mut to_write[..attr_len as usize], ctx);
-
-			length += attr.len() as usize;
-			(attrs_prefix, to_write) = buff.split_at_mut(length);
-		}
-
-		Some(len)
+		StunEncoder::encode(buff, &self.typ, &self.txid, &self.attrs)
 	}
 }
 
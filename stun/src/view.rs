@@ -0,0 +1,280 @@
+use std::borrow::Borrow;
+
+use crate::{
+	attr::{AttrContext, IntegrityKey, StunAttrDecodeErr},
+	attrs::{
+		typed::{self, TypedAttr},
+		StunAttrs,
+	},
+	StunDecodeErr, StunTyp,
+};
+
+// A StunView only validates the header.  Attributes are found and decoded on demand, without allocating.
+#[derive(Debug, Clone, Copy)]
+pub struct StunView<'i> {
+	header: &'i [u8; 20],
+	attrs: &'i [u8],
+}
+impl<'i> StunView<'i> {
+	pub fn decode(buff: &'i [u8]) -> Result<Self, StunDecodeErr> {
+		let ret = Self::parse(buff);
+		#[cfg(feature = "tracing")]
+		if let Err(e) = &ret {
+			tracing::trace!(error = ?e, len = buff.len(), "invalid stun message");
+		}
+		ret
+	}
+	fn parse(buff: &'i [u8]) -> Result<Self, StunDecodeErr> {
+		if buff.len() < 20 {
+			return Err(StunDecodeErr::PacketTooSmall);
+		}
+		let header: &[u8; 20] = buff[..20].try_into().unwrap();
+		StunTyp::try_from(u16::from_be_bytes([header[0], header[1]]))?;
+
+		let length = u16::from_be_bytes([header[2], header[3]]);
+		if !length.is_multiple_of(4) {
+			return Err(StunDecodeErr::UnalignedLength);
+		}
+
+		let magic = u32::from_be_bytes(header[4..][..4].try_into().unwrap());
+		if magic != 0x2112A442 {
+			return Err(StunDecodeErr::BadMagic);
+		}
+
+		let attrs = buff[20..]
+			.get(..length as usize)
+			.ok_or(StunDecodeErr::PacketTooSmall)?;
+
+		Ok(Self { header, attrs })
+	}
+	pub fn typ(&self) -> StunTyp {
+		// The type was already checked in decode
+		StunTyp::try_from(u16::from_be_bytes([self.header[0], self.header[1]])).unwrap()
+	}
+	pub fn txid(&self) -> [u8; 12] {
+		self.header[8..].try_into().unwrap()
+	}
+	pub fn header(&self) -> &'i [u8; 20] {
+		self.header
+	}
+	#[allow(clippy::len_without_is_empty)]
+	pub fn len(&self) -> usize {
+		20 + self.attrs.len()
+	}
+	pub fn attrs(&self) -> StunAttrs<'i> {
+		StunAttrs::Parse {
+			buff: self.attrs,
+			header: self.header,
+		}
+	}
+	pub fn raw(&self) -> RawIter<'i> {
+		RawIter {
+			header: self.header,
+			buff: self.attrs,
+			length: 0,
+			integrity: false,
+			fingerprint: false,
+		}
+	}
+	// Like Flat, only the first attribute of a type is returned, and attributes after the integrity / fingerprint are ignored.
+	pub fn try_get<A: TypedAttr<'i>>(&self) -> Result<Option<A::Value>, StunAttrDecodeErr> {
+		for raw in self.raw() {
+			let (typ, data, ctx) = raw?;
+			if typ == A::TYP {
+				return A::decode(data, ctx).map(Some);
+			}
+		}
+		Ok(None)
+	}
+	pub fn get<A: TypedAttr<'i>>(&self) -> Option<A::Value> {
+		self.try_get::<A>().ok().flatten()
+	}
+	pub fn has<A: TypedAttr<'i>>(&self) -> bool {
+		self.raw().flatten().any(|(typ, ..)| typ == A::TYP)
+	}
+	// check_auth only works if the packet contains a username.  The callback can return more than one candidate
+	// key (e.g. while a shared secret is being rotated): the first one that verifies is returned.
+	pub fn check_auth<K, I, F>(&self, f: F) -> Option<(&'i str, K)>
+	where
+		K: Borrow<IntegrityKey>,
+		I: IntoIterator<Item = K>,
+		F: FnOnce(&str, Option<&str>) -> I,
+	{
+		let username = self.get::<typed::Username>()?;
+		let realm = self.get::<typed::Realm>();
+		let integrity = self.get::<typed::Integrity>()?;
+
+		let ret = f(username, realm)
+			.into_iter()
+			.find(|key| integrity.verify_key(key.borrow()))
+			.map(|key| (username, key));
+		#[cfg(feature = "tracing")]
+		if ret.is_none() {
+			tracing::trace!(username, realm, "message integrity didn't verify");
+		}
+		ret
+	}
+	pub fn verify(&self, key_data: &[u8]) -> bool {
+		self.verify_key(&IntegrityKey::new(key_data))
+	}
+	pub fn verify_key(&self, key: &IntegrityKey) -> bool {
+		self.get::<typed::Integrity>()
+			.is_some_and(|i| i.verify_key(key))
+	}
+}
+
+// Iterates over the undecoded attributes: (type, value, context)
+pub struct RawIter<'i> {
+	header: &'i [u8; 20],
+	buff: &'i [u8],
+	length: usize,
+	integrity: bool,
+	fingerprint: bool,
+}
+impl<'i> Iterator for RawIter<'i> {
+	type Item = Result<(u16, &'i [u8], AttrContext<'i>), StunAttrDecodeErr>;
+	fn next(&mut self) -> Option<Self::Item> {
+		let (attrs_prefix, unread) = self.buff.split_at(self.length);
+		if unread.len() < 4 || self.fingerprint {
+			return None;
+		}
+		let typ = u16::from_be_bytes([unread[0], unread[1]]);
+		let attr_length = u16::from_be_bytes([unread[2], unread[3]]);
+		let attr_len = 4 + attr_length as usize;
+		if unread.len() < attr_len {
+			self.length = self.buff.len();
+			return Some(Err(StunAttrDecodeErr::AttrLengthExceedsPacketLength));
+		}
+		match typ {
+			0x8028 => self.fingerprint = true,
+			_ if self.integrity => return None,
+			0x0008 => self.integrity = true,
+			_ => {}
+		}
+		let mut padded_len = attr_len;
+		while !padded_len.is_multiple_of(4) {
+			padded_len += 1;
+		}
+		self.length = (self.length + padded_len).min(self.buff.len());
+
+		let ctx = AttrContext {
+			header: self.header,
+			attrs_prefix,
+			attr_len: attr_len as u16,
+			zero_xor_bytes: false,
+		};
+		Some(Ok((typ, &unread[4..][..attr_length as usize], ctx)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use super::*;
+	use crate::attr::StunAttr;
+
+	// RFC 5769 section 2.2: a Binding success response with an IPv4 XOR-MAPPED-ADDRESS, whose password is
+	// VOkJxbRl1RmTxUk/WvJxBt
+	const RESPONSE: [u8; 80] = [
+		0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+		0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+		0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+		0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9,
+		0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+	];
+	const PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+	#[test]
+	fn decode() {
+		let view = StunView::decode(&RESPONSE).unwrap();
+		assert!(matches!(view.typ(), StunTyp::Res(0x001)));
+		assert_eq!(view.txid(), RESPONSE[8..20]);
+		assert_eq!(view.len(), RESPONSE.len());
+		assert_eq!(view.get::<typed::Software>(), Some("test vector"));
+		assert_eq!(view.get::<typed::XMapped>(), Some(SocketAddr::from(([192, 0, 2, 1], 32853))));
+		assert!(view.try_get::<typed::Fingerprint>().unwrap().is_some());
+		assert!(view.verify(PASSWORD));
+		assert!(!view.verify(b"VOkJxbRl1RmTxUk/WvJxBu"));
+		assert!(!view.has::<typed::Username>());
+		// The lazy lookups agree with decoding everything
+		let attrs = (&view.attrs()).into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+		assert!(matches!(
+			attrs[..],
+			[StunAttr::Software("test vector"), StunAttr::XMapped(_), StunAttr::Integrity(_), StunAttr::Fingerprint]
+		));
+	}
+
+	#[test]
+	fn bad_header() {
+		assert!(matches!(StunView::decode(&RESPONSE[..19]), Err(StunDecodeErr::PacketTooSmall)));
+		// The length says there's more than there is
+		assert!(matches!(StunView::decode(&RESPONSE[..76]), Err(StunDecodeErr::PacketTooSmall)));
+		let mut packet = RESPONSE;
+		packet[3] = 0x3b;
+		assert!(matches!(StunView::decode(&packet), Err(StunDecodeErr::UnalignedLength)));
+		let mut packet = RESPONSE;
+		packet[4] = 0x22;
+		assert!(matches!(StunView::decode(&packet), Err(StunDecodeErr::BadMagic)));
+		let mut packet = RESPONSE;
+		packet[0] = 0x40;
+		assert!(matches!(StunView::decode(&packet), Err(StunDecodeErr::TypeOutOfRange)));
+	}
+
+	#[test]
+	fn bad_attrs() {
+		// SOFTWARE's length runs past the end of the message
+		let mut packet = RESPONSE;
+		packet[23] = 0x40;
+		let view = StunView::decode(&packet).unwrap();
+		assert!(matches!(
+			view.try_get::<typed::Software>(),
+			Err(StunAttrDecodeErr::AttrLengthExceedsPacketLength)
+		));
+		assert!(view.get::<typed::XMapped>().is_none());
+		assert!(!view.verify(PASSWORD));
+
+		// The message ends partway into an attribute (with the length still a multiple of 4)
+		let mut packet = RESPONSE;
+		packet[3] = 0x10;
+		let view = StunView::decode(&packet[..36]).unwrap();
+		assert_eq!(view.get::<typed::Software>(), Some("test vector"));
+		let mut packet = RESPONSE;
+		packet[3] = 0x14;
+		let view = StunView::decode(&packet[..40]).unwrap();
+		assert!(view.try_get::<typed::XMapped>().is_err());
+
+		// An XOR-MAPPED-ADDRESS that's too short for its family
+		let mut packet = RESPONSE;
+		packet[41] = 0x02;
+		let view = StunView::decode(&packet).unwrap();
+		assert!(matches!(view.try_get::<typed::XMapped>(), Err(StunAttrDecodeErr::ValueUnexpectedLength)));
+	}
+
+	#[test]
+	fn tampered() {
+		// Changing the mapped address breaks both the integrity and the fingerprint
+		let mut packet = RESPONSE;
+		packet[47] ^= 1;
+		let view = StunView::decode(&packet).unwrap();
+		assert!(!view.verify(PASSWORD));
+		assert!(matches!(view.try_get::<typed::Fingerprint>(), Err(StunAttrDecodeErr::BadFingerprint)));
+		assert!((&view.attrs()).into_iter().any(|attr| attr.is_err()));
+
+		// A bad fingerprint on its own
+		let mut packet = RESPONSE;
+		packet[79] ^= 1;
+		let view = StunView::decode(&packet).unwrap();
+		assert!(view.verify(PASSWORD));
+		assert!(matches!(view.try_get::<typed::Fingerprint>(), Err(StunAttrDecodeErr::BadFingerprint)));
+
+		// And a bad MESSAGE-INTEGRITY on its own (with the fingerprint fixed up to match)
+		let mut packet = RESPONSE;
+		packet[60] ^= 1;
+		let fingerprint = crc32fast::hash(&packet[..72]) ^ 0x5354554e;
+		packet[76..].copy_from_slice(&fingerprint.to_be_bytes());
+		let view = StunView::decode(&packet).unwrap();
+		assert!(view.try_get::<typed::Fingerprint>().unwrap().is_some());
+		assert!(!view.verify(PASSWORD));
+	}
+}